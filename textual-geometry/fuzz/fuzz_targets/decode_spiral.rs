@@ -0,0 +1,42 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use textual_geometry::decode::{decode_spiral, decode_spiral_report, DecodeLimits, Sampling};
+use textual_geometry::rendering::{Layout, Scrambler, StegoLayout};
+use textual_geometry::threshold::Threshold;
+
+fuzz_target!(|data: &[u8]| {
+    // Keep limits small so the fuzzer spends its time on parsing, not on huge rasters.
+    let limits = DecodeLimits {
+        max_width: 512,
+        max_height: 512,
+        max_alloc: 8 * 1024 * 1024,
+        max_decoded_len: 64 * 1024,
+    };
+
+    let _ = decode_spiral(data, &limits);
+
+    // The first 13 bytes pick a sampling and its layout, as image metadata would, the
+    // rest is the image. Layout fields are taken whole so overflowing ones get tried,
+    // and stego layouts reuse them as dimension, plane and seed.
+    let Some((head, image)) = data.split_first_chunk::<13>() else {
+        return;
+    };
+    let word = |at: usize| u32::from_le_bytes([head[at], head[at + 1], head[at + 2], head[at + 3]]);
+    let layout = Layout::new(word(1))
+        .with_module(word(5))
+        .with_quiet(word(9))
+        .with_finders(head[0] & 0x80 != 0);
+    let stego = StegoLayout::new(word(1))
+        .with_plane(head[5])
+        .with_seed(word(9) as u64);
+    let sampling = match head[0] % 5 {
+        0 => Sampling::Exact,
+        1 => Sampling::Layout(layout),
+        2 => Sampling::Finders(layout),
+        3 => Sampling::Scrambled(layout, Scrambler::new("fuzz")),
+        _ => Sampling::Stego(stego),
+    };
+
+    let _ = decode_spiral_report(image, &limits, &sampling, &Threshold::Auto);
+});
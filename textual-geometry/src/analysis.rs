@@ -0,0 +1,342 @@
+//! Standard metrics for judging an image cipher, comparing plaintext renders against
+//! their encrypted counterparts.
+
+use image::GrayImage;
+
+use crate::rendering::Scrambler;
+
+/// Chi-square critical value for 255 degrees of freedom at a 0.05 significance level.
+/// A histogram scoring below it is consistent with uniform.
+pub const CHI_SQUARE_CRITICAL: f64 = 293.2478;
+
+/// Expected NPCR and UACI, in percent, between two independent uniformly random images.
+pub const IDEAL_NPCR: f64 = 99.6094;
+pub const IDEAL_UACI: f64 = 33.4635;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Horizontal,
+    Vertical,
+    Diagonal,
+}
+
+impl Direction {
+    fn step(&self) -> (u32, u32) {
+        match *self {
+            Direction::Horizontal => (1, 0),
+            Direction::Vertical => (0, 1),
+            Direction::Diagonal => (1, 1),
+        }
+    }
+}
+
+/// Metrics of a single image.
+#[derive(Clone, Debug)]
+pub struct ImageMetrics {
+    /// Shannon entropy of the intensity histogram in bits, 8 at most.
+    pub entropy: f64,
+    pub chi_square: f64,
+    pub horizontal: f64,
+    pub vertical: f64,
+    pub diagonal: f64,
+}
+
+impl ImageMetrics {
+    pub fn of(image: &GrayImage) -> ImageMetrics {
+        let histogram = histogram(image);
+        ImageMetrics {
+            entropy: entropy(&histogram),
+            chi_square: chi_square(&histogram),
+            horizontal: correlation(image, Direction::Horizontal),
+            vertical: correlation(image, Direction::Vertical),
+            diagonal: correlation(image, Direction::Diagonal),
+        }
+    }
+
+    fn to_json(&self) -> String {
+        format!(
+            "{{\"entropy\":{},\"chi_square\":{},\"histogram_uniform\":{},\"correlation\":{{\"horizontal\":{},\"vertical\":{},\"diagonal\":{}}}}}",
+            json_number(self.entropy),
+            json_number(self.chi_square),
+            self.chi_square < CHI_SQUARE_CRITICAL,
+            json_number(self.horizontal),
+            json_number(self.vertical),
+            json_number(self.diagonal)
+        )
+    }
+}
+
+/// NPCR and UACI between two images, in percent.
+#[derive(Clone, Copy, Debug)]
+pub struct Difference {
+    pub npcr: f64,
+    pub uaci: f64,
+}
+
+impl Difference {
+    /// None if the images differ in size.
+    pub fn between(a: &GrayImage, b: &GrayImage) -> Option<Difference> {
+        Some(Difference {
+            npcr: npcr(a, b)?,
+            uaci: uaci(a, b)?,
+        })
+    }
+
+    fn to_json(self) -> String {
+        format!(
+            "{{\"npcr\":{},\"uaci\":{}}}",
+            json_number(self.npcr),
+            json_number(self.uaci)
+        )
+    }
+}
+
+/// Everything a reviewer asks for about one plaintext and its ciphertext.
+#[derive(Clone, Debug)]
+pub struct Analysis {
+    pub plain: ImageMetrics,
+    pub cipher: Option<ImageMetrics>,
+    /// Plaintext against ciphertext.
+    pub difference: Option<Difference>,
+    /// Ciphertexts of the plaintext and of the plaintext with one pixel changed, under
+    /// the same key. This is what NPCR and UACI are usually quoted for.
+    pub differential: Option<Difference>,
+}
+
+impl Analysis {
+    /// Metrics of a plaintext render alone, or against a ciphertext of it.
+    pub fn compare(plain: &GrayImage, cipher: Option<&GrayImage>) -> Analysis {
+        Analysis {
+            plain: ImageMetrics::of(plain),
+            cipher: cipher.map(ImageMetrics::of),
+            difference: cipher.and_then(|cipher| Difference::between(plain, cipher)),
+            differential: None,
+        }
+    }
+
+    /// Encrypt `plain` with `scrambler` and measure the result, including the one
+    /// pixel differential test. None if the scrambler can't handle the image.
+    pub fn scrambled(plain: &GrayImage, scrambler: &Scrambler) -> Option<Analysis> {
+        let mut cipher = plain.clone();
+        if !scrambler.apply(&mut cipher) {
+            return None;
+        }
+
+        let mut tweaked = plain.clone();
+        let (width, height) = tweaked.dimensions();
+        let pixel = tweaked.get_pixel_mut(width / 2, height / 2);
+        pixel.0[0] = pixel.0[0].wrapping_add(1);
+        scrambler.apply(&mut tweaked);
+
+        let mut analysis = Analysis::compare(plain, Some(&cipher));
+        analysis.differential = Difference::between(&cipher, &tweaked);
+        Some(analysis)
+    }
+
+    pub fn to_json(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| String::from("null"));
+        format!(
+            "{{\"plain\":{},\"cipher\":{},\"difference\":{},\"differential\":{},\"ideal\":{{\"npcr\":{},\"uaci\":{},\"entropy\":8,\"chi_square_critical\":{}}}}}",
+            self.plain.to_json(),
+            optional(self.cipher.as_ref().map(ImageMetrics::to_json)),
+            optional(self.difference.map(Difference::to_json)),
+            optional(self.differential.map(Difference::to_json)),
+            IDEAL_NPCR,
+            IDEAL_UACI,
+            CHI_SQUARE_CRITICAL
+        )
+    }
+}
+
+pub fn histogram(image: &GrayImage) -> [u64; 256] {
+    let mut histogram = [0u64; 256];
+    for pixel in image.pixels() {
+        histogram[pixel.0[0] as usize] += 1;
+    }
+    histogram
+}
+
+pub fn entropy(histogram: &[u64; 256]) -> f64 {
+    let total: u64 = histogram.iter().sum();
+    if total == 0 {
+        return 0.;
+    }
+
+    histogram
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+/// Chi-square statistic of the histogram against a uniform distribution.
+pub fn chi_square(histogram: &[u64; 256]) -> f64 {
+    let expected = histogram.iter().sum::<u64>() as f64 / 256.;
+    if expected == 0. {
+        return 0.;
+    }
+
+    histogram
+        .iter()
+        .map(|&count| (count as f64 - expected).powi(2) / expected)
+        .sum()
+}
+
+/// Pearson correlation of every pixel with its neighbour in `direction`. Flat images
+/// have no defined correlation and score 0.
+pub fn correlation(image: &GrayImage, direction: Direction) -> f64 {
+    let (width, height) = image.dimensions();
+    let (dx, dy) = direction.step();
+    if width <= dx || height <= dy {
+        return 0.;
+    }
+
+    let (mut n, mut sx, mut sy, mut sxx, mut syy, mut sxy) = (0f64, 0f64, 0f64, 0f64, 0f64, 0f64);
+    for y in 0..height - dy {
+        for x in 0..width - dx {
+            let a = image.get_pixel(x, y).0[0] as f64;
+            let b = image.get_pixel(x + dx, y + dy).0[0] as f64;
+            n += 1.;
+            sx += a;
+            sy += b;
+            sxx += a * a;
+            syy += b * b;
+            sxy += a * b;
+        }
+    }
+
+    let covariance = sxy / n - (sx / n) * (sy / n);
+    let variance_x = sxx / n - (sx / n).powi(2);
+    let variance_y = syy / n - (sy / n).powi(2);
+    if variance_x <= 0. || variance_y <= 0. {
+        return 0.;
+    }
+
+    covariance / (variance_x.sqrt() * variance_y.sqrt())
+}
+
+/// Number of pixels change rate: the percentage of positions that differ.
+pub fn npcr(a: &GrayImage, b: &GrayImage) -> Option<f64> {
+    if a.dimensions() != b.dimensions() || a.as_raw().is_empty() {
+        return None;
+    }
+
+    let changed = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .filter(|(a, b)| a != b)
+        .count();
+    Some(100. * changed as f64 / a.as_raw().len() as f64)
+}
+
+/// Unified average changing intensity: mean absolute difference as a percentage of 255.
+pub fn uaci(a: &GrayImage, b: &GrayImage) -> Option<f64> {
+    if a.dimensions() != b.dimensions() || a.as_raw().is_empty() {
+        return None;
+    }
+
+    let total: u64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&a, &b)| a.abs_diff(b) as u64)
+        .sum();
+    Some(100. * total as f64 / (255. * a.as_raw().len() as f64))
+}
+
+/// JSON has no NaN or infinity.
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{:.6}", value)
+    } else {
+        String::from("null")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn compares_pixels() {
+        let black = GrayImage::new(16, 16);
+        let white = GrayImage::from_pixel(16, 16, Luma([255]));
+        assert_eq!(npcr(&black, &black), Some(0.));
+        assert_eq!(uaci(&black, &black), Some(0.));
+        assert_eq!(npcr(&black, &white), Some(100.));
+        assert_eq!(uaci(&black, &white), Some(100.));
+
+        // A quarter of the pixels off by 51, a fifth of full scale.
+        let mut gray = black.clone();
+        for x in 0..16 {
+            for y in 0..4 {
+                gray.put_pixel(x, y, Luma([51]));
+            }
+        }
+        let difference = Difference::between(&black, &gray).unwrap();
+        assert!(close(difference.npcr, 25.));
+        assert!(close(difference.uaci, 5.));
+
+        assert!(Difference::between(&black, &GrayImage::new(16, 8)).is_none());
+        assert!(npcr(&GrayImage::new(0, 0), &GrayImage::new(0, 0)).is_none());
+    }
+
+    #[test]
+    fn scores_histograms() {
+        let every = GrayImage::from_fn(16, 16, |x, y| Luma([(y * 16 + x) as u8]));
+        let uniform = histogram(&every);
+        assert!(uniform.iter().all(|&count| count == 1));
+        assert!(close(entropy(&uniform), 8.));
+        assert!(close(chi_square(&uniform), 0.));
+
+        // Every pixel in one bin of 256, each expected to hold one.
+        let flat = histogram(&GrayImage::from_pixel(16, 16, Luma([7])));
+        assert!(close(entropy(&flat), 0.));
+        assert!(close(chi_square(&flat), 255. * 255. + 255.));
+
+        // Two values, equally common.
+        let halves = histogram(&GrayImage::from_fn(16, 16, |x, _| Luma([(x % 2) as u8])));
+        assert!(close(entropy(&halves), 1.));
+
+        assert_eq!(entropy(&[0; 256]), 0.);
+        assert_eq!(chi_square(&[0; 256]), 0.);
+    }
+
+    #[test]
+    fn correlates_neighbours() {
+        let ramp = GrayImage::from_fn(32, 32, |x, _| Luma([(x * 8) as u8]));
+        for direction in [
+            Direction::Horizontal,
+            Direction::Vertical,
+            Direction::Diagonal,
+        ] {
+            assert!(close(correlation(&ramp, direction), 1.), "{:?}", direction);
+        }
+
+        let checkers = GrayImage::from_fn(32, 32, |x, y| Luma([((x + y) % 2 * 255) as u8]));
+        assert!(close(correlation(&checkers, Direction::Horizontal), -1.));
+        assert!(close(correlation(&checkers, Direction::Vertical), -1.));
+        assert!(close(correlation(&checkers, Direction::Diagonal), 1.));
+
+        let flat = GrayImage::from_pixel(32, 32, Luma([128]));
+        let metrics = ImageMetrics::of(&flat);
+        assert_eq!(
+            (metrics.horizontal, metrics.vertical, metrics.diagonal),
+            (0., 0., 0.)
+        );
+        assert_eq!(
+            correlation(&GrayImage::new(1, 1), Direction::Horizontal),
+            0.
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt;
+
+use crate::decode::{decode_spiral_report, DecodeError, DecodeLimits, Sampling};
+use crate::encoder::Encoder;
+use crate::geometry::SpiralGeometry;
+use crate::rendering::{Bitmap, Layout, Paper};
+use crate::threshold::Threshold;
+
+/// Leading bytes of every backup page, ahead of its index and checksums.
+const PAGE_MAGIC: &[u8; 4] = b"\0TBK";
+const PAGE_VERSION: u8 = 1;
+/// Magic, version, index and count, total length and checksum, page checksum.
+const HEADER_LEN: usize = 4 + 1 + 2 + 2 + 4 + 4 + 4;
+
+#[derive(Debug)]
+pub enum BackupError {
+    /// A scan couldn't be decoded at all, by position in the list of scans.
+    Scan(usize, DecodeError),
+    /// A scan decoded, but not to a backup page.
+    NotABackupPage(usize),
+    /// Scans from more than one backup were mixed together.
+    Inconsistent,
+    /// Pages that were never read intact, numbered from 1.
+    MissingPages(Vec<u16>),
+    Checksum,
+    TooLarge(usize),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackupError::Scan(ix, e) => write!(f, "scan {}: {}", ix + 1, e),
+            BackupError::NotABackupPage(ix) => {
+                write!(f, "scan {} is not a page of a paper backup", ix + 1)
+            }
+            BackupError::Inconsistent => write!(f, "scans are from different backups"),
+            BackupError::MissingPages(pages) => {
+                let pages = pages.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                write!(f, "missing or damaged pages: {}", pages.join(", "))
+            }
+            BackupError::Checksum => write!(f, "restored payload fails its checksum"),
+            BackupError::TooLarge(n) => write!(f, "{} bytes is too large for a paper backup", n),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+/// Identifies a page within a backup. Every page repeats the length and checksum of
+/// the whole payload so pages of different backups can't be mixed up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PageHeader {
+    /// Numbered from 0.
+    pub index: u16,
+    pub count: u16,
+    pub total_len: u32,
+    pub checksum: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Page {
+    pub header: PageHeader,
+    pub data: Vec<u8>,
+}
+
+impl Page {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = PAGE_MAGIC.to_vec();
+        out.push(PAGE_VERSION);
+        out.extend(self.header.index.to_be_bytes());
+        out.extend(self.header.count.to_be_bytes());
+        out.extend(self.header.total_len.to_be_bytes());
+        out.extend(self.header.checksum.to_be_bytes());
+        out.extend(crc32fast::hash(&self.data).to_be_bytes());
+        out.extend(&self.data);
+        out
+    }
+
+    /// Parse a decoded page. None if it isn't one, or its own checksum doesn't match.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Page> {
+        let rest = bytes.strip_prefix(PAGE_MAGIC)?;
+        if rest.len() < HEADER_LEN - PAGE_MAGIC.len() || rest[0] != PAGE_VERSION {
+            return None;
+        }
+
+        let u16_at = |at: usize| u16::from_be_bytes([rest[at], rest[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(rest[at..at + 4].try_into().unwrap());
+        let header = PageHeader {
+            index: u16_at(1),
+            count: u16_at(3),
+            total_len: u32_at(5),
+            checksum: u32_at(9),
+        };
+        let data = rest[17..].to_vec();
+        if crc32fast::hash(&data) != u32_at(13) || header.index >= header.count {
+            return None;
+        }
+
+        Some(Page { header, data })
+    }
+}
+
+/// Payload bytes that fit on one page of a `dim` spiral.
+pub fn page_capacity(dim: u32) -> usize {
+    SpiralGeometry::capacity(dim).saturating_sub(HEADER_LEN)
+}
+
+/// Split a payload, typically the output of `Pipeline::encode`, into pages.
+pub fn split(payload: &[u8], dim: u32) -> Result<Vec<Page>, BackupError> {
+    let capacity = page_capacity(dim);
+    let total_len =
+        u32::try_from(payload.len()).map_err(|_| BackupError::TooLarge(payload.len()))?;
+    let count = payload.len().div_ceil(capacity.max(1)).max(1);
+    let count = u16::try_from(count).map_err(|_| BackupError::TooLarge(payload.len()))?;
+    let checksum = crc32fast::hash(payload);
+
+    let mut chunks: Vec<&[u8]> = payload.chunks(capacity.max(1)).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, data)| Page {
+            header: PageHeader {
+                index: index as u16,
+                count,
+                total_len,
+                checksum,
+            },
+            data: data.to_vec(),
+        })
+        .collect())
+}
+
+/// Reassemble pages, in any order and with duplicates, back into the payload.
+pub fn join(pages: &[Page]) -> Result<Vec<u8>, BackupError> {
+    let first = pages
+        .first()
+        .ok_or(BackupError::MissingPages(vec![]))?
+        .header;
+
+    let mut slots: Vec<Option<&Page>> = vec![None; first.count as usize];
+    for page in pages {
+        let header = page.header;
+        if (header.count, header.total_len, header.checksum)
+            != (first.count, first.total_len, first.checksum)
+        {
+            return Err(BackupError::Inconsistent);
+        }
+        slots[header.index as usize].get_or_insert(page);
+    }
+
+    let missing: Vec<u16> = (0..first.count)
+        .filter(|&ix| slots[ix as usize].is_none())
+        .map(|ix| ix + 1)
+        .collect();
+    if !missing.is_empty() {
+        return Err(BackupError::MissingPages(missing));
+    }
+
+    let payload: Vec<u8> = slots
+        .into_iter()
+        .flatten()
+        .flat_map(|p| p.data.clone())
+        .collect();
+    if payload.len() != first.total_len as usize || crc32fast::hash(&payload) != first.checksum {
+        return Err(BackupError::Checksum);
+    }
+
+    Ok(payload)
+}
+
+/**
+ * Render a payload onto printable sheets, one page of spiral per sheet with finder
+ * patterns so scans can be read back with `restore`. Each sheet is headed by `title`,
+ * its page number and the payload's length and checksum.
+ */
+pub fn backup(
+    payload: &[u8],
+    title: &str,
+    layout: &Layout,
+    paper: Paper,
+) -> Result<Paper, BackupError> {
+    // The paper does the module scaling, render one pixel per cell.
+    let cells = Layout::new(layout.dim)
+        .with_quiet(layout.quiet)
+        .with_finders(true);
+    let mut paper = paper.with_module(layout.module);
+
+    for page in split(payload, layout.dim)? {
+        let header = page.header;
+        let mut geometry = SpiralGeometry::new(cells.dim);
+        let encoder = Encoder::from_bytes(cells.dim, &page.to_bytes(), &mut geometry);
+        let mut bitmap = Bitmap::with_layout(cells);
+        encoder.render(&mut bitmap);
+
+        let lines = vec![
+            match title {
+                "" => String::from("tim paper backup"),
+                title => format!("tim paper backup: {}", title),
+            },
+            format!(
+                "page {} of {}   {} bytes   crc32 {:08x}   page crc32 {:08x}",
+                header.index + 1,
+                header.count,
+                header.total_len,
+                header.checksum,
+                crc32fast::hash(&page.data)
+            ),
+            String::from("restore: scan every page, then run `tim restore SCAN...`"),
+        ];
+        paper.add_sheet(lines, bitmap.buf);
+    }
+
+    Ok(paper)
+}
+
+/// Decode scans of backup sheets, in any order, and reassemble the payload. Damaged
+/// scans of a page are skipped as long as another scan of it reads intact.
+pub fn restore(
+    scans: &[Vec<u8>],
+    limits: &DecodeLimits,
+    layout: &Layout,
+    threshold: &Threshold,
+) -> Result<Vec<u8>, BackupError> {
+    let sampling = Sampling::Finders(layout.with_finders(true));
+
+    let mut pages = vec![];
+    let mut failed = None;
+    for (ix, scan) in scans.iter().enumerate() {
+        let report = match decode_spiral_report(scan, limits, &sampling, threshold) {
+            Ok(report) => report,
+            Err(e) => {
+                failed.get_or_insert(BackupError::Scan(ix, e));
+                continue;
+            }
+        };
+
+        match report.payload.as_deref().and_then(Page::from_bytes) {
+            Some(page) => pages.push(page),
+            None => {
+                failed.get_or_insert(BackupError::NotABackupPage(ix));
+            }
+        }
+    }
+
+    // A scan that failed only matters if its page can't be had from another one, and
+    // join reports those as missing. With nothing read at all, say why.
+    if let (true, Some(e)) = (pages.is_empty(), failed) {
+        return Err(e);
+    }
+
+    join(&pages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Point;
+
+    /// A clean scan of one page, as rendered on its sheet.
+    fn scan(page: &Page, layout: Layout) -> Vec<u8> {
+        let mut geometry = SpiralGeometry::new(layout.dim);
+        let encoder: Encoder<Point> =
+            Encoder::from_bytes(layout.dim, &page.to_bytes(), &mut geometry);
+        encoder.to_bytes(&mut Bitmap::with_layout(layout)).unwrap()
+    }
+
+    #[test]
+    fn pages_join_in_any_order() {
+        let payload: Vec<u8> = (0..5000).map(|n| (n % 251) as u8).collect();
+        let mut pages: Vec<Page> = split(&payload, 256)
+            .unwrap()
+            .iter()
+            .map(|page| Page::from_bytes(&page.to_bytes()).unwrap())
+            .collect();
+        assert_eq!(pages.len(), payload.len().div_ceil(page_capacity(256)));
+
+        pages.reverse();
+        pages.push(pages[0].clone());
+        assert_eq!(join(&pages).unwrap(), payload);
+
+        let empty = split(b"", 256).unwrap();
+        assert_eq!(empty.len(), 1);
+        assert_eq!(join(&empty).unwrap(), b"");
+    }
+
+    #[test]
+    fn reports_missing_and_mixed_pages() {
+        let payload = vec![7u8; page_capacity(256) * 3];
+        let pages = split(&payload, 256).unwrap();
+        assert!(matches!(
+            join(&[pages[1].clone()]),
+            Err(BackupError::MissingPages(missing)) if missing == [1, 3]
+        ));
+
+        let other = split(&vec![8u8; payload.len()], 256).unwrap();
+        let mixed = [pages[0].clone(), other[1].clone(), pages[2].clone()];
+        assert!(matches!(join(&mixed), Err(BackupError::Inconsistent)));
+
+        let mut damaged = pages[0].to_bytes();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        assert!(Page::from_bytes(&damaged).is_none());
+    }
+
+    #[test]
+    fn restores_from_scans() {
+        let layout = Layout::new(256).with_quiet(4).with_finders(true);
+        let payload: Vec<u8> = (0..3000).map(|n| (n * 7 % 256) as u8).collect();
+        let scans: Vec<Vec<u8>> = split(&payload, 256)
+            .unwrap()
+            .iter()
+            .rev()
+            .map(|page| scan(page, layout))
+            .collect();
+
+        let restored = restore(&scans, &DecodeLimits::default(), &layout, &Threshold::Auto);
+        assert_eq!(restored.unwrap(), payload);
+
+        let partial = restore(
+            &scans[..1],
+            &DecodeLimits::default(),
+            &layout,
+            &Threshold::Auto,
+        );
+        assert!(matches!(partial, Err(BackupError::MissingPages(_))));
+    }
+}
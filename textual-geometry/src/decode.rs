@@ -0,0 +1,311 @@
+use std::fmt;
+
+use crate::geometry::{Point, SpiralGeometry, SpiralReading};
+use crate::rendering::{fiducial, Bitmap, Layout, Scrambler, Stego, StegoLayout};
+use crate::threshold::Threshold;
+use image::ImageError;
+
+/// Bounds applied when decoding images from an untrusted source.
+#[derive(Clone, Debug)]
+pub struct DecodeLimits {
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Upper bound on bytes the image decoder and the point buffer may allocate.
+    pub max_alloc: u64,
+    /// Upper bound on the length of the recovered payload in bytes.
+    pub max_decoded_len: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_width: 4096,
+            max_height: 4096,
+            max_alloc: 64 * 1024 * 1024,
+            max_decoded_len: 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Image(ImageError),
+    TooLarge { width: u32, height: u32 },
+    AllocationLimit(u64),
+    NotSquare { width: u32, height: u32 },
+    NotMultipleOf4(u32),
+    PayloadTooLarge(usize),
+    FindersNotFound,
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Image(e) => write!(f, "failed to decode image: {}", e),
+            DecodeError::TooLarge { width, height } => {
+                write!(f, "image dimensions {}x{} exceed limits", width, height)
+            }
+            DecodeError::AllocationLimit(n) => {
+                write!(f, "decoding would allocate {} bytes, over the limit", n)
+            }
+            DecodeError::NotSquare { width, height } => {
+                write!(f, "expected a square image, got {}x{}", width, height)
+            }
+            DecodeError::NotMultipleOf4(dim) => {
+                write!(f, "image dimension {} is not a multiple of 4", dim)
+            }
+            DecodeError::PayloadTooLarge(n) => {
+                write!(f, "decoded payload of {} bytes exceeds limit", n)
+            }
+            DecodeError::FindersNotFound => write!(f, "could not locate finder patterns"),
+            DecodeError::Malformed => write!(f, "image does not contain a valid geometry"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<ImageError> for DecodeError {
+    fn from(e: ImageError) -> Self {
+        DecodeError::Image(e)
+    }
+}
+
+/// How cells are recovered from an image before they are thresholded.
+#[derive(Clone, Debug)]
+pub enum Sampling {
+    /// One pixel per cell, as rendered with the default layout.
+    Exact,
+    /// Cells resampled relative to the image size, for module scaling and quiet zones.
+    Layout(Layout),
+    /// The grid is located by its finder patterns, for photos and screenshots.
+    Finders(Layout),
+    /// The grid is hidden in a bit-plane of a cover image.
+    Stego(StegoLayout),
+    /// The rendered image was scrambled, it is unscrambled before sampling the layout.
+    Scrambled(Layout, Scrambler),
+}
+
+/// The outcome of a decode along with where, if anywhere, it went wrong.
+#[derive(Clone, Debug)]
+pub struct DecodeReport {
+    pub reading: SpiralReading,
+    /// The decoded bytes. None if the recovered sequence isn't valid hex, typically
+    /// because an odd number of characters were lost.
+    pub payload: Option<Vec<u8>>,
+}
+
+/// Decode a spiral encoded image held in memory without trusting its contents.
+///
+/// Every failure mode is reported as a `DecodeError`; this never panics on arbitrary input.
+pub fn decode_spiral(bytes: &[u8], limits: &DecodeLimits) -> Result<Vec<u8>, DecodeError> {
+    decode_payload(bytes, limits, &Sampling::Exact)
+}
+
+/// Decode a spiral rendered with module scaling and/or a quiet zone. The image may have
+/// been rescaled since, cells are sampled relative to its actual size.
+pub fn decode_spiral_layout(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    layout: &Layout,
+) -> Result<Vec<u8>, DecodeError> {
+    decode_payload(bytes, limits, &Sampling::Layout(*layout))
+}
+
+/// Decode a spiral rendered with finder patterns from a photo or screenshot. The grid is
+/// located by its finders, so the image may be rotated, skewed or surrounded by clutter.
+pub fn decode_spiral_photo(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    layout: &Layout,
+) -> Result<Vec<u8>, DecodeError> {
+    decode_payload(bytes, limits, &Sampling::Finders(*layout))
+}
+
+/// Extract a spiral hidden in a cover image by `Stego`. The layout has to match the one
+/// it was embedded with.
+pub fn decode_spiral_stego(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    layout: &StegoLayout,
+) -> Result<Vec<u8>, DecodeError> {
+    decode_payload(bytes, limits, &Sampling::Stego(*layout))
+}
+
+/// Decode with an explicit threshold and report ambiguous and empty positions and
+/// per position confidence alongside the payload, rather than only the payload.
+pub fn decode_spiral_report(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    sampling: &Sampling,
+    threshold: &Threshold,
+) -> Result<DecodeReport, DecodeError> {
+    let (pregeometry, auto) = match sampling {
+        Sampling::Exact => {
+            let pregeometry = Bitmap::to_points_limited(bytes, limits)?;
+            let ((width, height), _) = pregeometry;
+            if width != height {
+                return Err(DecodeError::NotSquare { width, height });
+            }
+
+            (pregeometry, 0)
+        }
+        Sampling::Layout(layout) => {
+            let cells = check_layout(layout, limits)?;
+            let luma = Bitmap::load_limited(bytes, limits)?;
+            let (width, height) = luma.dimensions();
+
+            // Rescaling rarely keeps the aspect exactly, but a stretched grid won't sample right.
+            if width.abs_diff(height) > width.max(height) / 50 {
+                return Err(DecodeError::NotSquare { width, height });
+            }
+            if width < cells || height < cells {
+                return Err(DecodeError::Malformed);
+            }
+
+            (Bitmap::sample_grid(&luma, layout), 127)
+        }
+        Sampling::Finders(layout) => {
+            check_layout(layout, limits)?;
+            let luma = Bitmap::load_limited(bytes, limits)?;
+            fiducial::locate(&luma, layout).ok_or(DecodeError::FindersNotFound)?
+        }
+        Sampling::Scrambled(layout, scrambler) => {
+            check_layout(layout, limits)?;
+            let imsize = layout.checked_imsize().ok_or(DecodeError::Malformed)?;
+            let mut luma = Bitmap::load_limited(bytes, limits)?;
+            // Unlike plain layouts this has to be the exact rendered image, any
+            // resampling scatters into noise once unscrambled.
+            if luma.dimensions() != (imsize, imsize) {
+                return Err(DecodeError::Malformed);
+            }
+            scrambler.invert(&mut luma);
+
+            (Bitmap::sample_grid(&luma, layout), 127)
+        }
+        Sampling::Stego(layout) => {
+            check_grid(layout.dim, limits)?;
+            let image = Bitmap::load_limited_image(bytes, limits)?.into_rgb8();
+            let pregeometry = Stego::extract(&image, layout).ok_or(DecodeError::Malformed)?;
+            (pregeometry, 127)
+        }
+    };
+
+    let ((dim, _), _) = pregeometry;
+    if dim == 0 || !dim.is_multiple_of(4) {
+        return Err(DecodeError::NotMultipleOf4(dim));
+    }
+
+    let levels = threshold.levels(&pregeometry, auto);
+    let reading = SpiralGeometry::new(dim)
+        .read(&pregeometry, &levels)
+        .ok_or(DecodeError::Malformed)?;
+
+    // Two hex chars per byte, check before allocating the decoded buffer.
+    if reading.sequence.len() / 2 > limits.max_decoded_len {
+        return Err(DecodeError::PayloadTooLarge(reading.sequence.len() / 2));
+    }
+
+    let payload = hex::decode(&reading.sequence).ok();
+    Ok(DecodeReport { reading, payload })
+}
+
+/**
+ * Check a layout, which may have come from the image's own metadata, before sampling
+ * by it: the grid has to be a spiral's and the points sampled from it have to fit in
+ * `max_alloc`. Returns the image's side length in cells.
+ */
+fn check_layout(layout: &Layout, limits: &DecodeLimits) -> Result<u32, DecodeError> {
+    check_grid(layout.dim, limits)?;
+    layout.cells().ok_or(DecodeError::Malformed)
+}
+
+/// A `dim` x `dim` grid has to be a spiral's, and its points have to fit in `max_alloc`.
+fn check_grid(dim: u32, limits: &DecodeLimits) -> Result<(), DecodeError> {
+    if dim == 0 || !dim.is_multiple_of(4) {
+        return Err(DecodeError::NotMultipleOf4(dim));
+    }
+
+    let points = dim as u64 * dim as u64;
+    let required = points.saturating_mul(std::mem::size_of::<Point>() as u64);
+    if required > limits.max_alloc {
+        return Err(DecodeError::AllocationLimit(required));
+    }
+
+    Ok(())
+}
+
+fn decode_payload(
+    bytes: &[u8],
+    limits: &DecodeLimits,
+    sampling: &Sampling,
+) -> Result<Vec<u8>, DecodeError> {
+    decode_spiral_report(bytes, limits, sampling, &Threshold::Auto)?
+        .payload
+        .ok_or(DecodeError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::Encoder;
+    use crate::geometry::Point;
+
+    fn png(payload: &[u8], layout: Layout) -> Vec<u8> {
+        let mut geometry = SpiralGeometry::new(256);
+        let encoder: Encoder<Point> = Encoder::from_bytes(256, payload, &mut geometry);
+        encoder.to_bytes(&mut Bitmap::with_layout(layout)).unwrap()
+    }
+
+    #[test]
+    fn decodes_a_scaled_layout() {
+        let layout = Layout::new(256).with_module(2).with_quiet(4);
+        let png = png(b"scaled", layout);
+
+        let decoded = decode_spiral_layout(&png, &DecodeLimits::default(), &layout).unwrap();
+        assert_eq!(decoded, b"scaled");
+    }
+
+    #[test]
+    fn refuses_layouts_that_overflow() {
+        let png = png(b"overflow", Layout::new(256));
+        let layout = Layout::new(4294967292).with_quiet(2);
+
+        for sampling in [Sampling::Layout(layout), Sampling::Finders(layout)] {
+            let decoded =
+                decode_spiral_report(&png, &DecodeLimits::default(), &sampling, &Threshold::Auto);
+            assert!(decoded.is_err());
+        }
+    }
+
+    #[test]
+    fn counts_the_sampled_grid_against_max_alloc() {
+        let png = png(b"grid", Layout::new(256));
+        let layout = Layout::new(1 << 20);
+
+        let decoded = decode_spiral_layout(&png, &DecodeLimits::default(), &layout);
+        assert!(matches!(decoded, Err(DecodeError::AllocationLimit(_))));
+    }
+
+    #[test]
+    fn checks_stego_grids_like_layouts() {
+        let cover = image::RgbImage::from_pixel(300, 300, image::Rgb([90, 140, 200]));
+        let layout = StegoLayout::new(256).with_plane(1).with_seed(7);
+        let mut stego = Stego::with_cover(cover, layout).unwrap();
+        let mut geometry = SpiralGeometry::new(256);
+        let encoder: Encoder<Point> = Encoder::from_bytes(256, b"hidden", &mut geometry);
+        let png = encoder.to_bytes(&mut stego).unwrap();
+
+        let limits = DecodeLimits::default();
+        assert_eq!(
+            decode_spiral_stego(&png, &limits, &layout).unwrap(),
+            b"hidden"
+        );
+
+        let odd = decode_spiral_stego(&png, &limits, &StegoLayout::new(254));
+        assert!(matches!(odd, Err(DecodeError::NotMultipleOf4(254))));
+        let huge = decode_spiral_stego(&png, &limits, &StegoLayout::new(1 << 20));
+        assert!(matches!(huge, Err(DecodeError::AllocationLimit(_))));
+    }
+}
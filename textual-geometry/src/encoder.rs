@@ -1,57 +1,43 @@
-use crate::geometry::{Geometry, LossyPoint, Point};
-use crate::rendering::{Bitmap, Svg};
-use hex;
+use std::io;
 
-pub struct Encoder<'a> {
-    geometry: &'a mut dyn Geometry<Point>,
-    dim: u32,
+use crate::geometry::Geometry;
+use crate::rendering::Renderer;
+
+/// Pairs a geometry with any renderer that understands its point type.
+pub struct Encoder<'a, PointType> {
+    geometry: &'a mut dyn Geometry<PointType>,
 }
 
-impl<'a> Encoder<'a> {
+impl<'a, PointType> Encoder<'a, PointType> {
     pub fn from_sequence(
         dim: u32,
         input_sequence: String,
-        geometry: &'a mut dyn Geometry<Point>,
+        geometry: &'a mut dyn Geometry<PointType>,
     ) -> Self {
-        let hex_repr = hex::encode(input_sequence);
+        Encoder::from_bytes(dim, input_sequence.as_bytes(), geometry)
+    }
+
+    /// Encode an arbitrary byte payload, typically the output of `Pipeline::encode`.
+    pub fn from_bytes(dim: u32, input: &[u8], geometry: &'a mut dyn Geometry<PointType>) -> Self {
+        let hex_repr = hex::encode(input);
 
         geometry.set_dim(dim);
         geometry.translate(hex_repr);
 
-        Encoder { geometry, dim }
+        Encoder { geometry }
     }
 
-    pub fn to(&self, path: &str) {
-        let mut bitmap = Bitmap::new(self.dim);
-        bitmap.from_geometry(self.geometry);
-        bitmap.save(path);
+    pub fn render(&self, renderer: &mut dyn Renderer<PointType>) {
+        renderer.render(self.geometry);
     }
-}
-
-pub struct LossyEncoder<'a> {
-    geometry: &'a mut dyn Geometry<LossyPoint>,
-    dim: u32,
-    pad: u32,
-}
-
-impl<'a> LossyEncoder<'a> {
-    pub fn from_sequence(
-        dim: u32,
-        pad: u32,
-        input_sequence: String,
-        geometry: &'a mut dyn Geometry<LossyPoint>,
-    ) -> Self {
-        let hex_repr = hex::encode(input_sequence);
-
-        geometry.set_dim(dim);
-        geometry.translate(hex_repr);
 
-        LossyEncoder { geometry, dim, pad }
+    pub fn to(&self, renderer: &mut dyn Renderer<PointType>, path: &str) -> io::Result<()> {
+        self.render(renderer);
+        renderer.write_to_path(path)
     }
 
-    pub fn to(&self, path: &str) {
-        let mut svg = Svg::new(self.dim, self.pad);
-        svg.from_geometry(self.geometry);
-        svg.export(path);
+    pub fn to_bytes(&self, renderer: &mut dyn Renderer<PointType>) -> io::Result<Vec<u8>> {
+        self.render(renderer);
+        renderer.to_bytes()
     }
 }
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod geometry;
 pub mod nhedron_geometry;
 pub mod spiral_geometry;
@@ -1,4 +1,33 @@
 use super::{Geometry, Point, PreGeometry, ReversibleGeometry};
+use crate::threshold;
+
+/// What was read back from each position along the spiral, up to the last position
+/// with anything lit. Positions index the fold order, two per encoded byte.
+#[derive(Clone, Debug, Default)]
+pub struct SpiralReading {
+    /// Recovered hex sequence. Ambiguous positions contribute their brightest cell,
+    /// empty ones contribute nothing.
+    pub sequence: String,
+    /// Positions where more than one character's cell was lit.
+    pub ambiguous: Vec<usize>,
+    /// Positions where no cell was lit although later ones were, i.e. lost characters.
+    pub empty: Vec<usize>,
+    /// Per position confidence in `0.0..=1.0`: how far the least certain of the 16
+    /// cells sits from its level, relative to the room it had on that side. Ambiguous
+    /// positions score the gap between their two brightest cells, empty ones zero.
+    pub confidence: Vec<f32>,
+}
+
+impl SpiralReading {
+    /// True if every position read exactly one character.
+    pub fn is_clean(&self) -> bool {
+        self.ambiguous.is_empty() && self.empty.is_empty()
+    }
+
+    pub fn min_confidence(&self) -> f32 {
+        self.confidence.iter().copied().fold(1., f32::min)
+    }
+}
 
 pub struct SpiralGeometry {
     points: Vec<Point>,
@@ -9,7 +38,7 @@ pub struct SpiralGeometry {
 impl SpiralGeometry {
     pub fn new(dim: u32) -> Self {
         assert!(
-            dim % 4 == 0,
+            dim.is_multiple_of(4),
             "Ensure spiral::Geometry dim attr is divisible evenly by 4"
         );
 
@@ -19,11 +48,17 @@ impl SpiralGeometry {
         }
     }
 
+    /// Bytes a `dim` spiral holds. Every position of its dim/4 grid takes one hex
+    /// character, two to a byte.
+    pub const fn capacity(dim: u32) -> usize {
+        (dim as usize / 4) * (dim as usize / 4) / 2
+    }
+
     /**
      * Form a dim/4 spiral grid and perform an action cb() at each x/y
      */
-    fn fold(dim: u32, mut cb: impl FnMut(u32, u32) -> ()) {
-        assert!(dim % 4 == 0);
+    fn fold(dim: u32, mut cb: impl FnMut(u32, u32)) {
+        assert!(dim.is_multiple_of(4));
 
         let outer_offset_step = dim / 4;
 
@@ -55,18 +90,102 @@ impl SpiralGeometry {
                 y -= 1;
             }
 
-            // increase inner offset
+            // increase inner offset, starting the next ring on its own corner so
+            // the last cell of this ring isn't visited twice
             inner_offset += 1;
-            x = inner_offset - 1;
+            x = inner_offset;
             y = inner_offset;
         }
     }
+
+    /**
+     * Read a sampled grid back into a hex sequence, classifying each cell as lit when
+     * its luma is above its entry in `levels` and recording where that went wrong.
+     * None if the grid isn't a complete dim x dim raster.
+     */
+    pub fn read(&self, pregeometry: &PreGeometry, levels: &[u8]) -> Option<SpiralReading> {
+        let ((width, height), points) = pregeometry;
+        let chars: [char; 16] = [
+            '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f',
+        ];
+
+        // The grid lookups below assume a complete dim x dim raster, anything else
+        // is not a spiral we produced.
+        let cells = (self.dim as usize) * (self.dim as usize);
+        if *width != self.dim
+            || *height != self.dim
+            || points.len() != cells
+            || levels.len() != cells
+        {
+            return None;
+        }
+
+        let outer_offset_step = self.dim / 4;
+        let dim = self.dim as usize;
+
+        let mut positions: Vec<(Option<char>, bool, f32)> = vec![];
+        let read_position = |x: u32, y: u32| {
+            let mut lit: Vec<(usize, u8)> = vec![];
+            let mut certainty = 1f32;
+
+            for cursor_ix in 0..16 {
+                // Each character owns one dim/4 block, 4 across and 4 down.
+                let c_x = (cursor_ix % 4) * outer_offset_step + x;
+                let c_y = (cursor_ix / 4) * outer_offset_step + y;
+                let ix = c_y as usize * dim + c_x as usize;
+
+                let luma = threshold::clamp_luma(points[ix].z);
+                let level = levels[ix];
+                if luma > level {
+                    lit.push((cursor_ix as usize, luma));
+                    if level < 255 {
+                        certainty = certainty.min((luma - level) as f32 / (255 - level) as f32);
+                    }
+                } else if level > 0 {
+                    certainty = certainty.min((level - luma) as f32 / level as f32);
+                }
+            }
+
+            lit.sort_by_key(|&(_, luma)| std::cmp::Reverse(luma));
+            let position = match lit.as_slice() {
+                [] => (None, false, 0.),
+                [(ix, _)] => (Some(chars[*ix]), false, certainty),
+                [(ix, first), (_, second), ..] => {
+                    (Some(chars[*ix]), true, (first - second) as f32 / 255.)
+                }
+            };
+            positions.push(position);
+        };
+
+        SpiralGeometry::fold(self.dim, read_position);
+
+        // Trailing empty positions are just the end of the payload.
+        let end = positions
+            .iter()
+            .rposition(|(c, _, _)| c.is_some())
+            .map_or(0, |last| last + 1);
+        positions.truncate(end);
+
+        let mut reading = SpiralReading::default();
+        for (ix, (c, ambiguous, confidence)) in positions.into_iter().enumerate() {
+            match c {
+                Some(c) => reading.sequence.push(c),
+                None => reading.empty.push(ix),
+            }
+            if ambiguous {
+                reading.ambiguous.push(ix);
+            }
+            reading.confidence.push(confidence);
+        }
+
+        Some(reading)
+    }
 }
 
 impl Geometry<Point> for SpiralGeometry {
     fn set_dim(&mut self, dim: u32) {
         assert!(
-            dim % 4 == 0,
+            dim.is_multiple_of(4),
             "Ensure spiral::Geometry dim attr is divisible evenly by 4"
         );
         self.dim = dim;
@@ -124,57 +243,61 @@ impl Geometry<Point> for SpiralGeometry {
 
 impl ReversibleGeometry for SpiralGeometry {
     fn reverse(&mut self, pregeometry: PreGeometry) -> Option<String> {
-        let (_, points) = pregeometry;
-        let chars: [char; 16] = [
-            '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f',
-        ];
-
-        let outer_offset_step = self.dim / 4;
+        // Any luma at all counts as lit, exact renders are pure black and white.
+        let levels = vec![0; pregeometry.1.len()];
+        self.read(&pregeometry, &levels)
+            .map(|reading| reading.sequence)
+    }
+}
 
-        let mut cursors: Vec<Point> = Vec::with_capacity(16);
-        for i in 0..=15 {
-            let x_offset = i % 4;
-            let y_offset = i / 4;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            // Initialize each cursor at the first ix of it's respective character
-            cursors.push(Point {
-                x: x_offset * outer_offset_step,
-                y: y_offset * outer_offset_step,
-                z: None,
+    fn raster(geometry: &SpiralGeometry, dim: u32) -> PreGeometry {
+        let mut points: Vec<Point> = (0..dim * dim)
+            .map(|i| Point {
+                x: i % dim,
+                y: i / dim,
+                z: Some(0),
             })
+            .collect();
+        for point in geometry.get_points() {
+            points[(point.y * dim + point.x) as usize].z = point.z;
         }
+        ((dim, dim), points)
+    }
 
-        let points_grid: Vec<&[Point]> = points.chunks(self.dim as usize).collect();
-
-        let mut reconstructed = String::default();
-
-        let next_char = |x: u32, y: u32| {
-            let next_chars: Vec<char> = cursors
-                .iter()
-                .enumerate()
-                .filter_map(|(cursor_ix, cursor)| {
-                    let c_x = cursor.x + x;
-                    let c_y = cursor.y + y;
-
-                    let row = points_grid.get(c_y as usize).unwrap();
-                    let v = &row[c_x as usize];
-
-                    if v.z.unwrap_or(0) > 0 {
-                        let char_at_ix = chars[cursor_ix];
-                        return Some(char_at_ix);
-                    }
+    #[test]
+    fn round_trips_past_the_first_ring() {
+        // The outer ring of a 256 spiral holds 252 characters, this runs several rings in.
+        let sequence: String = (0..1000u32)
+            .map(|i| char::from_digit((i * 7 + i / 16) % 16, 16).unwrap())
+            .collect();
 
-                    return None;
-                })
-                .collect();
+        let mut geometry = SpiralGeometry::new(256);
+        geometry.translate(sequence.clone());
+        let pregeometry = raster(&geometry, 256);
 
-            if let Some(c) = next_chars.get(0) {
-                reconstructed.push(*c);
-            }
-        };
+        assert_eq!(geometry.reverse(pregeometry), Some(sequence));
+    }
 
-        SpiralGeometry::fold(self.dim, next_char);
+    #[test]
+    fn holds_its_capacity() {
+        for dim in [16, 64, 256] {
+            let mut positions = 0;
+            SpiralGeometry::fold(dim, |_, _| positions += 1);
+            assert_eq!(positions, 2 * SpiralGeometry::capacity(dim), "{}", dim);
+        }
+        assert_eq!(SpiralGeometry::capacity(256), 2048);
 
-        return Some(reconstructed);
+        let full = hex::encode(
+            (0..2048u32)
+                .map(|i| (i * 31 + i / 7) as u8)
+                .collect::<Vec<_>>(),
+        );
+        let mut geometry = SpiralGeometry::new(256);
+        geometry.translate(full.clone());
+        assert_eq!(geometry.reverse(raster(&geometry, 256)), Some(full));
     }
 }
@@ -1,6 +1,14 @@
+pub mod analysis;
+pub mod backup;
+pub mod decode;
 pub mod encoder;
 pub mod geometry;
+pub mod metadata;
+pub mod pipeline;
 pub mod rendering;
+pub mod sharing;
+pub mod signing;
+pub mod threshold;
 
 pub trait Encoder {
     fn from_sequence(input_sequence: String) -> Self;
@@ -1,18 +1,31 @@
 extern crate getopts;
 
-use getopts::Options;
+use getopts::{Matches, Options};
 use std::env;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Read, Write};
+use textual_geometry::analysis::Analysis;
+use textual_geometry::backup;
+use textual_geometry::decode::{decode_spiral_report, DecodeLimits, DecodeReport, Sampling};
 use textual_geometry::encoder::Encoder;
-use textual_geometry::encoder::LossyEncoder;
 use textual_geometry::geometry::NHedronGeometry;
+use textual_geometry::geometry::Point;
 use textual_geometry::geometry::SpiralGeometry;
-use textual_geometry::geometry::{Geometry, ReversibleGeometry};
-use textual_geometry::rendering::bitmap::Bitmap;
+use textual_geometry::metadata::{EncodingParams, FORMAT};
+use textual_geometry::pipeline::{stage_by_name, Pipeline};
+use textual_geometry::rendering::{
+    Bitmap, Layout, Paper, PaperFormat, PaperSize, Renderer, Scrambler, Stego, StegoLayout, Svg,
+    VisualCrypto, VisualMode, MAX_OR_SHARES,
+};
+use textual_geometry::sharing::{self, Share};
+use textual_geometry::signing::{self, Signed, VerifyingKey};
+use textual_geometry::threshold::Threshold;
 
 fn print_usage(program: &str, opts: Options) {
     let descript = "Encode sequential text data to and from image geometry";
-    let brief = format!("Usage: {} [options]\n{}", program, descript);
+    let brief = format!(
+        "Usage: {0} [options]\n       {0} backup [options] -p OUT.pdf < FILE\n       {0} restore [options] SCAN...\n       {0} analyze [--scramble KEY] PLAIN.png [CIPHER.png]\n       {0} -d spiral -p SHARE.png -p SHARE.png...\n       {0} keygen -p KEYFILE\n       {0} combine [--visual-mode MODE] [--overlay] -p OUT.png SHARE...\n{1}",
+        program, descript
+    );
     print!("{}", opts.usage(&brief));
 }
 
@@ -33,12 +46,130 @@ fn main() {
         "Geometry format with which to decode the input image",
         "[spiral]",
     );
-    opts.optopt(
+    opts.optmulti(
         "p",
         "path",
-        "Path to geometry file",
+        "Path to geometry file, repeat to decode shares together",
         "/path/to/my/geometry.png",
     );
+    opts.optopt(
+        "z",
+        "compress",
+        "Compress the input before encoding, applied ahead of any other stage",
+        "[deflate, zstd, brotli]",
+    );
+    opts.optmulti(
+        "s",
+        "stage",
+        "Byte level stage applied before encoding, repeat to stack stages in order",
+        "[frame, fec:N, interleave:N]",
+    );
+    opts.optopt(
+        "k",
+        "module",
+        "Render each cell as a KxK pixel square so the image survives rescaling",
+        "K",
+    );
+    opts.optopt(
+        "q",
+        "quiet",
+        "Width of the blank border around the grid, in cells",
+        "Q",
+    );
+    opts.optflag(
+        "f",
+        "finders",
+        "Draw finder patterns when encoding, locate them in a photo when decoding",
+    );
+    opts.optopt(
+        "t",
+        "threshold",
+        "How decoded cells are split into lit and dark: auto, otsu, local[:R] or a level 0-255",
+        "MODE",
+    );
+    opts.optflag(
+        "",
+        "report",
+        "After decoding, list ambiguous, empty and low confidence positions on stderr",
+    );
+    opts.optopt(
+        "",
+        "dpi",
+        "Printer resolution for backup sheets, each cell is K dots wide",
+        "DPI",
+    );
+    opts.optopt("", "paper", "Backup sheet size", "[a4|letter]");
+    opts.optopt(
+        "",
+        "cover",
+        "Hide the encoding in a bit-plane of this image instead of drawing it on black",
+        "IMAGE",
+    );
+    opts.optflag(
+        "",
+        "stego",
+        "Decode an encoding hidden in a cover image with --cover",
+    );
+    opts.optopt(
+        "",
+        "plane",
+        "Bit-plane of the cover carrying the encoding, 0 is the least significant",
+        "0-7",
+    );
+    opts.optopt(
+        "",
+        "seed",
+        "Keystream seed the hidden encoding is whitened with",
+        "SEED",
+    );
+    opts.optopt(
+        "",
+        "scramble",
+        "Scramble pixel positions and intensities with chaotic maps keyed by KEY",
+        "KEY",
+    );
+    opts.optopt(
+        "",
+        "shares",
+        "Split the input into N share images, written next to the path as name-1.png...",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "quorum",
+        "How many of the --shares images it takes to decode, all of them by default",
+        "K",
+    );
+    opts.optopt(
+        "",
+        "visual",
+        "Split the rendered image into N visual cryptography shares, written like --shares",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "visual-mode",
+        "Visual shares are stacked on transparencies or combined by xor",
+        "[or|xor]",
+    );
+    opts.optflag(
+        "",
+        "overlay",
+        "With combine, write the stacked shares as they look rather than the revealed image",
+    );
+    opts.optopt(
+        "",
+        "sign",
+        "Sign the payload and encoding parameters with the secret key in this file",
+        "KEYFILE",
+    );
+    opts.optopt(
+        "",
+        "verify",
+        "Refuse to decode unless signed by the public key in this file",
+        "KEYFILE.pub",
+    );
+    opts.optopt("", "title", "Title printed on every backup sheet", "TITLE");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => {
@@ -46,6 +177,58 @@ fn main() {
         }
     };
 
+    let layout = Layout::new(256)
+        .with_module(opt_u32(&matches, "k", 1))
+        .with_quiet(opt_u32(&matches, "q", 0))
+        .with_finders(matches.opt_present("f"));
+
+    match matches.free.first().map(String::as_str) {
+        Some("backup") => {
+            let Some(path) = matches.opt_str("p") else {
+                print_usage(&program, opts);
+                std::process::exit(1);
+            };
+            // Printed cells need to be a few scanner pixels wide, unlike on screen.
+            let layout = layout
+                .with_module(opt_u32(&matches, "k", 4))
+                .with_quiet(opt_u32(&matches, "q", 4));
+            return paper_backup(&matches, &path, layout);
+        }
+        Some("restore") => {
+            if matches.free.len() < 2 {
+                print_usage(&program, opts);
+                std::process::exit(1);
+            }
+            return paper_restore(&matches.free[1..], &threshold_from(&matches));
+        }
+        Some("analyze") => {
+            if matches.free.len() < 2 || matches.free.len() > 3 {
+                print_usage(&program, opts);
+                std::process::exit(1);
+            }
+            return analyze(
+                &matches.free[1],
+                matches.free.get(2).map(String::as_str),
+                matches.opt_str("scramble").map(|key| Scrambler::new(&key)),
+            );
+        }
+        Some("keygen") => {
+            let Some(path) = matches.opt_str("p") else {
+                print_usage(&program, opts);
+                std::process::exit(1);
+            };
+            return keygen(&path);
+        }
+        Some("combine") => {
+            let Some(path) = matches.opt_str("p") else {
+                print_usage(&program, opts);
+                std::process::exit(1);
+            };
+            return visual_combine(&matches, &path, &matches.free[1..]);
+        }
+        _ => {}
+    }
+
     if matches.opt_present("e") {
         let mut path: String = String::default();
         if let Some(p) = matches.opt_str("p") {
@@ -56,32 +239,124 @@ fn main() {
         }
 
         let input_text = read_stdin();
-        if input_text.len() < 1 {
+        if input_text.is_empty() {
             eprintln!("Nothing to encode.");
             print_usage(&program, opts);
         }
 
-        match matches.opt_str("e").as_deref() {
-            Some("spiral") => spiral_encode(input_text, &path),
-            _ => {
-                println!("Defaulting to Spiral");
-                spiral_encode(input_text, &path)
+        let pipeline = pipeline_from(&matches);
+        let payload = encode_payload(&matches, &input_text, &pipeline);
+
+        let metadata = EncodingParams::new("spiral", 256)
+            .with_layout(&layout)
+            .with_stages(pipeline.stage_names())
+            .with_scrambled(matches.opt_present("scramble"))
+            .with_signed(matches.opt_present("sign"));
+
+        // A cover image hides the spiral in one of its bit-planes, else it's drawn on black.
+        // Hidden ones carry no metadata, that would give them away.
+        let renderer = || -> Box<dyn Renderer<Point>> {
+            match matches.opt_str("cover") {
+                Some(cover) => Box::new(stego_renderer(&cover, stego_layout(&matches))),
+                None => {
+                    let bitmap = Bitmap::with_layout(layout).with_metadata(metadata.clone());
+                    match matches.opt_str("scramble") {
+                        Some(key) => Box::new(bitmap.with_scrambler(Scrambler::new(&key))),
+                        None => Box::new(bitmap),
+                    }
+                }
             }
+        };
+
+        if matches.opt_str("e").as_deref() != Some("spiral") {
+            println!("Defaulting to Spiral");
+        }
+
+        if matches.opt_present("visual") {
+            if matches.opt_present("cover") || matches.opt_present("scramble") {
+                eprintln!("--visual needs a plain black and white rendering");
+                std::process::exit(1);
+            }
+            let mut bitmap = Bitmap::with_layout(layout);
+            spiral_render(&payload, &mut bitmap);
+            visual_split(&matches, &path, &bitmap);
+        } else if matches.opt_present("shares") {
+            let count = opt_u32(&matches, "shares", 0);
+            let quorum = opt_u32(&matches, "quorum", count);
+            let (Ok(count), Ok(quorum)) = (u8::try_from(count), u8::try_from(quorum)) else {
+                eprintln!("--shares and --quorum go up to 255");
+                std::process::exit(1);
+            };
+            spiral_encode_shares(&payload, &path, quorum, count, &renderer)
+        } else {
+            spiral_encode(&payload, &path, renderer().as_mut())
         }
     } else if matches.opt_present("d") {
-        let mut path: String = String::default();
-        if let Some(p) = matches.opt_str("p") {
-            path = p;
-        } else if matches.opt_str("p").is_none() {
+        let paths = matches.opt_strs("p");
+        if paths.is_empty() {
             print_usage(&program, opts);
             std::process::exit(1);
         }
 
+        // Unless told otherwise, images that record how they were made configure the
+        // decoder themselves.
+        let explicit = ["k", "q", "f", "stego"]
+            .iter()
+            .any(|&o| matches.opt_present(o));
+        let metadata = match explicit {
+            true => None,
+            false => std::fs::read(&paths[0])
+                .ok()
+                .and_then(|png| EncodingParams::from_png(&png)),
+        };
+        if let Some(params) = &metadata {
+            // Metadata recording any dim other than 256 is ignored when parsed.
+            if params.geometry != "spiral" {
+                eprintln!(
+                    "{} holds a {} geometry, only spirals can be decoded",
+                    paths[0], params.geometry
+                );
+                std::process::exit(1);
+            }
+            if params.format < FORMAT {
+                eprintln!(
+                    "{} was written before the spiral stopped revisiting a cell per ring, anything past its first 126 bytes may not read back",
+                    paths[0]
+                );
+            }
+        }
+
+        // Scaled or bordered images have to be resampled onto the grid, plain ones are
+        // read pixel for pixel.
+        let sampling = if let Some(key) = matches.opt_str("scramble") {
+            let layout = metadata.as_ref().map_or(layout, EncodingParams::layout);
+            Sampling::Scrambled(layout, Scrambler::new(&key))
+        } else if matches.opt_present("stego") {
+            Sampling::Stego(stego_layout(&matches))
+        } else if let Some(params) = &metadata {
+            params.sampling().unwrap_or_else(|| {
+                eprintln!("{} is scrambled, pass its key with --scramble", paths[0]);
+                std::process::exit(1);
+            })
+        } else if layout.finders {
+            Sampling::Finders(layout)
+        } else if matches.opt_present("k") || matches.opt_present("q") {
+            Sampling::Layout(layout)
+        } else {
+            Sampling::Exact
+        };
+
+        let threshold = threshold_from(&matches);
+        let report = matches.opt_present("report");
+        let verify = matches
+            .opt_str("verify")
+            .map(|path| read_key(&path, signing::verifying_key_from_hex));
+
         match matches.opt_str("d").as_deref() {
-            Some("spiral") => spiral_decode(&path),
+            Some("spiral") => spiral_decode(&paths, &sampling, &threshold, report, verify.as_ref()),
             _ => {
                 println!("Defaulting to Spiral");
-                spiral_decode(&path)
+                spiral_decode(&paths, &sampling, &threshold, report, verify.as_ref())
             }
         }
     } else {
@@ -101,26 +376,523 @@ fn read_stdin() -> String {
         }
     }
 
-    return line;
+    line
+}
+
+fn pipeline_from(matches: &Matches) -> Pipeline {
+    let mut stages = matches.opt_strs("s");
+    if let Some(algorithm) = matches.opt_str("z") {
+        stages.insert(0, algorithm);
+    }
+
+    match Pipeline::from_names(&stages) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn threshold_from(matches: &Matches) -> Threshold {
+    match matches.opt_str("t") {
+        None => Threshold::Auto,
+        Some(mode) => match Threshold::from_name(&mode) {
+            Some(threshold) => threshold,
+            None => {
+                eprintln!("Unknown threshold '{}'", mode);
+                std::process::exit(1);
+            }
+        },
+    }
+}
+
+fn opt_u32(matches: &Matches, name: &str, default: u32) -> u32 {
+    match matches.opt_str(name).map(|v| v.parse::<u32>()) {
+        None => default,
+        Some(Ok(v)) => v,
+        Some(Err(_)) => {
+            eprintln!("-{} expects a positive integer", name);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Run the input through the pipeline, then sign it if asked to.
+fn encode_payload(matches: &Matches, input_text: &str, pipeline: &Pipeline) -> Vec<u8> {
+    let payload = pipeline
+        .encode(input_text.as_bytes())
+        .expect("Failed to run input through the pipeline.");
+
+    match matches.opt_str("sign") {
+        None => payload,
+        Some(path) => {
+            let key = read_key(&path, signing::signing_key_from_hex);
+            signing::sign(&payload, &signing::encoding_params("spiral", 256), &key)
+        }
+    }
+}
+
+fn read_key<K>(path: &str, parse: fn(&str) -> Result<K, signing::SigningError>) -> K {
+    let key = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("Failed to read key {}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    parse(&key).unwrap_or_else(|e| {
+        eprintln!("Failed to read key {}: {}", path, e);
+        std::process::exit(1);
+    })
+}
+
+/// Write a new secret key to `path` and its public key next to it as `path.pub`.
+fn keygen(path: &str) {
+    let key = signing::generate_key().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    let public = format!("{}.pub", path);
+
+    let mut options = std::fs::File::options();
+    options.write(true).create_new(true);
+    // The secret key is for its owner's eyes only.
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let written = options
+        .open(path)
+        .and_then(|mut file| writeln!(file, "{}", hex::encode(key.to_bytes())))
+        .and_then(|_| {
+            std::fs::write(
+                &public,
+                format!("{}\n", hex::encode(key.verifying_key().as_bytes())),
+            )
+        });
+    if let Err(e) = written {
+        eprintln!("Failed to write key {}: {}", path, e);
+        std::process::exit(1);
+    }
+
+    eprintln!("Wrote secret key to {} and public key to {}", path, public);
+}
+
+fn spiral_encode(payload: &[u8], path: &str, renderer: &mut dyn Renderer<Point>) {
+    spiral_render(payload, renderer);
+    renderer
+        .write_to_path(path)
+        .expect("Failed to save your geometry to disk.");
+}
+
+fn spiral_render(payload: &[u8], renderer: &mut dyn Renderer<Point>) {
+    let mut spiral_geo = SpiralGeometry::new(256);
+    let encoder = Encoder::from_bytes(256, payload, &mut spiral_geo);
+    encoder.render(renderer);
+}
+
+fn visual_crypto(matches: &Matches, shares: usize) -> VisualCrypto {
+    let mode = match matches.opt_str("visual-mode") {
+        None => VisualMode::Or,
+        Some(name) => VisualMode::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown visual mode '{}'", name);
+            std::process::exit(1);
+        }),
+    };
+
+    u8::try_from(shares)
+        .ok()
+        .and_then(|shares| VisualCrypto::new(mode, shares))
+        .unwrap_or_else(|| {
+            eprintln!(
+                "Visual cryptography takes 2 to {} shares stacked, or 2 to 255 xored",
+                MAX_OR_SHARES
+            );
+            std::process::exit(1);
+        })
+}
+
+/// Write the rendered bitmap out as visual cryptography shares next to `path`.
+fn visual_split(matches: &Matches, path: &str, bitmap: &Bitmap) {
+    let visual = visual_crypto(matches, opt_u32(matches, "visual", 2) as usize);
+    let shares = visual.split(&bitmap.buf).unwrap_or_else(|e| {
+        eprintln!("No randomness for the shares: {}", e);
+        std::process::exit(1);
+    });
+
+    for (ix, share) in shares.iter().enumerate() {
+        let share_path = share_path(path, ix as u8 + 1);
+        if let Err(e) = share.save(&share_path) {
+            eprintln!("Failed to write {}: {}", share_path, e);
+            std::process::exit(1);
+        }
+        eprintln!(
+            "Wrote visual share {} of {} to {}",
+            ix + 1,
+            shares.len(),
+            share_path
+        );
+    }
+}
+
+/// Combine every visual share given into the image they hide.
+fn visual_combine(matches: &Matches, path: &str, paths: &[String]) {
+    let visual = visual_crypto(matches, paths.len());
+    let shares = paths
+        .iter()
+        .map(|path| {
+            image::open(path)
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to read {}: {}", path, e);
+                    std::process::exit(1);
+                })
+                .into_luma8()
+        })
+        .collect::<Vec<_>>();
+
+    let combined = if matches.opt_present("overlay") {
+        visual.overlay(&shares)
+    } else {
+        visual.reveal(&shares)
+    };
+    let Some(combined) = combined else {
+        eprintln!("Shares don't match, they must all be the same size and from one split");
+        std::process::exit(1);
+    };
+
+    if let Err(e) = combined.save(path) {
+        eprintln!("Failed to write {}: {}", path, e);
+        std::process::exit(1);
+    }
+}
+
+/// Split the pipeline's output into shares and render each as its own spiral, at the
+/// path with the share's index added to the file name.
+fn spiral_encode_shares(
+    payload: &[u8],
+    path: &str,
+    quorum: u8,
+    count: u8,
+    renderer: &dyn Fn() -> Box<dyn Renderer<Point>>,
+) {
+    if payload.len() > sharing::share_capacity(256) {
+        eprintln!("{}", sharing::ShareError::TooLarge(payload.len()));
+        std::process::exit(1);
+    }
+
+    let shares = sharing::split(payload, quorum, count).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    for share in shares {
+        let share_path = share_path(path, share.index);
+        let mut spiral_geo = SpiralGeometry::new(256);
+        let encoder = Encoder::from_bytes(256, &share.to_bytes(), &mut spiral_geo);
+        encoder
+            .to(renderer().as_mut(), &share_path)
+            .expect("Failed to save your geometry to disk.");
+        eprintln!("Wrote share {} of {} to {}", share.index, count, share_path);
+    }
 }
 
-fn spiral_encode(input_text: String, path: &str) {
-    let hex_str = hex::encode(input_text);
-    let dim = 256;
-    let mut spiral_geo = SpiralGeometry::new(dim);
-    spiral_geo.translate(hex_str);
-    let mut bitmap = Bitmap::new(dim);
-    bitmap.from_geometry(&spiral_geo);
-    bitmap.save(&path);
+/// `out.png` becomes `out-1.png`, a path without an extension just gets the suffix.
+fn share_path(path: &str, index: u8) -> String {
+    let name_start = path.rfind('/').map_or(0, |ix| ix + 1);
+    match path[name_start..].rfind('.') {
+        Some(dot) if dot > 0 => {
+            let dot = name_start + dot;
+            format!("{}-{}{}", &path[..dot], index, &path[dot..])
+        }
+        _ => format!("{}-{}", path, index),
+    }
 }
 
-fn spiral_decode(path: &str) {
-    let pregeometry = Bitmap::to_points(path).expect("Failed to load pregeometry from src.");
-    let mut geometry = SpiralGeometry::new(pregeometry.0 .0);
-    let reconstructed = geometry.reverse(pregeometry).unwrap();
-    let bytes = hex::decode(reconstructed).unwrap();
+fn stego_layout(matches: &Matches) -> StegoLayout {
+    let plane = opt_u32(matches, "plane", 0);
+    if plane > 7 {
+        eprintln!("--plane expects a bit-plane from 0 to 7");
+        std::process::exit(1);
+    }
+
+    let seed = match matches.opt_str("seed").map(|v| v.parse::<u64>()) {
+        None => 0,
+        Some(Ok(seed)) => seed,
+        Some(Err(_)) => {
+            eprintln!("--seed expects a positive integer");
+            std::process::exit(1);
+        }
+    };
+
+    StegoLayout::new(256)
+        .with_plane(plane as u8)
+        .with_seed(seed)
+}
+
+fn stego_renderer(cover: &str, layout: StegoLayout) -> Stego {
+    let cover = match image::open(cover) {
+        Ok(cover) => cover.into_rgb8(),
+        Err(e) => {
+            eprintln!("Failed to read cover {}: {}", cover, e);
+            std::process::exit(1);
+        }
+    };
+
+    Stego::with_cover(cover, layout).unwrap_or_else(|| {
+        eprintln!("Cover image must be at least {0}x{0}", layout.dim);
+        std::process::exit(1);
+    })
+}
+
+fn spiral_decode(
+    paths: &[String],
+    sampling: &Sampling,
+    threshold: &Threshold,
+    report: bool,
+    verify: Option<&VerifyingKey>,
+) {
+    let mut clean = true;
+    let mut payloads = vec![];
+    for path in paths {
+        let (payload, read_clean) = decode_image(path, sampling, threshold, report);
+        clean &= read_clean;
+        payloads.push(payload);
+    }
+
+    // A single image is a payload of its own unless it's a share, several have to be
+    // shares of one secret.
+    let bytes = if let [payload] = payloads.as_slice() {
+        match Share::from_bytes(payload) {
+            Some(share) => combine_shares(vec![share]),
+            None => payloads.remove(0),
+        }
+    } else {
+        let shares = paths
+            .iter()
+            .zip(&payloads)
+            .map(|(path, payload)| {
+                Share::from_bytes(payload).unwrap_or_else(|| {
+                    eprintln!("Failed to decode {}: not a share", path);
+                    std::process::exit(1);
+                })
+            })
+            .collect();
+        combine_shares(shares)
+    };
+
+    let params = signing::encoding_params("spiral", 256);
+    let bytes = match (verify, Signed::from_bytes(&bytes)) {
+        (Some(key), _) => signing::verify(&bytes, &params, key).unwrap_or_else(|e| {
+            eprintln!("Failed to verify {}: {}", paths.join(", "), e);
+            std::process::exit(3);
+        }),
+        (None, Some(signed)) => {
+            eprintln!(
+                "Signed by key {}, pass --verify to check the signature",
+                hex::encode(signed.key_id)
+            );
+            signed.payload
+        }
+        (None, None) => bytes,
+    };
+
+    let bytes = match Pipeline::from_manifest(&bytes, stage_by_name)
+        .and_then(|pipeline| pipeline.decode(&bytes))
+    {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to undo pipeline for {}: {}", paths.join(", "), e);
+            std::process::exit(1);
+        }
+    };
     let s = String::from_utf8_lossy(&bytes);
     println!("{}", s);
+
+    // The payload is still printed for salvage, but scripts shouldn't mistake it for clean.
+    if !clean {
+        std::process::exit(2);
+    }
+}
+
+/// Decode one image to its payload bytes, and whether every position read cleanly.
+fn decode_image(
+    path: &str,
+    sampling: &Sampling,
+    threshold: &Threshold,
+    report: bool,
+) -> (Vec<u8>, bool) {
+    let src = std::fs::read(path).expect("Failed to read geometry from src.");
+    let decoded = decode_spiral_report(&src, &DecodeLimits::default(), sampling, threshold);
+    let decoded = match decoded {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            eprintln!("Failed to decode {}: {}", path, e);
+            std::process::exit(1);
+        }
+    };
+
+    let clean = decoded.reading.is_clean();
+    if report {
+        print_report(&decoded);
+    } else if !decoded.reading.is_clean() {
+        eprintln!(
+            "Warning: {} ambiguous and {} empty positions in {}, the payload is likely damaged (see --report)",
+            decoded.reading.ambiguous.len(),
+            decoded.reading.empty.len(),
+            path
+        );
+    }
+
+    match decoded.payload {
+        Some(bytes) => (bytes, clean),
+        None => {
+            eprintln!(
+                "Failed to decode {}: recovered sequence is not valid hex",
+                path
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn combine_shares(shares: Vec<Share>) -> Vec<u8> {
+    sharing::combine(&shares).unwrap_or_else(|e| {
+        eprintln!("Failed to combine shares: {}", e);
+        std::process::exit(1);
+    })
+}
+
+fn paper_backup(matches: &Matches, path: &str, layout: Layout) {
+    let mut input = vec![];
+    io::stdin()
+        .lock()
+        .read_to_end(&mut input)
+        .expect("Failed to read stdin.");
+    if input.is_empty() {
+        eprintln!("Nothing to back up.");
+        std::process::exit(1);
+    }
+
+    let size = match matches.opt_str("paper") {
+        None => PaperSize::A4,
+        Some(name) => PaperSize::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Unknown paper size '{}'", name);
+            std::process::exit(1);
+        }),
+    };
+    let paper = Paper::new(PaperFormat::from_path(path))
+        .with_size(size)
+        .with_dpi(opt_u32(matches, "dpi", 300));
+    let title = matches.opt_str("title").unwrap_or_default();
+
+    let payload = pipeline_from(matches)
+        .encode(&input)
+        .expect("Failed to run input through the pipeline.");
+    let paper = match backup::backup(&payload, &title, &layout, paper) {
+        Ok(paper) => paper,
+        Err(e) => {
+            eprintln!("Failed to back up: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = paper.write_to_path(path) {
+        eprintln!("Failed to write {}: {}", path, e);
+        std::process::exit(1);
+    }
+
+    eprintln!("Wrote {} sheet(s) to {}", paper.sheets().len(), path);
+}
+
+fn paper_restore(paths: &[String], threshold: &Threshold) {
+    let scans = paths
+        .iter()
+        .map(|path| {
+            std::fs::read(path).unwrap_or_else(|e| {
+                eprintln!("Failed to read {}: {}", path, e);
+                std::process::exit(1);
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let limits = DecodeLimits::default();
+    let payload = backup::restore(&scans, &limits, &Layout::new(256), threshold)
+        .map_err(|e| e.to_string())
+        .and_then(|payload| {
+            Pipeline::from_manifest(&payload, stage_by_name)
+                .and_then(|pipeline| pipeline.decode(&payload))
+                .map_err(|e| e.to_string())
+        });
+
+    match payload {
+        // Backups are byte exact, unlike -d nothing is appended.
+        Ok(bytes) => io::stdout()
+            .write_all(&bytes)
+            .expect("Failed to write to stdout."),
+        Err(e) => {
+            eprintln!("Failed to restore: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn analyze(plain: &str, cipher: Option<&str>, scrambler: Option<Scrambler>) {
+    let open = |path: &str| {
+        image::open(path)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read {}: {}", path, e);
+                std::process::exit(1);
+            })
+            .into_luma8()
+    };
+
+    let plain = open(plain);
+    let analysis = match (cipher, scrambler) {
+        (Some(cipher), _) => Analysis::compare(&plain, Some(&open(cipher))),
+        (None, Some(scrambler)) => Analysis::scrambled(&plain, &scrambler).unwrap_or_else(|| {
+            eprintln!("Only square images can be scrambled.");
+            std::process::exit(1);
+        }),
+        (None, None) => Analysis::compare(&plain, None),
+    };
+
+    println!("{}", analysis.to_json());
+}
+
+/// Positions read with less confidence than this are listed by `--report`.
+const LOW_CONFIDENCE: f32 = 0.25;
+
+fn print_report(decoded: &DecodeReport) {
+    let reading = &decoded.reading;
+    let low: Vec<usize> = (0..reading.confidence.len())
+        .filter(|&ix| reading.confidence[ix] < LOW_CONFIDENCE)
+        .collect();
+    let mean = reading.confidence.iter().sum::<f32>() / reading.confidence.len().max(1) as f32;
+
+    eprintln!("positions:      {}", reading.confidence.len());
+    eprintln!("ambiguous:      {}", list_positions(&reading.ambiguous));
+    eprintln!("empty:          {}", list_positions(&reading.empty));
+    eprintln!("low confidence: {}", list_positions(&low));
+    eprintln!(
+        "confidence:     min {:.2}, mean {:.2}",
+        reading.min_confidence(),
+        mean
+    );
+}
+
+fn list_positions(positions: &[usize]) -> String {
+    const SHOWN: usize = 16;
+
+    let mut list = positions
+        .iter()
+        .take(SHOWN)
+        .map(|p| p.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if positions.len() > SHOWN {
+        list.push_str(&format!(" ... ({} total)", positions.len()));
+    }
+    if list.is_empty() {
+        list.push_str("none");
+    }
+    list
 }
 
 #[allow(dead_code)]
@@ -128,10 +900,20 @@ fn encode_all(input_txt: String, cwd: &str) {
     let mut spiral_geo = SpiralGeometry::new(0);
     let spiral_encoder = Encoder::from_sequence(256, input_txt.clone(), &mut spiral_geo);
     let spiral_outfile = format!("{}/output_geometry/{}", cwd, "spiral.png");
-    spiral_encoder.to(&spiral_outfile);
+    spiral_encoder
+        .to(
+            &mut Bitmap::new(256).with_metadata(EncodingParams::new("spiral", 256)),
+            &spiral_outfile,
+        )
+        .expect("Failed to save your geometry to disk.");
 
     let mut nhedron_geo = NHedronGeometry::new(0.);
-    let nhedron_encoder = LossyEncoder::from_sequence(256, 2, input_txt, &mut nhedron_geo);
+    let nhedron_encoder = Encoder::from_sequence(256, input_txt, &mut nhedron_geo);
     let nhedron_outfile = format!("{}/output_geometry/{}", cwd, "nhedron.svg");
-    nhedron_encoder.to(&nhedron_outfile);
+    nhedron_encoder
+        .to(
+            &mut Svg::new(256, 2).with_metadata(EncodingParams::new("nhedron", 256)),
+            &nhedron_outfile,
+        )
+        .expect("Failed to save your geometry to disk.");
 }
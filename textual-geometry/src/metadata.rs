@@ -0,0 +1,412 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::decode::Sampling;
+use crate::rendering::Layout;
+
+/// Keyword prefix of tim's own text chunks, next to the registered `Software` and
+/// `Creation Time` keywords.
+const PREFIX: &str = "tim:";
+const PNG_SIGNATURE: &[u8; 8] = b"\x89PNG\r\n\x1a\n";
+
+/// The only dimension images are encoded at.
+const DIM: u32 = 256;
+/// Largest module a recorded layout is trusted with, past it images run to gigabytes.
+pub const MAX_MODULE: u32 = 16;
+/// Widest quiet zone a recorded layout is trusted with, in cells.
+pub const MAX_QUIET: u32 = 64;
+/// Revision of the order cells are laid out in. Images recording none predate 2, which
+/// fixed the spiral revisiting a cell on every ring and only read back their first 126
+/// bytes reliably.
+pub const FORMAT: u32 = 2;
+
+/**
+ * How an image was made, stored next to the pixels: PNG text chunks and SVG `<metadata>`.
+ * Decoders configure themselves from it when present, so an image on disk doesn't have
+ * to be accompanied by the flags it was encoded with. Nothing secret is recorded, a
+ * scrambled image says so but not with what key.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct EncodingParams {
+    pub geometry: String,
+    pub dim: u32,
+    /// How payload bytes are spelled out along the geometry.
+    pub alphabet: String,
+    /// Pipeline stages, in the order they were applied.
+    pub stages: Vec<String>,
+    pub module: u32,
+    pub quiet: u32,
+    pub finders: bool,
+    pub scrambled: bool,
+    pub signed: bool,
+    /// Cell order revision, see `FORMAT`.
+    pub format: u32,
+    /// Version of the library that wrote the image.
+    pub version: String,
+    /// UTC, ISO 8601.
+    pub created: Option<String>,
+}
+
+impl EncodingParams {
+    /// Parameters for an image being made now by this version of the library.
+    pub fn new(geometry: &str, dim: u32) -> EncodingParams {
+        EncodingParams {
+            geometry: geometry.to_string(),
+            dim,
+            alphabet: String::from("hex"),
+            stages: vec![],
+            module: 1,
+            quiet: 0,
+            finders: false,
+            scrambled: false,
+            signed: false,
+            format: FORMAT,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|since| iso_8601(since.as_secs())),
+        }
+    }
+
+    pub fn with_layout(mut self, layout: &Layout) -> EncodingParams {
+        self.dim = layout.dim;
+        self.module = layout.module;
+        self.quiet = layout.quiet;
+        self.finders = layout.finders;
+        self
+    }
+
+    pub fn with_stages(mut self, stages: Vec<String>) -> EncodingParams {
+        self.stages = stages;
+        self
+    }
+
+    pub fn with_scrambled(mut self, scrambled: bool) -> EncodingParams {
+        self.scrambled = scrambled;
+        self
+    }
+
+    pub fn with_signed(mut self, signed: bool) -> EncodingParams {
+        self.signed = signed;
+        self
+    }
+
+    pub fn layout(&self) -> Layout {
+        Layout::new(self.dim)
+            .with_module(self.module)
+            .with_quiet(self.quiet)
+            .with_finders(self.finders)
+    }
+
+    /// How to sample an image made with these parameters. None when it was scrambled,
+    /// that takes the key as well.
+    pub fn sampling(&self) -> Option<Sampling> {
+        if self.scrambled {
+            return None;
+        }
+
+        let layout = self.layout();
+        Some(if self.finders {
+            Sampling::Finders(layout)
+        } else if self.module > 1 || self.quiet > 0 {
+            Sampling::Layout(layout)
+        } else {
+            Sampling::Exact
+        })
+    }
+
+    /// Keyword and value pairs, in the order they are written.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let mut entries = vec![(String::from("Software"), format!("tim {}", self.version))];
+        if let Some(created) = &self.created {
+            entries.push((String::from("Creation Time"), created.clone()));
+        }
+
+        let flag = |set: bool| String::from(if set { "true" } else { "false" });
+        for (key, value) in [
+            ("geometry", self.geometry.clone()),
+            ("dim", self.dim.to_string()),
+            ("alphabet", self.alphabet.clone()),
+            ("stages", self.stages.join(",")),
+            ("module", self.module.to_string()),
+            ("quiet", self.quiet.to_string()),
+            ("finders", flag(self.finders)),
+            ("scrambled", flag(self.scrambled)),
+            ("signed", flag(self.signed)),
+            ("format", self.format.to_string()),
+        ] {
+            entries.push((format!("{}{}", PREFIX, key), value));
+        }
+        entries
+    }
+
+    /// None unless at least the geometry and its dimension are recorded. Entries come
+    /// from whoever made the image, so a dimension other than 256, or a module or quiet
+    /// zone past `MAX_MODULE` and `MAX_QUIET`, makes for None as well.
+    pub fn from_entries(entries: &[(String, String)]) -> Option<EncodingParams> {
+        let get = |key: &str| {
+            entries
+                .iter()
+                .find(|(k, _)| k.strip_prefix(PREFIX) == Some(key))
+                .map(|(_, v)| v.as_str())
+        };
+        let flag = |key: &str| get(key) == Some("true");
+        let number =
+            |key: &str, default: u32| get(key).and_then(|v| v.parse().ok()).unwrap_or(default);
+
+        let dim = get("dim")?.parse().ok()?;
+        let module = number("module", 1).max(1);
+        let quiet = number("quiet", 0);
+        if dim != DIM || module > MAX_MODULE || quiet > MAX_QUIET {
+            return None;
+        }
+
+        let software = entries.iter().find(|(k, _)| k == "Software");
+        Some(EncodingParams {
+            geometry: get("geometry")?.to_string(),
+            dim,
+            alphabet: get("alphabet").unwrap_or("hex").to_string(),
+            stages: get("stages")
+                .unwrap_or("")
+                .split(',')
+                .filter(|stage| !stage.is_empty())
+                .map(String::from)
+                .collect(),
+            module,
+            quiet,
+            finders: flag("finders"),
+            scrambled: flag("scrambled"),
+            signed: flag("signed"),
+            format: number("format", 1),
+            version: software
+                .and_then(|(_, v)| v.strip_prefix("tim "))
+                .unwrap_or("")
+                .to_string(),
+            created: entries
+                .iter()
+                .find(|(k, _)| k == "Creation Time")
+                .map(|(_, v)| v.clone()),
+        })
+    }
+
+    /// Add the parameters to an encoded PNG as text chunks. None if it isn't a PNG.
+    pub fn to_png(&self, png: &[u8]) -> Option<Vec<u8>> {
+        write_png_text(png, &self.entries())
+    }
+
+    /// Read the parameters back from a PNG's text chunks.
+    pub fn from_png(png: &[u8]) -> Option<EncodingParams> {
+        EncodingParams::from_entries(&read_png_text(png))
+    }
+
+    /// An SVG `<metadata>` element carrying the parameters.
+    pub fn to_svg(&self) -> String {
+        let mut out = String::from("<metadata><tim:encoding xmlns:tim=\"urn:tim:encoding\">");
+        for (key, value) in self.entries() {
+            out.push_str(&format!(
+                "<tim:param name=\"{}\">{}</tim:param>",
+                escape_xml(&key),
+                escape_xml(&value)
+            ));
+        }
+        out.push_str("</tim:encoding></metadata>");
+        out
+    }
+}
+
+/**
+ * Insert text chunks into a PNG right after its header. ASCII values go in `tEXt`, any
+ * others in uncompressed `iTXt` so they stay UTF-8. None if the bytes aren't a PNG.
+ */
+pub fn write_png_text(png: &[u8], entries: &[(String, String)]) -> Option<Vec<u8>> {
+    let rest = png.strip_prefix(PNG_SIGNATURE)?;
+    let (kind, data) = chunks(rest).next()?;
+    if kind != *b"IHDR" {
+        return None;
+    }
+    let header_end = PNG_SIGNATURE.len() + 12 + data.len();
+
+    let mut out = png[..header_end].to_vec();
+    for (key, value) in entries {
+        // Keywords are 1 to 79 Latin-1 characters, there's no escaping anything else.
+        if key.is_empty() || key.len() > 79 || !key.is_ascii() || key.contains('\0') {
+            continue;
+        }
+
+        let mut data = key.as_bytes().to_vec();
+        data.push(0);
+        if value.is_ascii() {
+            data.extend(value.as_bytes());
+            push_chunk(&mut out, b"tEXt", &data);
+        } else {
+            // Uncompressed, with empty language tag and translated keyword.
+            data.extend([0, 0, 0, 0]);
+            data.extend(value.as_bytes());
+            push_chunk(&mut out, b"iTXt", &data);
+        }
+    }
+    out.extend(&png[header_end..]);
+    Some(out)
+}
+
+/// Keyword and value pairs from a PNG's `tEXt` and uncompressed `iTXt` chunks. Empty if
+/// it isn't a PNG.
+pub fn read_png_text(png: &[u8]) -> Vec<(String, String)> {
+    let Some(rest) = png.strip_prefix(PNG_SIGNATURE) else {
+        return vec![];
+    };
+
+    chunks(rest)
+        .filter_map(|(kind, data)| {
+            let nul = data.iter().position(|&b| b == 0)?;
+            // Latin-1 maps byte for byte onto the first 256 code points.
+            let key: String = data[..nul].iter().map(|&b| b as char).collect();
+            let rest = &data[nul + 1..];
+            match &kind {
+                b"tEXt" => Some((key, rest.iter().map(|&b| b as char).collect())),
+                b"iTXt" => {
+                    let (&compressed, rest) = rest.split_first()?;
+                    if compressed != 0 {
+                        return None;
+                    }
+                    // Skip the compression method, language tag and translated keyword.
+                    let rest = rest.get(1..)?;
+                    let language_end = rest.iter().position(|&b| b == 0)?;
+                    let rest = &rest[language_end + 1..];
+                    let translated_end = rest.iter().position(|&b| b == 0)?;
+                    let text = std::str::from_utf8(&rest[translated_end + 1..]).ok()?;
+                    Some((key, text.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Chunks after the signature as type and data, up to IEND or the first truncated one.
+fn chunks(mut rest: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        if rest.len() < 12 {
+            return None;
+        }
+        let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let kind: [u8; 4] = rest[4..8].try_into().unwrap();
+        if kind == *b"IEND" || rest.len() < 12 + len {
+            return None;
+        }
+
+        let data = &rest[8..8 + len];
+        rest = &rest[12 + len..];
+        Some((kind, data))
+    })
+}
+
+fn push_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    out.extend(kind);
+    out.extend(data);
+
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(data);
+    out.extend(crc.finalize().to_be_bytes());
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Seconds since the epoch as `YYYY-MM-DDTHH:MM:SSZ`.
+fn iso_8601(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+
+    // Civil from days, after Howard Hinnant's algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::{Bitmap, Renderer};
+
+    fn png() -> Vec<u8> {
+        <Bitmap as Renderer<crate::geometry::Point>>::to_bytes(&Bitmap::new(DIM)).unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_png_text() {
+        let params = EncodingParams::new("spiral", DIM)
+            .with_layout(&Layout::new(DIM).with_module(2).with_quiet(4))
+            .with_stages(vec![String::from("deflate"), String::from("crc32")])
+            .with_signed(true);
+
+        let png = params.to_png(&png()).unwrap();
+        assert_eq!(EncodingParams::from_png(&png), Some(params));
+        assert!(image::load_from_memory(&png).is_ok());
+    }
+
+    #[test]
+    fn keeps_non_ascii_values_in_itxt() {
+        let entries = vec![(String::from("Title"), String::from("Grüße"))];
+        let png = write_png_text(&png(), &entries).unwrap();
+
+        assert_eq!(read_png_text(&png), entries);
+    }
+
+    #[test]
+    fn refuses_out_of_range_layouts() {
+        let recorded = |dim: &str, module: &str, quiet: &str| {
+            let params = EncodingParams::new("spiral", DIM);
+            let entries: Vec<_> = params
+                .entries()
+                .into_iter()
+                .map(|(key, value)| match key.strip_prefix(PREFIX) {
+                    Some("dim") => (key, dim.to_string()),
+                    Some("module") => (key, module.to_string()),
+                    Some("quiet") => (key, quiet.to_string()),
+                    _ => (key, value),
+                })
+                .collect();
+            EncodingParams::from_entries(&entries)
+        };
+
+        assert!(recorded("256", "16", "64").is_some());
+        assert!(recorded("4294967292", "1", "2").is_none());
+        assert!(recorded("256", "100000", "0").is_none());
+        assert!(recorded("256", "1", "65").is_none());
+    }
+
+    #[test]
+    fn takes_images_without_a_format_for_the_first() {
+        let entries: Vec<_> = EncodingParams::new("spiral", DIM)
+            .entries()
+            .into_iter()
+            .filter(|(key, _)| key != "tim:format")
+            .collect();
+
+        assert_eq!(EncodingParams::from_entries(&entries).unwrap().format, 1);
+        assert_eq!(EncodingParams::new("spiral", DIM).format, FORMAT);
+    }
+}
@@ -0,0 +1,186 @@
+use std::io::{self, Read};
+
+use super::{PipelineError, Stage};
+
+/// Decompressed payloads larger than this are rejected, so a crafted image can't
+/// expand into an arbitrarily large buffer.
+pub const DEFAULT_MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    #[cfg(feature = "deflate")]
+    Deflate,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Algorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            #[cfg(feature = "deflate")]
+            "deflate" => Some(Algorithm::Deflate),
+            #[cfg(feature = "zstd")]
+            "zstd" => Some(Algorithm::Zstd),
+            #[cfg(feature = "brotli")]
+            "brotli" => Some(Algorithm::Brotli),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            #[cfg(feature = "deflate")]
+            Algorithm::Deflate => "deflate",
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd => "zstd",
+            #[cfg(feature = "brotli")]
+            Algorithm::Brotli => "brotli",
+        }
+    }
+}
+
+/// Compresses the payload before it is hex expanded into geometry.
+pub struct Compress {
+    algorithm: Algorithm,
+    max_len: usize,
+}
+
+impl Compress {
+    pub fn new(algorithm: Algorithm) -> Self {
+        Compress {
+            algorithm,
+            max_len: DEFAULT_MAX_DECOMPRESSED_LEN,
+        }
+    }
+
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    fn compress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self.algorithm {
+            #[cfg(feature = "deflate")]
+            Algorithm::Deflate => {
+                use std::io::Write;
+
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd => zstd::stream::encode_all(bytes, 19),
+            #[cfg(feature = "brotli")]
+            Algorithm::Brotli => {
+                use std::io::Write;
+
+                let mut out = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut out, 4096, 11, 22);
+                    writer.write_all(bytes)?;
+                }
+                Ok(out)
+            }
+        }
+    }
+
+    fn decompress(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let reader: Box<dyn Read + '_> = match self.algorithm {
+            #[cfg(feature = "deflate")]
+            Algorithm::Deflate => Box::new(flate2::read::DeflateDecoder::new(bytes)),
+            #[cfg(feature = "zstd")]
+            Algorithm::Zstd => Box::new(zstd::stream::read::Decoder::new(bytes)?),
+            #[cfg(feature = "brotli")]
+            Algorithm::Brotli => Box::new(brotli::Decompressor::new(bytes, 4096)),
+        };
+
+        // Read one byte past the limit to tell "exactly max_len" apart from "too large".
+        let mut out = Vec::new();
+        reader.take(self.max_len as u64 + 1).read_to_end(&mut out)?;
+        if out.len() > self.max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "decompressed payload exceeds limit",
+            ));
+        }
+
+        Ok(out)
+    }
+}
+
+impl Stage for Compress {
+    fn name(&self) -> String {
+        String::from(self.algorithm.name())
+    }
+
+    fn forward(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        self.compress(&bytes).map_err(|e| PipelineError::Stage {
+            name: self.name(),
+            reason: e.to_string(),
+        })
+    }
+
+    fn inverse(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        self.decompress(&bytes).map_err(|e| PipelineError::Stage {
+            name: self.name(),
+            reason: e.to_string(),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "deflate"))]
+mod tests {
+    use super::*;
+    use crate::geometry::SpiralGeometry;
+
+    const PROSE: &str = include_str!("../../../README.md");
+
+    fn algorithms() -> Vec<Algorithm> {
+        ["deflate", "zstd", "brotli"]
+            .into_iter()
+            .filter_map(Algorithm::from_name)
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_with_every_algorithm() {
+        for algorithm in algorithms() {
+            let stage = Compress::new(algorithm);
+            assert_eq!(stage.name(), algorithm.name());
+
+            for input in [&b""[..], b"x", PROSE.as_bytes()] {
+                let compressed = stage.forward(input.to_vec()).unwrap();
+                assert_eq!(stage.inverse(compressed).unwrap(), input);
+            }
+            assert!(stage.inverse(b"not compressed at all".to_vec()).is_err());
+        }
+    }
+
+    #[test]
+    fn refuses_to_expand_past_the_limit() {
+        for algorithm in algorithms() {
+            let compressed = Compress::new(algorithm).forward(vec![0; 4096]).unwrap();
+
+            let limited = Compress::new(algorithm).with_max_len(4095);
+            assert!(matches!(
+                limited.inverse(compressed.clone()),
+                Err(PipelineError::Stage { .. })
+            ));
+            let exact = Compress::new(algorithm).with_max_len(4096);
+            assert_eq!(exact.inverse(compressed).unwrap().len(), 4096);
+        }
+    }
+
+    #[test]
+    fn fits_twice_the_prose_in_one_spiral() {
+        let prose = &PROSE.as_bytes()[..4096];
+        let compressed = Compress::new(Algorithm::Deflate)
+            .forward(prose.to_vec())
+            .unwrap();
+        let capacity = SpiralGeometry::capacity(256);
+        assert!(compressed.len() <= capacity, "{} bytes", compressed.len());
+    }
+}
@@ -0,0 +1,317 @@
+use super::{PipelineError, Stage};
+
+/// Longest Reed-Solomon codeword over GF(256), data and parity together.
+const BLOCK_LEN: usize = 255;
+/// Most parity bytes a block is given, half its length.
+pub const MAX_PARITY: usize = 128;
+
+/// Log and antilog tables of GF(256) under the polynomial x^8 + x^4 + x^3 + x^2 + 1.
+/// The antilog table runs twice round so sums of two logs index it directly.
+const TABLES: ([u8; 512], [u8; 256]) = tables();
+
+const fn tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = &TABLES;
+    exp[log[a as usize] as usize + log[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    let (exp, log) = &TABLES;
+    exp[log[a as usize] as usize + 255 - log[b as usize] as usize]
+}
+
+/// The generator to the power `e`.
+fn alpha(e: usize) -> u8 {
+    TABLES.0[e % 255]
+}
+
+/// Evaluate a polynomial stored lowest degree first.
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter().rev().fold(0, |acc, &c| mul(acc, x) ^ c)
+}
+
+/**
+ * Reed-Solomon forward error correction. The payload is cut into blocks of up to
+ * 255 - `parity` bytes and each gets `parity` check bytes, enough to repair up to
+ * half as many damaged bytes anywhere in its block. Errors come in bursts where an
+ * image is smudged or torn, so an `interleave` stage after this one spreads a burst
+ * over every block instead of overwhelming one.
+ */
+pub struct Fec {
+    parity: usize,
+    /// Generator polynomial, highest degree first, its leading 1 included.
+    generator: Vec<u8>,
+}
+
+impl Fec {
+    /// None for no parity, or past `MAX_PARITY`.
+    pub fn new(parity: usize) -> Option<Self> {
+        if parity == 0 || parity > MAX_PARITY {
+            return None;
+        }
+
+        // The product of (x - a^i) for every i below `parity`.
+        let mut generator = vec![1u8];
+        for i in 0..parity {
+            let mut next = vec![0u8; generator.len() + 1];
+            for (j, &c) in generator.iter().enumerate() {
+                next[j] ^= c;
+                next[j + 1] ^= mul(c, alpha(i));
+            }
+            generator = next;
+        }
+
+        Some(Fec { parity, generator })
+    }
+
+    fn data_len(&self) -> usize {
+        BLOCK_LEN - self.parity
+    }
+
+    /// Check bytes for one block, the remainder of dividing it by the generator.
+    fn check_bytes(&self, data: &[u8]) -> Vec<u8> {
+        let mut remainder = vec![0u8; self.parity];
+        for &byte in data {
+            let feedback = byte ^ remainder[0];
+            remainder.rotate_left(1);
+            remainder[self.parity - 1] = 0;
+            for (r, &g) in remainder.iter_mut().zip(&self.generator[1..]) {
+                *r ^= mul(g, feedback);
+            }
+        }
+        remainder
+    }
+
+    /// The block evaluated at each root of the generator, all zero when intact.
+    fn syndromes(&self, block: &[u8]) -> Vec<u8> {
+        (0..self.parity)
+            .map(|i| block.iter().fold(0, |acc, &c| mul(acc, alpha(i)) ^ c))
+            .collect()
+    }
+
+    /**
+     * Repair a block in place: Berlekamp-Massey for the error locator, a Chien search
+     * for where its roots fall and Forney's formula for what to flip there. False if
+     * there are more errors than the parity can place.
+     */
+    fn correct(&self, block: &mut [u8]) -> bool {
+        let syndromes = self.syndromes(block);
+        if syndromes.iter().all(|&s| s == 0) {
+            return true;
+        }
+
+        let mut locator = vec![1u8];
+        let mut previous = vec![1u8];
+        let (mut errors, mut shift, mut last) = (0, 1, 1u8);
+        for n in 0..self.parity {
+            let mut discrepancy = syndromes[n];
+            for i in 1..=errors.min(locator.len() - 1) {
+                discrepancy ^= mul(locator[i], syndromes[n - i]);
+            }
+            if discrepancy == 0 {
+                shift += 1;
+                continue;
+            }
+
+            let scale = div(discrepancy, last);
+            let before = locator.clone();
+            locator.resize(locator.len().max(previous.len() + shift), 0);
+            for (i, &p) in previous.iter().enumerate() {
+                locator[i + shift] ^= mul(scale, p);
+            }
+            if 2 * errors <= n {
+                errors = n + 1 - errors;
+                previous = before;
+                last = discrepancy;
+                shift = 1;
+            } else {
+                shift += 1;
+            }
+        }
+        while locator.last() == Some(&0) {
+            locator.pop();
+        }
+        if locator.len() - 1 != errors || 2 * errors > self.parity {
+            return false;
+        }
+
+        // Error evaluator, the syndromes times the locator, cut off at the parity length.
+        let mut evaluator = vec![0u8; self.parity];
+        for (i, &l) in locator.iter().enumerate() {
+            for (j, &s) in syndromes.iter().enumerate() {
+                if i + j < self.parity {
+                    evaluator[i + j] ^= mul(l, s);
+                }
+            }
+        }
+        // Formal derivative, only odd powers survive in characteristic 2.
+        let derivative: Vec<u8> = locator
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(i, &l)| if i % 2 == 1 { l } else { 0 })
+            .collect();
+
+        let len = block.len();
+        let mut found = 0;
+        for (ix, byte) in block.iter_mut().enumerate() {
+            let power = len - 1 - ix;
+            let inverse = alpha(255 - power % 255);
+            if eval(&locator, inverse) != 0 {
+                continue;
+            }
+
+            let denominator = eval(&derivative, inverse);
+            if denominator == 0 {
+                return false;
+            }
+            *byte ^= mul(alpha(power), div(eval(&evaluator, inverse), denominator));
+            found += 1;
+        }
+
+        found == errors && self.syndromes(block).iter().all(|&s| s == 0)
+    }
+}
+
+impl Stage for Fec {
+    fn name(&self) -> String {
+        format!("fec:{}", self.parity)
+    }
+
+    fn forward(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        let blocks = bytes.len().div_ceil(self.data_len());
+        let mut out = Vec::with_capacity(bytes.len() + blocks * self.parity);
+        for data in bytes.chunks(self.data_len()) {
+            out.extend(data);
+            out.extend(self.check_bytes(data));
+        }
+
+        Ok(out)
+    }
+
+    fn inverse(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        let err = |reason: String| PipelineError::Stage {
+            name: self.name(),
+            reason,
+        };
+
+        let mut out = Vec::with_capacity(bytes.len());
+        for (i, block) in bytes.chunks(BLOCK_LEN).enumerate() {
+            // A short last block still carries all its check bytes.
+            if block.len() <= self.parity {
+                return Err(err(String::from("block is truncated")));
+            }
+
+            let mut block = block.to_vec();
+            if !self.correct(&mut block) {
+                return Err(err(format!("block {} is damaged beyond repair", i)));
+            }
+            out.extend(&block[..block.len() - self.parity]);
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::Interleave;
+
+    fn payload(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 131 + i / 7) as u8).collect()
+    }
+
+    #[test]
+    fn round_trips_any_length() {
+        let fec = Fec::new(16).unwrap();
+        for len in [0, 1, 238, 239, 240, 1000] {
+            let encoded = fec.forward(payload(len)).unwrap();
+            assert_eq!(encoded.len(), len + len.div_ceil(239) * 16);
+            assert_eq!(fec.inverse(encoded).unwrap(), payload(len), "{}", len);
+        }
+    }
+
+    #[test]
+    fn repairs_up_to_half_the_parity_per_block() {
+        for parity in [2, 16, 32] {
+            let fec = Fec::new(parity).unwrap();
+            let encoded = fec.forward(payload(600)).unwrap();
+
+            // Spread over every block, the short last one included.
+            let mut damaged = encoded.clone();
+            for block in damaged.chunks_mut(BLOCK_LEN) {
+                let step = block.len() / (parity / 2);
+                for k in 0..parity / 2 {
+                    block[k * step] ^= 0x5a + k as u8;
+                }
+            }
+            assert_eq!(fec.inverse(damaged).unwrap(), payload(600), "{}", parity);
+        }
+
+        let fec = Fec::new(16).unwrap();
+        let mut damaged = fec.forward(payload(200)).unwrap();
+        for byte in damaged[..9].iter_mut() {
+            *byte ^= 0xff;
+        }
+        assert!(fec.inverse(damaged).is_err());
+    }
+
+    #[test]
+    fn survives_bursts_behind_an_interleaver() {
+        let fec = Fec::new(16).unwrap();
+        let interleave = Interleave::new(24).unwrap();
+        let encoded = interleave
+            .forward(fec.forward(payload(600)).unwrap())
+            .unwrap();
+
+        // A run of damaged cells, too long for any one block to repair on its own.
+        let mut burst = encoded.clone();
+        for byte in burst[300..318].iter_mut() {
+            *byte = !*byte;
+        }
+        let deinterleaved = interleave.inverse(burst).unwrap();
+        assert_eq!(fec.inverse(deinterleaved).unwrap(), payload(600));
+
+        let mut unspread = fec.forward(payload(600)).unwrap();
+        for byte in unspread[300..318].iter_mut() {
+            *byte = !*byte;
+        }
+        assert!(fec.inverse(unspread).is_err());
+    }
+
+    #[test]
+    fn refuses_truncated_blocks() {
+        let fec = Fec::new(16).unwrap();
+        let encoded = fec.forward(payload(300)).unwrap();
+        assert!(fec.inverse(encoded[..255 + 16].to_vec()).is_err());
+        assert!(Fec::new(0).is_none());
+        assert!(Fec::new(MAX_PARITY + 1).is_none());
+    }
+}
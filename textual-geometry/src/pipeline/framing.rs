@@ -0,0 +1,48 @@
+use super::{PipelineError, Stage};
+
+/// Length prefix and CRC32 trailer, so truncated or corrupted payloads are rejected
+/// instead of decoding to garbage.
+pub struct Framing;
+
+impl Stage for Framing {
+    fn name(&self) -> String {
+        String::from("frame")
+    }
+
+    fn forward(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        let len = u32::try_from(bytes.len()).map_err(|_| PipelineError::Stage {
+            name: self.name(),
+            reason: String::from("payload too large to frame"),
+        })?;
+
+        let mut out = Vec::with_capacity(bytes.len() + 8);
+        out.extend(len.to_be_bytes());
+        out.extend(&bytes);
+        out.extend(crc32fast::hash(&bytes).to_be_bytes());
+        Ok(out)
+    }
+
+    fn inverse(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        let err = |reason: &str| PipelineError::Stage {
+            name: self.name(),
+            reason: String::from(reason),
+        };
+
+        if bytes.len() < 8 {
+            return Err(err("frame is truncated"));
+        }
+
+        let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+        if bytes.len() - 8 < len {
+            return Err(err("frame is truncated"));
+        }
+
+        let payload = &bytes[4..4 + len];
+        let crc = &bytes[4 + len..8 + len];
+        if crc32fast::hash(payload).to_be_bytes() != crc {
+            return Err(err("checksum mismatch"));
+        }
+
+        Ok(payload.to_vec())
+    }
+}
@@ -0,0 +1,96 @@
+use super::{PipelineError, Stage};
+
+/// Deepest interleaver accepted. Depths come from manifests inside decoded images, and
+/// anything past the largest payload behaves the same as that payload's length anyway.
+pub const MAX_DEPTH: usize = 1 << 20;
+
+/// Block interleaver. Bytes are written row by row into a matrix `depth` rows tall and
+/// read back column by column, so a burst of damaged pixels lands on bytes that are
+/// far apart in the original payload.
+pub struct Interleave {
+    depth: usize,
+}
+
+impl Interleave {
+    /// None for a depth of 0 or past `MAX_DEPTH`.
+    pub fn new(depth: usize) -> Option<Self> {
+        if depth == 0 || depth > MAX_DEPTH {
+            return None;
+        }
+
+        Some(Interleave { depth })
+    }
+
+    /**
+     * Source index for each output position. Partial last rows are skipped rather than
+     * padded so the payload length is unchanged.
+     */
+    fn permutation(&self, len: usize) -> Vec<usize> {
+        let cols = len.div_ceil(self.depth);
+        let mut order = Vec::with_capacity(len);
+
+        // Rows past the payload's length are always empty.
+        let rows = self.depth.min(len);
+        for col in 0..cols {
+            for row in 0..rows {
+                let ix = row * cols + col;
+                if ix < len {
+                    order.push(ix);
+                }
+            }
+        }
+
+        order
+    }
+}
+
+impl Stage for Interleave {
+    fn name(&self) -> String {
+        format!("interleave:{}", self.depth)
+    }
+
+    fn forward(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        Ok(self
+            .permutation(bytes.len())
+            .into_iter()
+            .map(|ix| bytes[ix])
+            .collect())
+    }
+
+    fn inverse(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError> {
+        let mut out = vec![0u8; bytes.len()];
+        for (src, dst) in self.permutation(bytes.len()).into_iter().enumerate() {
+            out[dst] = bytes[src];
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_spreads_bursts() {
+        let payload: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let interleave = Interleave::new(7).unwrap();
+
+        let interleaved = interleave.forward(payload.clone()).unwrap();
+        assert_eq!(interleaved.len(), payload.len());
+        // Neighbours in the output sit a column apart in the original.
+        assert_eq!(interleaved[..2], [payload[0], payload[143]]);
+        assert_eq!(interleave.inverse(interleaved).unwrap(), payload);
+    }
+
+    #[test]
+    fn depths_past_the_payload_are_cheap() {
+        let interleave = Interleave::new(MAX_DEPTH).unwrap();
+        let payload = b"short".to_vec();
+
+        let interleaved = interleave.forward(payload.clone()).unwrap();
+        assert_eq!(interleave.inverse(interleaved).unwrap(), payload);
+        assert!(Interleave::new(MAX_DEPTH + 1).is_none());
+        assert!(Interleave::new(0).is_none());
+    }
+}
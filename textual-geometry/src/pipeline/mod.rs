@@ -0,0 +1,289 @@
+#[cfg(any(feature = "deflate", feature = "zstd", feature = "brotli"))]
+pub mod compress;
+pub mod fec;
+pub mod framing;
+pub mod interleave;
+
+#[cfg(any(feature = "deflate", feature = "zstd", feature = "brotli"))]
+pub use compress::*;
+pub use fec::*;
+pub use framing::*;
+pub use interleave::*;
+
+use std::fmt;
+
+/// Leading bytes of a recorded stage manifest. Text payloads never start with NUL, so
+/// images encoded before pipelines existed still decode as raw bytes.
+const MANIFEST_MAGIC: &[u8; 4] = b"\0TIM";
+const MANIFEST_VERSION: u8 = 1;
+
+#[derive(Debug)]
+pub enum PipelineError {
+    UnknownStage(String),
+    StageMismatch {
+        expected: Vec<String>,
+        found: Vec<String>,
+    },
+    Manifest,
+    Stage {
+        name: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::UnknownStage(name) => write!(f, "unknown pipeline stage '{}'", name),
+            PipelineError::StageMismatch { expected, found } => write!(
+                f,
+                "payload was encoded with stages [{}], pipeline has [{}]",
+                found.join(", "),
+                expected.join(", ")
+            ),
+            PipelineError::Manifest => write!(f, "malformed stage manifest"),
+            PipelineError::Stage { name, reason } => write!(f, "stage '{}': {}", name, reason),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+/// A reversible transform over the byte payload, applied before geometry translation.
+pub trait Stage {
+    /// Identifier recorded in the manifest, including any parameters needed to invert
+    /// the stage (e.g. `interleave:8`). Secrets must never be part of the name.
+    fn name(&self) -> String;
+
+    fn forward(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError>;
+
+    fn inverse(&self, bytes: Vec<u8>) -> Result<Vec<u8>, PipelineError>;
+}
+
+/// Resolve a recorded stage name into one of the built in, keyless stages.
+pub fn stage_by_name(name: &str) -> Option<Box<dyn Stage>> {
+    #[cfg(any(feature = "deflate", feature = "zstd", feature = "brotli"))]
+    if let Some(algorithm) = Algorithm::from_name(name) {
+        return Some(Box::new(Compress::new(algorithm)));
+    }
+
+    let (kind, param) = match name.split_once(':') {
+        Some((kind, param)) => (kind, Some(param)),
+        None => (name, None),
+    };
+
+    match (kind, param) {
+        ("frame", None) => Some(Box::new(Framing)),
+        ("fec", Some(parity)) => Some(Box::new(Fec::new(parity.parse().ok()?)?)),
+        ("interleave", Some(depth)) => Some(Box::new(Interleave::new(depth.parse().ok()?)?)),
+        _ => None,
+    }
+}
+
+/// An ordered stack of stages. Encoding runs them first to last and prepends the list of
+/// stage names; decoding reads that list back and undoes them last to first.
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Pipeline { stages: vec![] }
+    }
+
+    pub fn stage(mut self, stage: impl Stage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    pub fn boxed_stage(mut self, stage: Box<dyn Stage>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    /// Build a pipeline from stage names, e.g. from the command line.
+    pub fn from_names(names: &[String]) -> Result<Self, PipelineError> {
+        Pipeline::from_names_with(names, stage_by_name)
+    }
+
+    pub fn from_names_with(
+        names: &[String],
+        resolve: impl Fn(&str) -> Option<Box<dyn Stage>>,
+    ) -> Result<Self, PipelineError> {
+        let mut pipeline = Pipeline::new();
+        for name in names {
+            let stage = resolve(name).ok_or_else(|| PipelineError::UnknownStage(name.clone()))?;
+            pipeline = pipeline.boxed_stage(stage);
+        }
+
+        Ok(pipeline)
+    }
+
+    /// Build the pipeline that undoes an encoded payload from the manifest it carries.
+    pub fn from_manifest(
+        bytes: &[u8],
+        resolve: impl Fn(&str) -> Option<Box<dyn Stage>>,
+    ) -> Result<Self, PipelineError> {
+        let (names, _) = Pipeline::read_manifest(bytes)?;
+        Pipeline::from_names_with(&names, resolve)
+    }
+
+    pub fn stage_names(&self) -> Vec<String> {
+        self.stages.iter().map(|s| s.name()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+
+    pub fn encode(&self, input: &[u8]) -> Result<Vec<u8>, PipelineError> {
+        // No manifest for an empty pipeline keeps plain encodings byte-identical to before.
+        if self.stages.is_empty() {
+            return Ok(input.to_vec());
+        }
+
+        let mut payload = input.to_vec();
+        for stage in self.stages.iter() {
+            payload = stage.forward(payload)?;
+        }
+
+        let mut out = Pipeline::write_manifest(&self.stage_names())?;
+        out.extend(payload);
+        Ok(out)
+    }
+
+    pub fn decode(&self, input: &[u8]) -> Result<Vec<u8>, PipelineError> {
+        let (found, payload) = Pipeline::read_manifest(input)?;
+        let expected = self.stage_names();
+        if found != expected {
+            return Err(PipelineError::StageMismatch { expected, found });
+        }
+
+        let mut payload = payload.to_vec();
+        for stage in self.stages.iter().rev() {
+            payload = stage.inverse(payload)?;
+        }
+
+        Ok(payload)
+    }
+
+    fn write_manifest(names: &[String]) -> Result<Vec<u8>, PipelineError> {
+        let count = u8::try_from(names.len()).map_err(|_| PipelineError::Manifest)?;
+
+        let mut out = MANIFEST_MAGIC.to_vec();
+        out.push(MANIFEST_VERSION);
+        out.push(count);
+        for name in names {
+            let len = u8::try_from(name.len()).map_err(|_| PipelineError::Manifest)?;
+            out.push(len);
+            out.extend(name.as_bytes());
+        }
+
+        Ok(out)
+    }
+
+    /// Split a payload into its recorded stage names and the remaining bytes. Payloads
+    /// without a manifest have no stages.
+    pub fn read_manifest(bytes: &[u8]) -> Result<(Vec<String>, &[u8]), PipelineError> {
+        let Some(rest) = bytes.strip_prefix(MANIFEST_MAGIC) else {
+            return Ok((vec![], bytes));
+        };
+
+        let (&version, rest) = rest.split_first().ok_or(PipelineError::Manifest)?;
+        if version != MANIFEST_VERSION {
+            return Err(PipelineError::Manifest);
+        }
+
+        let (&count, mut rest) = rest.split_first().ok_or(PipelineError::Manifest)?;
+        let mut names = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (&len, tail) = rest.split_first().ok_or(PipelineError::Manifest)?;
+            if tail.len() < len as usize {
+                return Err(PipelineError::Manifest);
+            }
+
+            let (name, tail) = tail.split_at(len as usize);
+            let name = String::from_utf8(name.to_vec()).map_err(|_| PipelineError::Manifest)?;
+            names.push(name);
+            rest = tail;
+        }
+
+        Ok((names, rest))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn round_trips_through_its_manifest() {
+        let pipeline = Pipeline::from_names(&names(&["frame", "interleave:8"])).unwrap();
+        let input = b"A payload long enough to spread over several interleaver columns.";
+
+        let encoded = pipeline.encode(input).unwrap();
+        assert!(encoded.starts_with(MANIFEST_MAGIC));
+        let (recorded, _) = Pipeline::read_manifest(&encoded).unwrap();
+        assert_eq!(recorded, names(&["frame", "interleave:8"]));
+
+        let decoder = Pipeline::from_manifest(&encoded, stage_by_name).unwrap();
+        assert_eq!(decoder.decode(&encoded).unwrap(), input);
+    }
+
+    #[test]
+    fn leaves_plain_payloads_alone() {
+        let pipeline = Pipeline::new();
+        assert_eq!(pipeline.encode(b"plain").unwrap(), b"plain");
+
+        let decoder = Pipeline::from_manifest(b"plain", stage_by_name).unwrap();
+        assert!(decoder.is_empty());
+        assert_eq!(decoder.decode(b"plain").unwrap(), b"plain");
+    }
+
+    #[test]
+    fn refuses_mismatched_and_malformed_manifests() {
+        let encoded = Pipeline::from_names(&names(&["frame"]))
+            .unwrap()
+            .encode(b"framed")
+            .unwrap();
+        let other = Pipeline::from_names(&names(&["interleave:2"])).unwrap();
+        assert!(matches!(
+            other.decode(&encoded),
+            Err(PipelineError::StageMismatch { .. })
+        ));
+
+        // A name running past the end of the payload.
+        assert!(Pipeline::read_manifest(b"\0TIM\x01\x01\x40frame").is_err());
+        assert!(Pipeline::read_manifest(b"\0TIM\x02\x00").is_err());
+        for unknown in [
+            "interleave:0",
+            "interleave:100000000000",
+            "fec:0",
+            "fec",
+            "rot13",
+        ] {
+            let mut manifest = b"\0TIM\x01\x01".to_vec();
+            manifest.push(unknown.len() as u8);
+            manifest.extend(unknown.as_bytes());
+            assert!(Pipeline::from_manifest(&manifest, stage_by_name).is_err());
+        }
+    }
+
+    #[test]
+    fn framing_rejects_corruption() {
+        let framed = Framing.forward(b"checked".to_vec()).unwrap();
+        assert_eq!(Framing.inverse(framed.clone()).unwrap(), b"checked");
+
+        let mut corrupted = framed.clone();
+        corrupted[5] ^= 1;
+        assert!(Framing.inverse(corrupted).is_err());
+        assert!(Framing
+            .inverse(framed[..framed.len() - 1].to_vec())
+            .is_err());
+    }
+}
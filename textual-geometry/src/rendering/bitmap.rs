@@ -1,33 +1,192 @@
-use std::io::Cursor;
+use std::io::{self, Cursor, Write};
 
+use super::fiducial::{self, FINDER_MARGIN};
+use super::scramble::Scrambler;
+use super::Renderer;
+use crate::decode::{DecodeError, DecodeLimits};
 use crate::geometry::Geometry;
-use crate::geometry::Point;
 use crate::geometry::PreGeometry;
+use crate::geometry::{LossyPoint, Point};
+use crate::metadata::EncodingParams;
+use image::codecs::png::PngEncoder;
+use image::io::Limits;
 use image::io::Reader as ImageReader;
+use image::DynamicImage;
+use image::GenericImageView;
 use image::GrayImage;
+use image::ImageEncoder;
 use image::ImageError;
 
+/// How logical cells map onto pixels. Each cell is drawn as a `module` x `module` square
+/// and the grid is surrounded by a blank border `quiet` cells wide. With `finders` set,
+/// finder and alignment patterns are drawn in a margin between the grid and the border.
+#[derive(Clone, Copy, Debug)]
+pub struct Layout {
+    pub dim: u32,
+    pub module: u32,
+    pub quiet: u32,
+    pub finders: bool,
+}
+
+impl Layout {
+    pub fn new(dim: u32) -> Layout {
+        Layout {
+            dim,
+            module: 1,
+            quiet: 0,
+            finders: false,
+        }
+    }
+
+    pub fn with_finders(mut self, finders: bool) -> Layout {
+        self.finders = finders;
+        self
+    }
+
+    /// Cells between the edge of the image and the first cell of the grid.
+    pub fn border(&self) -> u32 {
+        if self.finders {
+            self.quiet.saturating_add(FINDER_MARGIN)
+        } else {
+            self.quiet
+        }
+    }
+
+    /// Side length of the image in cells, border included. None if it overflows, as a
+    /// layout read from an untrusted source may.
+    pub fn cells(&self) -> Option<u32> {
+        let margin = if self.finders { FINDER_MARGIN } else { 0 };
+        let border = self.quiet.checked_add(margin)?;
+        border.checked_mul(2)?.checked_add(self.dim)
+    }
+
+    /// Side length of the rendered image in pixels, None if it overflows.
+    pub fn checked_imsize(&self) -> Option<u32> {
+        self.cells()?.checked_mul(self.module)
+    }
+
+    pub fn with_module(mut self, module: u32) -> Layout {
+        self.module = module.max(1);
+        self
+    }
+
+    pub fn with_quiet(mut self, quiet: u32) -> Layout {
+        self.quiet = quiet;
+        self
+    }
+
+    /// Side length of the rendered image in pixels. Panics if it overflows, check
+    /// untrusted layouts with `checked_imsize` first.
+    pub fn imsize(&self) -> u32 {
+        self.checked_imsize()
+            .expect("layout image size overflows u32")
+    }
+}
+
 pub struct Bitmap {
     pub buf: GrayImage,
+    layout: Layout,
+    scrambler: Option<Scrambler>,
+    metadata: Option<EncodingParams>,
 }
 
 impl Bitmap {
     pub fn new(dim: u32) -> Bitmap {
-        let imsize = dim;
-        let image_buffer = GrayImage::from_fn(imsize, imsize, |_, _| image::Luma([0u8]));
+        Bitmap::with_layout(Layout::new(dim))
+    }
+
+    pub fn with_layout(layout: Layout) -> Bitmap {
+        let imsize = layout.imsize();
+        let mut image_buffer = GrayImage::from_fn(imsize, imsize, |_, _| image::Luma([0u8]));
+
+        if layout.finders {
+            fiducial::draw(&mut image_buffer, &layout);
+        }
+
+        Bitmap {
+            buf: image_buffer,
+            layout,
+            scrambler: None,
+            metadata: None,
+        }
+    }
 
-        Bitmap { buf: image_buffer }
+    /// Scramble the image with `scrambler` whenever it is written out, `buf` itself
+    /// stays in the clear.
+    pub fn with_scrambler(mut self, scrambler: Scrambler) -> Bitmap {
+        self.scrambler = Some(scrambler);
+        self
+    }
+
+    /// Record how the image was made in text chunks of the PNG, see `EncodingParams`.
+    pub fn with_metadata(mut self, metadata: EncodingParams) -> Bitmap {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn layout(&self) -> &Layout {
+        &self.layout
+    }
+
+    /// Scramble the rendered image in place, see `Scrambler`.
+    pub fn scramble(&mut self, scrambler: &Scrambler) {
+        scrambler.apply(&mut self.buf);
     }
 
     pub fn from_geometry(&mut self, geometry: &dyn Geometry<Point>) {
         let points = geometry.get_points();
 
         for point in points.iter() {
-            let pix = self.buf.get_pixel_mut(point.x, point.y);
-            *pix = image::Luma([255u8])
+            self.paint(point.x, point.y, 255u8);
         }
     }
 
+    /// Fill the module for logical cell (x, y).
+    fn paint(&mut self, x: u32, y: u32, luminosity: u8) {
+        let border = self.layout.border();
+        fill_cell(
+            &mut self.buf,
+            &self.layout,
+            border + x,
+            border + y,
+            luminosity,
+        );
+    }
+
+    /**
+     * Resample a rendered (and possibly rescaled) image back onto its logical grid.
+     * The module size is inferred from the image size, each cell is read as the mean
+     * of a window around its center, leaving binarization to a `Threshold`.
+     */
+    pub fn sample_grid(luma: &GrayImage, layout: &Layout) -> PreGeometry {
+        let (width, height) = luma.dimensions();
+        let border = layout.border();
+        let cells = layout.dim as f32 + 2. * border as f32;
+        let cell_w = width as f32 / cells;
+        let cell_h = height as f32 / cells;
+
+        // Stay well inside the cell so edge blur from resampling doesn't leak in.
+        let radius_x = (cell_w / 6.).floor() as i64;
+        let radius_y = (cell_h / 6.).floor() as i64;
+
+        let mut points = Vec::with_capacity(layout.dim as usize * layout.dim as usize);
+        for y in 0..layout.dim {
+            for x in 0..layout.dim {
+                let cx = (border as f32 + x as f32 + 0.5) * cell_w;
+                let cy = (border as f32 + y as f32 + 0.5) * cell_h;
+
+                let mean = window_mean(luma, cx, cy, radius_x, radius_y);
+                points.push(Point {
+                    x,
+                    y,
+                    z: Some(mean as u32),
+                });
+            }
+        }
+
+        ((layout.dim, layout.dim), points)
+    }
+
     pub fn to_sparse_points(src: &str) -> Result<PreGeometry, ImageError> {
         let image = ImageReader::open(src)?.decode()?;
         let luma8 = image.into_luma8();
@@ -37,11 +196,11 @@ impl Bitmap {
             .enumerate_pixels()
             .filter_map(|(x, y, pix)| -> Option<Point> {
                 if pix.0[0] > 0 {
-                    return Some(Point {
+                    Some(Point {
                         x,
                         y,
                         z: Some(pix.0[0] as u32),
-                    });
+                    })
                 } else {
                     None
                 }
@@ -59,26 +218,237 @@ impl Bitmap {
         let points = luma8
             .enumerate_pixels()
             .map(|(x, y, pix)| -> Point {
-                return Point {
+                Point {
                     x,
                     y,
                     z: Some(pix.0[0] as u32),
-                };
+                }
             })
             .collect::<Vec<Point>>();
 
         Ok(((width, height), points))
     }
 
+    /// Like `to_points`, but for untrusted in-memory images. Dimensions and allocation
+    /// are checked against `limits` before any pixel data is decoded.
+    pub fn to_points_limited(
+        bytes: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<PreGeometry, DecodeError> {
+        let luma8 = Bitmap::load_limited(bytes, limits)?;
+        let (width, height) = luma8.dimensions();
+
+        let points = luma8
+            .enumerate_pixels()
+            .map(|(x, y, pix)| Point {
+                x,
+                y,
+                z: Some(pix.0[0] as u32),
+            })
+            .collect::<Vec<Point>>();
+
+        Ok(((width, height), points))
+    }
+
+    /// Decode an untrusted in-memory image to grayscale within `limits`.
+    pub fn load_limited(bytes: &[u8], limits: &DecodeLimits) -> Result<GrayImage, DecodeError> {
+        Ok(Bitmap::load_limited_image(bytes, limits)?.into_luma8())
+    }
+
+    /// Like `load_limited`, keeping the image's own color type.
+    pub fn load_limited_image(
+        bytes: &[u8],
+        limits: &DecodeLimits,
+    ) -> Result<DynamicImage, DecodeError> {
+        let open = || {
+            ImageReader::new(Cursor::new(bytes))
+                .with_guessed_format()
+                .map_err(|e| DecodeError::Image(ImageError::IoError(e)))
+        };
+
+        let probe = open()?;
+        if probe.format().is_none() {
+            return Err(DecodeError::Malformed);
+        }
+
+        let (width, height) = probe.into_dimensions()?;
+        if width > limits.max_width || height > limits.max_height {
+            return Err(DecodeError::TooLarge { width, height });
+        }
+
+        // One Point per pixel on top of the decoded luma buffer.
+        let pixels = width as u64 * height as u64;
+        let required = pixels * (std::mem::size_of::<Point>() as u64 + 1);
+        if required > limits.max_alloc {
+            return Err(DecodeError::AllocationLimit(required));
+        }
+
+        let mut decoder_limits = Limits::default();
+        decoder_limits.max_image_width = Some(limits.max_width);
+        decoder_limits.max_image_height = Some(limits.max_height);
+        decoder_limits.max_alloc = Some(limits.max_alloc);
+
+        let mut reader = open()?;
+        reader.limits(decoder_limits);
+        let image = reader.decode()?;
+
+        // Headers can lie about dimensions, trust only the decoded buffer.
+        let (width, height) = image.dimensions();
+        if width > limits.max_width || height > limits.max_height {
+            return Err(DecodeError::TooLarge { width, height });
+        }
+
+        Ok(image)
+    }
+
+    /// Write the PNG to `path` as `Renderer::write_to` does, scrambled and with metadata
+    /// when the bitmap carries them.
     pub fn save(&self, path: &str) {
-        self.buf.save(path).unwrap();
+        let mut file = std::fs::File::create(path).unwrap();
+        <Bitmap as Renderer<Point>>::write_to(self, &mut file).unwrap();
     }
 
     pub fn write_png_to(&self, buffer: &mut std::io::BufWriter<Cursor<Vec<u8>>>) {
-        self.buf.write_to(buffer, image::ImageFormat::Png).unwrap()
+        <Bitmap as Renderer<Point>>::write_to(self, buffer).unwrap()
     }
 
     pub fn get_export_type() -> image::ImageOutputFormat {
         image::ImageOutputFormat::Png
     }
 }
+
+/// Fill the module for canvas cell (x, y), counted from the image edge.
+pub(crate) fn fill_cell(buf: &mut GrayImage, layout: &Layout, x: u32, y: u32, luminosity: u8) {
+    let module = layout.module;
+    let x0 = x * module;
+    let y0 = y * module;
+
+    for py in y0..y0 + module {
+        for px in x0..x0 + module {
+            *buf.get_pixel_mut(px, py) = image::Luma([luminosity]);
+        }
+    }
+}
+
+/// Mean luma of the window `radius_x`/`radius_y` pixels around (cx, cy), clipped to the image.
+pub(crate) fn window_mean(luma: &GrayImage, cx: f32, cy: f32, radius_x: i64, radius_y: i64) -> u64 {
+    let (width, height) = luma.dimensions();
+
+    let mut sum = 0u64;
+    let mut count = 0u64;
+    for dy in -radius_y..=radius_y {
+        for dx in -radius_x..=radius_x {
+            let px = cx as i64 + dx;
+            let py = cy as i64 + dy;
+            if px < 0 || py < 0 || px >= width as i64 || py >= height as i64 {
+                continue;
+            }
+
+            sum += luma.get_pixel(px as u32, py as u32).0[0] as u64;
+            count += 1;
+        }
+    }
+
+    sum.checked_div(count).unwrap_or(0)
+}
+
+impl Renderer<Point> for Bitmap {
+    fn render(&mut self, geometry: &dyn Geometry<Point>) {
+        self.from_geometry(geometry);
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let scrambled = self.scrambler.as_ref().map(|scrambler| {
+            let mut buf = self.buf.clone();
+            scrambler.apply(&mut buf);
+            buf
+        });
+        let buf = scrambled.as_ref().unwrap_or(&self.buf);
+
+        let (width, height) = buf.dimensions();
+        let Some(metadata) = &self.metadata else {
+            return PngEncoder::new(writer)
+                .write_image(buf.as_raw(), width, height, image::ColorType::L8)
+                .map_err(io::Error::other);
+        };
+
+        let mut png = vec![];
+        PngEncoder::new(&mut png)
+            .write_image(buf.as_raw(), width, height, image::ColorType::L8)
+            .map_err(io::Error::other)?;
+        let png = metadata
+            .to_png(&png)
+            .ok_or_else(|| io::Error::other("encoder wrote a malformed PNG"))?;
+        writer.write_all(&png)
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/png"
+    }
+}
+
+impl Renderer<LossyPoint> for Bitmap {
+    /// Lossy points are rounded to the nearest cell, points falling outside the grid are dropped.
+    fn render(&mut self, geometry: &dyn Geometry<LossyPoint>) {
+        let dim = self.layout.dim as f32;
+
+        for point in geometry.get_points().iter() {
+            let x = point.x.round();
+            let y = point.y.round();
+            if x < 0. || y < 0. || x >= dim || y >= dim {
+                continue;
+            }
+
+            let luminosity = point.z.unwrap_or(255.).clamp(0., 255.) as u8;
+            self.paint(x as u32, y as u32, luminosity);
+        }
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        <Bitmap as Renderer<Point>>::write_to(self, writer)
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/png"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{decode_spiral_report, Sampling};
+    use crate::encoder::Encoder;
+    use crate::geometry::SpiralGeometry;
+    use crate::threshold::Threshold;
+
+    #[test]
+    fn saves_scrambled_with_metadata() {
+        let mut geometry = SpiralGeometry::new(256);
+        let encoder: Encoder<Point> = Encoder::from_bytes(256, b"scrambled", &mut geometry);
+        let scrambler = Scrambler::new("passphrase");
+        let metadata = EncodingParams::new("spiral", 256).with_scrambled(true);
+        let mut bitmap = Bitmap::new(256)
+            .with_scrambler(scrambler.clone())
+            .with_metadata(metadata.clone());
+        encoder.render(&mut bitmap);
+
+        let path = std::env::temp_dir().join(format!("tim-save-{}.png", std::process::id()));
+        bitmap.save(path.to_str().unwrap());
+        let saved = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut written = std::io::BufWriter::new(Cursor::new(vec![]));
+        bitmap.write_png_to(&mut written);
+        assert_eq!(written.into_inner().unwrap().into_inner(), saved);
+
+        assert_eq!(EncodingParams::from_png(&saved), Some(metadata));
+        let report = decode_spiral_report(
+            &saved,
+            &DecodeLimits::default(),
+            &Sampling::Scrambled(*bitmap.layout(), scrambler),
+            &Threshold::Auto,
+        )
+        .unwrap();
+        assert_eq!(report.payload.unwrap(), b"scrambled");
+    }
+}
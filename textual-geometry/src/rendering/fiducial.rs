@@ -0,0 +1,624 @@
+use std::collections::HashMap;
+
+use image::GrayImage;
+
+use super::bitmap::{fill_cell, window_mean};
+use super::Layout;
+use crate::geometry::{Point, PreGeometry};
+use crate::threshold::otsu;
+
+/// Cells on each side of the grid reserved for a 7 cell finder pattern and a 1 cell separator.
+pub const FINDER_MARGIN: u32 = 8;
+
+const FINDER_SIZE: u32 = 7;
+const ALIGNMENT_SIZE: u32 = 5;
+
+// Run length ratios across the center of each pattern, lit-dark-lit-dark-lit.
+const FINDER_RATIO: [f32; 5] = [1., 1., 3., 1., 1.];
+const ALIGNMENT_RATIO: [f32; 5] = [1., 1., 1., 1., 1.];
+
+/// Candidates kept from one scan. A frame has three finders and the odd false positive,
+/// an image turning up more than this is noise, or made to be expensive to search.
+const MAX_CANDIDATES: usize = 1024;
+
+/**
+ * Draw QR style finder patterns in the top left, top right and bottom left corners of
+ * the margin, and a smaller alignment pattern in the bottom right. The patterns are
+ * inverted relative to QR (lit on dark) to match the spiral's white on black.
+ */
+pub fn draw(buf: &mut GrayImage, layout: &Layout) {
+    let near = layout.quiet;
+    let far = layout.quiet + FINDER_MARGIN + layout.dim + 1;
+
+    for (ox, oy) in [(near, near), (far, near), (near, far)] {
+        for j in 0..FINDER_SIZE {
+            for i in 0..FINDER_SIZE {
+                let ring = i.abs_diff(3).max(j.abs_diff(3));
+                if ring != 2 {
+                    fill_cell(buf, layout, ox + i, oy + j, 255);
+                }
+            }
+        }
+    }
+
+    // Centered in the 7 cell slot a finder would occupy.
+    let offset = (FINDER_SIZE - ALIGNMENT_SIZE) / 2;
+    for j in 0..ALIGNMENT_SIZE {
+        for i in 0..ALIGNMENT_SIZE {
+            let ring = i.abs_diff(2).max(j.abs_diff(2));
+            if ring != 1 {
+                fill_cell(buf, layout, far + offset + i, far + offset + j, 255);
+            }
+        }
+    }
+}
+
+/// Projective transform from frame cell coordinates to image pixel coordinates.
+pub struct Homography([f64; 9]);
+
+impl Homography {
+    /// Solve for the transform mapping each `src` point onto the matching `dst` point.
+    pub fn from_points(src: &[(f64, f64); 4], dst: &[(f64, f64); 4]) -> Option<Homography> {
+        let mut a = [[0f64; 9]; 8];
+        for (i, (&(x, y), &(u, v))) in src.iter().zip(dst.iter()).enumerate() {
+            a[2 * i] = [x, y, 1., 0., 0., 0., -u * x, -u * y, u];
+            a[2 * i + 1] = [0., 0., 0., x, y, 1., -v * x, -v * y, v];
+        }
+
+        // Gaussian elimination with partial pivoting on the augmented 8x9 system.
+        for col in 0..8 {
+            let pivot =
+                (col..8).max_by(|&r1, &r2| a[r1][col].abs().total_cmp(&a[r2][col].abs()))?;
+            if a[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            a.swap(col, pivot);
+
+            for row in 0..8 {
+                if row == col {
+                    continue;
+                }
+
+                let pivot_row = a[col];
+                let factor = a[row][col] / pivot_row[col];
+                for (k, value) in a[row].iter_mut().enumerate().skip(col) {
+                    *value -= factor * pivot_row[k];
+                }
+            }
+        }
+
+        let mut h = [1f64; 9];
+        for (i, row) in a.iter().enumerate() {
+            h[i] = row[8] / row[i];
+        }
+
+        Some(Homography(h))
+    }
+
+    pub fn map(&self, x: f64, y: f64) -> (f64, f64) {
+        let h = &self.0;
+        let w = h[6] * x + h[7] * y + h[8];
+        (
+            (h[0] * x + h[1] * y + h[2]) / w,
+            (h[3] * x + h[4] * y + h[5]) / w,
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Candidate {
+    x: f32,
+    y: f32,
+    module: f32,
+    hits: u32,
+}
+
+/// Thresholded view of a grayscale image.
+struct Binary<'a> {
+    luma: &'a GrayImage,
+    threshold: u8,
+}
+
+impl Binary<'_> {
+    fn lit(&self, x: i64, y: i64) -> Option<bool> {
+        let (width, height) = self.luma.dimensions();
+        if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+            return None;
+        }
+
+        Some(self.luma.get_pixel(x as u32, y as u32).0[0] > self.threshold)
+    }
+}
+
+/**
+ * Find the data grid of a layout rendered with finders in an arbitrary photo or
+ * screenshot, and resample it to a dim x dim pregeometry of mean cell luma. Also
+ * returns the level between the finders' lit and dark modules.
+ */
+pub fn locate(luma: &GrayImage, layout: &Layout) -> Option<(PreGeometry, u8)> {
+    let binary = Binary {
+        luma,
+        threshold: otsu(luma.pixels().map(|pix| pix.0[0])),
+    };
+
+    // However the frame sits in the image, its finders can't be much larger than if it
+    // filled the image, allowing for perspective stretching the near corner.
+    let (width, height) = luma.dimensions();
+    let span = layout.dim as f32 + (FINDER_MARGIN * 2) as f32;
+    let max_module = 2. * width.max(height) as f32 / span;
+
+    let candidates = find_candidates(&binary, &FINDER_RATIO, None, max_module);
+    let (tl, tr, bl) = pick_finders(&candidates, layout.dim)?;
+
+    // Start from the parallelogram estimate, then refine with the alignment pattern
+    // when it can be found since it carries the perspective.
+    let module = (tl.module + tr.module + bl.module) / 3.;
+    let estimate = (tr.x + bl.x - tl.x, tr.y + bl.y - tl.y);
+    let br = find_alignment(&binary, estimate, module).unwrap_or(estimate);
+
+    // Pattern centers in frame cells, relative to the top left corner of the top left finder.
+    let near = FINDER_SIZE as f64 / 2.;
+    let far = FINDER_MARGIN as f64 + layout.dim as f64 + 1. + near;
+    let homography = Homography::from_points(
+        &[(near, near), (far, near), (near, far), (far, far)],
+        &[
+            (tl.x as f64, tl.y as f64),
+            (tr.x as f64, tr.y as f64),
+            (bl.x as f64, bl.y as f64),
+            (br.0 as f64, br.1 as f64),
+        ],
+    )?;
+
+    let radius = (module / 6.).floor() as i64;
+    let sample = |cx: f64, cy: f64| {
+        let (px, py) = homography.map(cx, cy);
+        window_mean(luma, px as f32, py as f32, radius, radius)
+    };
+
+    // Take the lit and dark levels from the finders themselves rather than trusting the
+    // global threshold, lighting across a photo is rarely even.
+    let mut lit = 0;
+    let mut dark = 0;
+    for (ox, oy) in [(0., 0.), (far - near, 0.), (0., far - near)] {
+        lit += sample(ox + near, oy + near);
+        dark += sample(ox + near, oy + 1.5);
+    }
+    let threshold = (lit + dark) / 6;
+
+    let margin = FINDER_MARGIN as f64;
+    let mut points = Vec::with_capacity(layout.dim as usize * layout.dim as usize);
+    for y in 0..layout.dim {
+        for x in 0..layout.dim {
+            let mean = sample(margin + x as f64 + 0.5, margin + y as f64 + 0.5);
+            points.push(Point {
+                x,
+                y,
+                z: Some(mean as u32),
+            });
+        }
+    }
+
+    Some((((layout.dim, layout.dim), points), threshold as u8))
+}
+
+/// Module size if `counts` matches `ratio` within half a module per unit.
+fn check_ratio(counts: &[u32; 5], ratio: &[f32; 5]) -> Option<f32> {
+    let total: u32 = counts.iter().sum();
+    let units: f32 = ratio.iter().sum();
+    if (total as f32) < units {
+        return None;
+    }
+
+    let module = total as f32 / units;
+    let tolerance = module * 0.5;
+    for (count, unit) in counts.iter().zip(ratio.iter()) {
+        if (*count as f32 - unit * module).abs() > unit * tolerance {
+            return None;
+        }
+    }
+
+    Some(module)
+}
+
+/**
+ * Measure the lit-dark-lit-dark-lit runs crossing step 0 of a line, `at(i)` giving the
+ * pixel i steps from the start. Returns the run lengths and the offset of the center
+ * run's midpoint.
+ */
+fn cross_runs(at: impl Fn(i64) -> Option<bool>, max_run: u32) -> Option<([u32; 5], f32)> {
+    if at(0) != Some(true) {
+        return None;
+    }
+
+    let mut counts = [0u32; 5];
+
+    // Walk outwards from the center run in one direction, filling the runs on that side.
+    let walk = |dir: i64, counts: &mut [u32; 5]| -> Option<i64> {
+        let (center, inner, outer) = if dir < 0 { (2, 1, 0) } else { (2, 3, 4) };
+        let mut i = 0;
+        while at(i) == Some(true) {
+            counts[center] += 1;
+            if counts[center] > max_run * 3 {
+                return None;
+            }
+            i += dir;
+        }
+        let edge = i - dir;
+
+        for (slot, want) in [(inner, false), (outer, true)] {
+            while at(i) == Some(want) {
+                counts[slot] += 1;
+                if counts[slot] > max_run {
+                    return None;
+                }
+                i += dir;
+            }
+            if counts[slot] == 0 {
+                return None;
+            }
+        }
+
+        Some(edge)
+    };
+
+    let low = walk(-1, &mut counts)?;
+    let high = walk(1, &mut counts)?;
+    // The center pixel was counted by both walks.
+    counts[2] -= 1;
+
+    Some((counts, (low + high) as f32 / 2.))
+}
+
+/**
+ * Scan rows for the given pattern and cross check each hit vertically and then
+ * horizontally again. Hits within a module or two of each other are merged, patterns
+ * with modules over `max_module` pixels are ignored. `window` restricts the scan to
+ * (x0, y0, x1, y1). The scan gives up once it holds `MAX_CANDIDATES`.
+ */
+fn find_candidates(
+    binary: &Binary,
+    ratio: &[f32; 5],
+    window: Option<(i64, i64, i64, i64)>,
+    max_module: f32,
+) -> Vec<Candidate> {
+    let (width, height) = binary.luma.dimensions();
+    let (x0, y0, x1, y1) = window.unwrap_or((0, 0, width as i64, height as i64));
+    let (x0, y0) = (x0.max(0), y0.max(0));
+    let (x1, y1) = (x1.min(width as i64), y1.min(height as i64));
+
+    let mut candidates = Candidates::new(max_module);
+
+    for y in y0..y1 {
+        // Run lengths of the row as (lit, start, length).
+        let mut runs: Vec<(bool, i64, u32)> = vec![];
+        for x in x0..x1 {
+            let lit = binary.lit(x, y) == Some(true);
+            match runs.last_mut() {
+                Some(run) if run.0 == lit => run.2 += 1,
+                _ => runs.push((lit, x, 1)),
+            }
+        }
+
+        for window in runs.windows(5) {
+            if !window[0].0 {
+                continue;
+            }
+
+            let counts = [
+                window[0].2,
+                window[1].2,
+                window[2].2,
+                window[3].2,
+                window[4].2,
+            ];
+            let Some(module) = check_ratio(&counts, ratio).filter(|&m| m <= max_module) else {
+                continue;
+            };
+
+            let cx = window[2].1 + window[2].2 as i64 / 2;
+            let Some(candidate) = cross_check(binary, ratio, cx, y, module) else {
+                continue;
+            };
+            if candidate.module <= max_module && !candidates.merge(candidate) {
+                return candidates.all;
+            }
+        }
+    }
+
+    candidates.all
+}
+
+fn cross_check(
+    binary: &Binary,
+    ratio: &[f32; 5],
+    x: i64,
+    y: i64,
+    module: f32,
+) -> Option<Candidate> {
+    let max_run = (module * 2.).ceil() as u32 + 1;
+
+    let (vertical, dy) = cross_runs(|i| binary.lit(x, y + i), max_run)?;
+    let module_v = check_ratio(&vertical, ratio)?;
+    let cy = y as f32 + dy;
+
+    let (horizontal, dx) = cross_runs(|i| binary.lit(x + i, cy as i64), max_run)?;
+    let module_h = check_ratio(&horizontal, ratio)?;
+    let cx = x as f32 + dx;
+
+    // A square pattern seen through moderate perspective stays roughly square.
+    if module_v / module_h > 2. || module_h / module_v > 2. {
+        return None;
+    }
+
+    Some(Candidate {
+        x: cx + 0.5,
+        y: cy + 0.5,
+        module: (module_v + module_h) / 2.,
+        hits: 1,
+    })
+}
+
+/**
+ * Candidates bucketed by position in cells two of the largest modules wide. Hits merge
+ * with candidates within two modules of them, so only the 3x3 cells around a hit need
+ * comparing, however many candidates there are.
+ */
+struct Candidates {
+    cell: f32,
+    all: Vec<Candidate>,
+    grid: HashMap<(i64, i64), Vec<usize>>,
+}
+
+impl Candidates {
+    fn new(max_module: f32) -> Candidates {
+        Candidates {
+            cell: (max_module * 2.).max(1.),
+            all: vec![],
+            grid: HashMap::new(),
+        }
+    }
+
+    fn key(&self, candidate: &Candidate) -> (i64, i64) {
+        (
+            (candidate.x / self.cell).floor() as i64,
+            (candidate.y / self.cell).floor() as i64,
+        )
+    }
+
+    /// Fold a hit into a nearby candidate or add it. False if it had to be added and
+    /// there are `MAX_CANDIDATES` already.
+    fn merge(&mut self, candidate: Candidate) -> bool {
+        let (kx, ky) = self.key(&candidate);
+        let near = (ky - 1..=ky + 1)
+            .flat_map(|y| (kx - 1..=kx + 1).map(move |x| (x, y)))
+            .filter_map(|key| self.grid.get(&key))
+            .flatten()
+            .copied()
+            .find(|&ix| {
+                let existing = &self.all[ix];
+                let distance = (existing.x - candidate.x).hypot(existing.y - candidate.y);
+                distance < existing.module.max(candidate.module) * 2.
+            });
+
+        let Some(ix) = near else {
+            if self.all.len() >= MAX_CANDIDATES {
+                return false;
+            }
+            self.grid.entry((kx, ky)).or_default().push(self.all.len());
+            self.all.push(candidate);
+            return true;
+        };
+
+        let old = self.key(&self.all[ix]);
+        let existing = &mut self.all[ix];
+        let n = existing.hits as f32;
+        existing.x = (existing.x * n + candidate.x) / (n + 1.);
+        existing.y = (existing.y * n + candidate.y) / (n + 1.);
+        existing.module = (existing.module * n + candidate.module) / (n + 1.);
+        existing.hits += 1;
+
+        // Averaging can carry a candidate over into the next cell.
+        let new = self.key(&self.all[ix]);
+        if new != old {
+            if let Some(bucket) = self.grid.get_mut(&old) {
+                bucket.retain(|&other| other != ix);
+            }
+            self.grid.entry(new).or_default().push(ix);
+        }
+
+        true
+    }
+}
+
+/**
+ * Pick the three candidates forming the top left, top right and bottom left finders.
+ * They have to form a roughly isosceles right triangle about `dim + 9` modules on a
+ * side; the winding of the triangle decides which corner is which, so any rotation
+ * of the image decodes.
+ */
+fn pick_finders(candidates: &[Candidate], dim: u32) -> Option<(Candidate, Candidate, Candidate)> {
+    let mut strongest = candidates.to_vec();
+    strongest.sort_by_key(|c| std::cmp::Reverse(c.hits));
+    strongest.truncate(8);
+
+    let expected = dim as f32 + (FINDER_MARGIN + 1) as f32;
+    let mut best: Option<((Candidate, Candidate, Candidate), f32)> = None;
+
+    for (i, &a) in strongest.iter().enumerate() {
+        for (j, &b) in strongest.iter().enumerate() {
+            for (k, &c) in strongest.iter().enumerate() {
+                if i == j || j == k || i == k || j > k {
+                    continue;
+                }
+
+                let ab = (b.x - a.x, b.y - a.y);
+                let ac = (c.x - a.x, c.y - a.y);
+                let len_ab = ab.0.hypot(ab.1);
+                let len_ac = ac.0.hypot(ac.1);
+                if len_ab == 0. || len_ac == 0. {
+                    continue;
+                }
+
+                let cos = (ab.0 * ac.0 + ab.1 * ac.1) / (len_ab * len_ac);
+                let skew = len_ab / len_ac;
+                if cos.abs() > 0.35 || !(0.6..=1.67).contains(&skew) {
+                    continue;
+                }
+
+                let module = (a.module + b.module + c.module) / 3.;
+                let span = (len_ab + len_ac) / 2. / module;
+                if !(expected * 0.6..=expected * 1.67).contains(&span) {
+                    continue;
+                }
+
+                let score = (a.hits + b.hits + c.hits) as f32 * (1. - cos.abs());
+                if best.as_ref().is_none_or(|(_, s)| score > *s) {
+                    // Clockwise from a (y down) means b is top right.
+                    let cross = ab.0 * ac.1 - ab.1 * ac.0;
+                    let corners = if cross > 0. { (a, b, c) } else { (a, c, b) };
+                    best = Some((corners, score));
+                }
+            }
+        }
+    }
+
+    best.map(|(corners, _)| corners)
+}
+
+/// Search for the alignment pattern in growing windows around the parallelogram
+/// estimate, strong perspective can push it a few dozen modules away.
+fn find_alignment(binary: &Binary, estimate: (f32, f32), module: f32) -> Option<(f32, f32)> {
+    let (ex, ey) = (estimate.0 as i64, estimate.1 as i64);
+
+    for modules in [6., 16., 32., 64.] {
+        let reach = (module * modules).ceil() as i64;
+        let window = (ex - reach, ey - reach, ex + reach, ey + reach);
+
+        let nearest = find_candidates(binary, &ALIGNMENT_RATIO, Some(window), module * 2.)
+            .into_iter()
+            .filter(|c| c.module > module * 0.5 && c.module < module * 2.)
+            .min_by(|a, b| {
+                let da = (a.x - estimate.0).hypot(a.y - estimate.1);
+                let db = (b.x - estimate.0).hypot(b.y - estimate.1);
+                da.total_cmp(&db)
+            });
+
+        if let Some(c) = nearest {
+            return Some((c.x, c.y));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::decode::{decode_spiral_photo, DecodeError, DecodeLimits};
+    use crate::encoder::Encoder;
+    use crate::geometry::SpiralGeometry;
+    use crate::rendering::Bitmap;
+
+    fn render(payload: &[u8], layout: Layout) -> GrayImage {
+        let mut geometry = SpiralGeometry::new(layout.dim);
+        let encoder: Encoder<Point> = Encoder::from_bytes(layout.dim, payload, &mut geometry);
+        let mut bitmap = Bitmap::with_layout(layout);
+        encoder.render(&mut bitmap);
+        bitmap.buf
+    }
+
+    fn png(image: GrayImage) -> Vec<u8> {
+        let mut out = Cursor::new(vec![]);
+        image::DynamicImage::ImageLuma8(image)
+            .write_to(&mut out, image::ImageOutputFormat::Png)
+            .unwrap();
+        out.into_inner()
+    }
+
+    /// Project the image's corners onto `corners` of a larger canvas, as a photo of it
+    /// taken at an angle would, sampling the nearest source pixel.
+    fn warp(image: &GrayImage, corners: [(f64, f64); 4], size: u32) -> GrayImage {
+        let (w, h) = (image.width() as f64, image.height() as f64);
+        let to_source =
+            Homography::from_points(&corners, &[(0., 0.), (w, 0.), (0., h), (w, h)]).unwrap();
+
+        GrayImage::from_fn(size, size, |x, y| {
+            let (sx, sy) = to_source.map(x as f64 + 0.5, y as f64 + 0.5);
+            if sx < 0. || sy < 0. || sx >= w || sy >= h {
+                return image::Luma([0]);
+            }
+            *image.get_pixel(sx as u32, sy as u32)
+        })
+    }
+
+    #[test]
+    fn decodes_rotated_and_perspective_photos() {
+        let layout = Layout::new(256)
+            .with_module(3)
+            .with_quiet(4)
+            .with_finders(true);
+        let payload = b"photographed at an angle, upside down and all";
+        let image = render(payload, layout);
+        let limits = DecodeLimits::default();
+        let size = image.width() as f64;
+
+        let rotated = image::imageops::rotate270(&image);
+        let decoded = decode_spiral_photo(&png(rotated), &limits, &layout).unwrap();
+        assert_eq!(decoded, payload);
+
+        let corners = [
+            (60., 30.),
+            (size + 70., 80.),
+            (20., size + 40.),
+            (size + 110., size + 120.),
+        ];
+        let warped = warp(&image, corners, size as u32 + 160);
+        let decoded = decode_spiral_photo(&png(warped), &limits, &layout).unwrap();
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn refuses_images_without_finders() {
+        let layout = Layout::new(256).with_finders(true);
+        let limits = DecodeLimits::default();
+
+        let plain = render(b"no finders here", Layout::new(256).with_quiet(8));
+        let decoded = decode_spiral_photo(&png(plain), &limits, &layout);
+        assert!(matches!(decoded, Err(DecodeError::FindersNotFound)));
+
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let noise = GrayImage::from_fn(300, 300, |_, _| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            image::Luma([state as u8])
+        });
+        assert!(decode_spiral_photo(&png(noise), &limits, &layout).is_err());
+    }
+
+    #[test]
+    fn caps_candidates_in_images_full_of_finders() {
+        // One pixel finders on a 9 pixel pitch, every one a valid candidate.
+        let tiled = GrayImage::from_fn(1024, 1024, |x, y| {
+            let (i, j) = (x % 9, y % 9);
+            let ring = i.abs_diff(4).max(j.abs_diff(4));
+            image::Luma([if ring == 2 || ring == 4 { 0 } else { 255 }])
+        });
+
+        let binary = Binary {
+            luma: &tiled,
+            threshold: 127,
+        };
+        let candidates = find_candidates(&binary, &FINDER_RATIO, None, 8.);
+        assert_eq!(candidates.len(), MAX_CANDIDATES);
+
+        let decoded = decode_spiral_photo(
+            &png(tiled),
+            &DecodeLimits::default(),
+            &Layout::new(256).with_finders(true),
+        );
+        assert!(decoded.is_err());
+    }
+}
@@ -1,5 +1,16 @@
 pub mod bitmap;
+pub mod fiducial;
+pub mod paper;
+pub mod renderer;
+pub mod scramble;
+pub mod stego;
 pub mod svg;
+pub mod visual;
 
 pub use bitmap::*;
+pub use paper::*;
+pub use renderer::*;
+pub use scramble::*;
+pub use stego::*;
 pub use svg::*;
+pub use visual::*;
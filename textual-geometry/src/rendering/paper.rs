@@ -0,0 +1,432 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use image::GrayImage;
+
+/// Margin around every sheet, in points.
+const MARGIN: f64 = 36.;
+const FONT_SIZE: f64 = 10.;
+const LINE_HEIGHT: f64 = 13.;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaperFormat {
+    Pdf,
+    PostScript,
+}
+
+impl PaperFormat {
+    /// PostScript for `.ps` and `.eps` paths, PDF for anything else.
+    pub fn from_path(path: &str) -> Self {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".ps") || lower.ends_with(".eps") {
+            PaperFormat::PostScript
+        } else {
+            PaperFormat::Pdf
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaperSize {
+    A4,
+    Letter,
+}
+
+impl PaperSize {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "a4" => Some(PaperSize::A4),
+            "letter" => Some(PaperSize::Letter),
+            _ => None,
+        }
+    }
+
+    /// Width and height in points.
+    pub fn points(&self) -> (f64, f64) {
+        match *self {
+            PaperSize::A4 => (595., 842.),
+            PaperSize::Letter => (612., 792.),
+        }
+    }
+}
+
+/// One printed sheet: a few lines of human readable header above an image.
+pub struct Sheet {
+    pub header: Vec<String>,
+    /// One pixel per cell, scaled to `module` printer dots when laid out.
+    pub image: GrayImage,
+}
+
+/**
+ * Lays images out onto printable PDF or PostScript sheets, one per page, with each
+ * image pixel printed as a `module` x `module` square of dots at `dpi`.
+ */
+pub struct Paper {
+    format: PaperFormat,
+    size: PaperSize,
+    dpi: u32,
+    module: u32,
+    sheets: Vec<Sheet>,
+}
+
+impl Paper {
+    pub fn new(format: PaperFormat) -> Paper {
+        Paper {
+            format,
+            size: PaperSize::A4,
+            dpi: 300,
+            module: 4,
+            sheets: vec![],
+        }
+    }
+
+    pub fn with_size(mut self, size: PaperSize) -> Paper {
+        self.size = size;
+        self
+    }
+
+    pub fn with_dpi(mut self, dpi: u32) -> Paper {
+        self.dpi = dpi.max(1);
+        self
+    }
+
+    pub fn with_module(mut self, module: u32) -> Paper {
+        self.module = module.max(1);
+        self
+    }
+
+    pub fn add_sheet(&mut self, header: Vec<String>, image: GrayImage) {
+        self.sheets.push(Sheet { header, image });
+    }
+
+    pub fn sheets(&self) -> &[Sheet] {
+        &self.sheets
+    }
+
+    pub fn write_to_path(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        match self.format {
+            PaperFormat::Pdf => self.write_pdf(writer),
+            PaperFormat::PostScript => self.write_postscript(writer),
+        }
+    }
+
+    /// Where a sheet's image goes, as its lower left corner and printed width and height
+    /// in points. Errors if it doesn't fit below the header at this dpi.
+    fn place(&self, sheet: &Sheet) -> io::Result<(f64, f64, f64, f64)> {
+        let (page_w, page_h) = self.size.points();
+        let scale = self.module as f64 * 72. / self.dpi as f64;
+        let (w, h) = (
+            sheet.image.width() as f64 * scale,
+            sheet.image.height() as f64 * scale,
+        );
+
+        let top = page_h - MARGIN - LINE_HEIGHT * (sheet.header.len() as f64 + 1.);
+        if w > page_w - 2. * MARGIN || h > top - MARGIN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a {:.0}x{:.0}pt grid doesn't fit on the sheet, raise the dpi or lower the module",
+                    w, h
+                ),
+            ));
+        }
+
+        Ok(((page_w - w) / 2., top - h, w, h))
+    }
+
+    fn header_lines<'s>(&self, sheet: &'s Sheet) -> impl Iterator<Item = (f64, f64, &'s str)> {
+        let (_, page_h) = self.size.points();
+        sheet.header.iter().enumerate().map(move |(i, line)| {
+            (
+                MARGIN,
+                page_h - MARGIN - LINE_HEIGHT * (i as f64 + 1.),
+                line.as_str(),
+            )
+        })
+    }
+
+    fn write_pdf(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let (page_w, page_h) = self.size.points();
+
+        // Objects 1 and 2 are the catalog and page tree, 3 the font, then a page, its
+        // content stream and its image for every sheet.
+        let mut objects: Vec<Vec<u8>> = vec![];
+        objects.push(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+        let kids = (0..self.sheets.len())
+            .map(|i| format!("{} 0 R", 4 + i * 3))
+            .collect::<Vec<_>>()
+            .join(" ");
+        objects.push(
+            format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids,
+                self.sheets.len()
+            )
+            .into_bytes(),
+        );
+        objects.push(b"<< /Type /Font /Subtype /Type1 /BaseFont /Courier >>".to_vec());
+
+        for (i, sheet) in self.sheets.iter().enumerate() {
+            let (x, y, w, h) = self.place(sheet)?;
+            let content_id = 5 + i * 3;
+            let image_id = 6 + i * 3;
+
+            objects.push(
+                format!(
+                    "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Contents {} 0 R \
+                     /Resources << /Font << /F1 3 0 R >> /XObject << /Im1 {} 0 R >> >> >>",
+                    page_w, page_h, content_id, image_id
+                )
+                .into_bytes(),
+            );
+
+            let mut content = String::new();
+            for (tx, ty, line) in self.header_lines(sheet) {
+                content.push_str(&format!(
+                    "BT /F1 {} Tf {:.2} {:.2} Td ({}) Tj ET\n",
+                    FONT_SIZE,
+                    tx,
+                    ty,
+                    escape_text(line)
+                ));
+            }
+            content.push_str(&format!(
+                "q {:.3} 0 0 {:.3} {:.3} {:.3} cm /Im1 Do Q\n",
+                w, h, x, y
+            ));
+            objects.push(stream(
+                &format!("<< /Length {} >>", content.len()),
+                content.as_bytes(),
+            ));
+
+            let (iw, ih) = sheet.image.dimensions();
+            let dict = format!(
+                "<< /Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceGray \
+                 /BitsPerComponent 8 /Interpolate false /Length {} >>",
+                iw,
+                ih,
+                sheet.image.as_raw().len()
+            );
+            objects.push(stream(&dict, sheet.image.as_raw()));
+        }
+
+        let mut out: Vec<u8> = b"%PDF-1.4\n".to_vec();
+        let mut offsets = Vec::with_capacity(objects.len());
+        for (i, object) in objects.iter().enumerate() {
+            offsets.push(out.len());
+            out.extend(format!("{} 0 obj\n", i + 1).as_bytes());
+            out.extend(object);
+            out.extend(b"\nendobj\n");
+        }
+
+        let xref = out.len();
+        out.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+        for offset in offsets {
+            out.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+        }
+        out.extend(
+            format!(
+                "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+                objects.len() + 1,
+                xref
+            )
+            .as_bytes(),
+        );
+
+        writer.write_all(&out)
+    }
+
+    fn write_postscript(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let (page_w, page_h) = self.size.points();
+
+        writeln!(writer, "%!PS-Adobe-3.0")?;
+        writeln!(writer, "%%BoundingBox: 0 0 {} {}", page_w, page_h)?;
+        writeln!(writer, "%%Pages: {}", self.sheets.len())?;
+        writeln!(writer, "%%EndComments")?;
+
+        for (i, sheet) in self.sheets.iter().enumerate() {
+            let (x, y, w, h) = self.place(sheet)?;
+            let (iw, ih) = sheet.image.dimensions();
+
+            writeln!(writer, "%%Page: {} {}", i + 1, i + 1)?;
+            writeln!(
+                writer,
+                "<< /PageSize [{} {}] >> setpagedevice",
+                page_w, page_h
+            )?;
+            writeln!(writer, "/Courier findfont {} scalefont setfont", FONT_SIZE)?;
+            for (tx, ty, line) in self.header_lines(sheet) {
+                writeln!(
+                    writer,
+                    "{:.2} {:.2} moveto ({}) show",
+                    tx,
+                    ty,
+                    escape_text(line)
+                )?;
+            }
+
+            writeln!(writer, "gsave")?;
+            writeln!(
+                writer,
+                "{:.3} {:.3} translate {:.3} {:.3} scale",
+                x, y, w, h
+            )?;
+            writeln!(writer, "/row {} string def", iw)?;
+            writeln!(
+                writer,
+                "{} {} 8 [{} 0 0 -{} 0 {}] {{currentfile row readhexstring pop}} image",
+                iw, ih, iw, ih, ih
+            )?;
+            for row in sheet.image.as_raw().chunks(iw as usize) {
+                // Keep lines short, some printers choke on very long ones.
+                for line in row.chunks(32) {
+                    writeln!(writer, "{}", hex::encode(line))?;
+                }
+            }
+            writeln!(writer, "grestore")?;
+            writeln!(writer, "showpage")?;
+        }
+
+        writeln!(writer, "%%EOF")
+    }
+}
+
+fn stream(dict: &str, data: &[u8]) -> Vec<u8> {
+    let mut out = dict.as_bytes().to_vec();
+    out.extend(b"\nstream\n");
+    out.extend(data);
+    out.extend(b"\nendstream");
+    out
+}
+
+/// Escape a line for a PDF or PostScript string literal. The base fonts only cover
+/// ASCII, anything else is replaced.
+fn escape_text(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '(' | ')' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' '..='~' => out.push(c),
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Luma;
+
+    use super::*;
+
+    fn paper(format: PaperFormat) -> Paper {
+        let mut paper = Paper::new(format).with_dpi(300).with_module(4);
+        for i in 0..2u8 {
+            let image = GrayImage::from_fn(64, 48, |x, y| Luma([((x + y) as u8) ^ i]));
+            paper.add_sheet(
+                vec![format!("page {} (of 2)", i + 1), String::from("tim")],
+                image,
+            );
+        }
+        paper
+    }
+
+    fn written(paper: &Paper) -> Vec<u8> {
+        let mut out = vec![];
+        paper.write_to(&mut out).unwrap();
+        out
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
+
+    #[test]
+    fn writes_a_pdf_with_valid_offsets() {
+        let pdf = written(&paper(PaperFormat::Pdf));
+        assert!(pdf.starts_with(b"%PDF-1.4\n"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+
+        let text = String::from_utf8_lossy(&pdf);
+        let startxref = text.rfind("startxref\n").unwrap();
+        let xref: usize = text[startxref + 10..]
+            .lines()
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(xref, find(&pdf, b"xref\n0 ").unwrap());
+
+        // The catalog, page tree and font, then three objects a sheet.
+        let mut lines = text[xref..].lines().skip(1);
+        assert_eq!(lines.next(), Some("0 10"));
+        assert_eq!(lines.next(), Some("0000000000 65535 f "));
+        for id in 1..10 {
+            let entry = lines.next().unwrap();
+            let offset: usize = entry[..10].parse().unwrap();
+            let object = format!("{} 0 obj\n", id);
+            assert!(pdf[offset..].starts_with(object.as_bytes()), "{}", id);
+        }
+        assert!(text.contains("/Size 10 /Root 1 0 R"));
+
+        assert!(text.contains("/Kids [4 0 R 7 0 R] /Count 2"));
+        assert_eq!(text.matches("/Type /Page ").count(), 2);
+        assert!(text.contains("(page 1 \\(of 2\\)) Tj"));
+        assert!(text.contains("/Width 64 /Height 48"));
+    }
+
+    #[test]
+    fn writes_postscript_pages() {
+        let paper = paper(PaperFormat::PostScript);
+        let ps = String::from_utf8(written(&paper)).unwrap();
+        assert!(ps.starts_with("%!PS-Adobe-3.0\n"));
+        assert!(ps.contains("%%Pages: 2\n"));
+        assert!(ps.contains("%%Page: 1 1\n") && ps.contains("%%Page: 2 2\n"));
+        assert_eq!(ps.matches("showpage").count(), 2);
+        assert!(ps.ends_with("%%EOF\n"));
+
+        // The first image's samples follow its image operator, in hex.
+        let start = ps.find("image\n").unwrap() + 6;
+        let hex: String = ps[start..]
+            .lines()
+            .take_while(|line| *line != "grestore")
+            .collect();
+        let first = &paper.sheets()[0].image;
+        assert_eq!(hex::decode(hex).unwrap(), first.as_raw().as_slice());
+    }
+
+    #[test]
+    fn refuses_grids_too_large_for_the_sheet() {
+        for format in [PaperFormat::Pdf, PaperFormat::PostScript] {
+            // 256 cells of 4 dots at 72dpi is over 1000pt across.
+            let mut paper = Paper::new(format).with_dpi(72).with_module(4);
+            paper.add_sheet(vec![], GrayImage::new(256, 256));
+            let error = paper.write_to(&mut vec![]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+            let mut fits = Paper::new(format).with_dpi(600).with_module(4);
+            fits.add_sheet(vec![], GrayImage::new(256, 256));
+            assert!(fits.write_to(&mut vec![]).is_ok());
+        }
+    }
+
+    #[test]
+    fn escapes_header_text() {
+        assert_eq!(escape_text("a (b) \\ c"), "a \\(b\\) \\\\ c");
+        assert_eq!(escape_text("café\n"), "caf??");
+        assert_eq!(PaperFormat::from_path("out.EPS"), PaperFormat::PostScript);
+        assert_eq!(PaperFormat::from_path("out.pdf"), PaperFormat::Pdf);
+        assert_eq!(PaperSize::from_name("Letter"), Some(PaperSize::Letter));
+    }
+}
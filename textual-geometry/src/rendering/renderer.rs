@@ -0,0 +1,28 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use crate::geometry::Geometry;
+
+/// An output backend for geometries with points of type `PointType`.
+///
+/// Implementations own their canvas; `render` draws a geometry onto it and the
+/// `write_*` methods serialize whatever has been drawn so far.
+pub trait Renderer<PointType> {
+    fn render(&mut self, geometry: &dyn Geometry<PointType>);
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()>;
+
+    fn mime_type(&self) -> &'static str;
+
+    fn write_to_path(&self, path: &str) -> io::Result<()> {
+        let mut file = BufWriter::new(File::create(path)?);
+        self.write_to(&mut file)?;
+        file.flush()
+    }
+
+    fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,337 @@
+use image::GrayImage;
+
+/// Logistic map iterations thrown away before the keystream starts, so nearby keys
+/// have diverged.
+const LOGISTIC_WARMUP: usize = 1000;
+
+/**
+ * A keyed, reversible pixel transform: positions are permuted with rounds of the
+ * Arnold cat map and a discretized baker's map, then intensities are diffused with a
+ * logistic map keystream chained through the previous output pixel, front to back and
+ * then back to front, so changing any one pixel changes all of them.
+ *
+ * This is the classic chaotic image cipher construction and is meant for
+ * experimentation, it is not a substitute for real encryption.
+ */
+#[derive(Clone, Debug)]
+pub struct Scrambler {
+    key: u64,
+    cat_rounds: u32,
+    baker_rounds: u32,
+    diffusion: bool,
+}
+
+impl Scrambler {
+    /// Derive every map parameter from a passphrase.
+    pub fn new(passphrase: &str) -> Scrambler {
+        // FNV-1a, stable across platforms and releases unlike std's hasher.
+        let mut key = 0xcbf2_9ce4_8422_2325u64;
+        for byte in passphrase.bytes() {
+            key ^= byte as u64;
+            key = key.wrapping_mul(0x0100_0000_01b3);
+        }
+
+        Scrambler {
+            key,
+            cat_rounds: 4,
+            baker_rounds: 2,
+            diffusion: true,
+        }
+    }
+
+    pub fn with_cat_rounds(mut self, rounds: u32) -> Scrambler {
+        self.cat_rounds = rounds;
+        self
+    }
+
+    pub fn with_baker_rounds(mut self, rounds: u32) -> Scrambler {
+        self.baker_rounds = rounds;
+        self
+    }
+
+    pub fn with_diffusion(mut self, diffusion: bool) -> Scrambler {
+        self.diffusion = diffusion;
+        self
+    }
+
+    /// Scramble a square image in place. Returns false, leaving it untouched, if the
+    /// image isn't square.
+    pub fn apply(&self, image: &mut GrayImage) -> bool {
+        let Some(permutation) = self.permutation(image) else {
+            return false;
+        };
+
+        let src = image.as_raw().clone();
+        let dst: &mut [u8] = image;
+        for (from, &to) in permutation.iter().enumerate() {
+            dst[to as usize] = src[from];
+        }
+
+        if self.diffusion {
+            // A change chained forward only reaches the pixels after it, so the output
+            // is chained back over again. Adding the previous output before the xor,
+            // rather than after, keeps a change from shifting every later pixel by the
+            // same amount.
+            let (forward, backward, iv) = self.keystream(dst.len());
+            let mut prev = iv;
+            for (pixel, k) in dst.iter_mut().zip(forward) {
+                *pixel = pixel.wrapping_add(prev) ^ k;
+                prev = *pixel;
+            }
+            prev = iv;
+            for (pixel, k) in dst.iter_mut().zip(backward).rev() {
+                *pixel = pixel.wrapping_add(prev) ^ k;
+                prev = *pixel;
+            }
+        }
+
+        true
+    }
+
+    /// Undo `apply` with the same key.
+    pub fn invert(&self, image: &mut GrayImage) -> bool {
+        let Some(permutation) = self.permutation(image) else {
+            return false;
+        };
+
+        if self.diffusion {
+            let len = image.as_raw().len();
+            let (forward, backward, iv) = self.keystream(len);
+            let pixels: &mut [u8] = image;
+            // Every backward output chains through the one after it, which is still
+            // in place when going front to back.
+            for ix in 0..len {
+                let next = pixels.get(ix + 1).copied().unwrap_or(iv);
+                pixels[ix] = (pixels[ix] ^ backward[ix]).wrapping_sub(next);
+            }
+            let mut prev = iv;
+            for (pixel, k) in pixels.iter_mut().zip(forward) {
+                let cipher = *pixel;
+                *pixel = (cipher ^ k).wrapping_sub(prev);
+                prev = cipher;
+            }
+        }
+
+        let src = image.as_raw().clone();
+        let dst: &mut [u8] = image;
+        for (from, &to) in permutation.iter().enumerate() {
+            dst[from] = src[to as usize];
+        }
+
+        true
+    }
+
+    /// Where every pixel ends up after all rounds, by row major index.
+    fn permutation(&self, image: &GrayImage) -> Option<Vec<u32>> {
+        let (width, height) = image.dimensions();
+        if width != height || width == 0 {
+            return None;
+        }
+
+        let n = width;
+        let mut rng = SplitMix(self.key);
+        let a = 1 + (rng.next() % n as u64) as u32;
+        let b = 1 + (rng.next() % n as u64) as u32;
+        let partition = baker_partition(n, &mut rng);
+
+        let mut total: Vec<u32> = (0..n * n).collect();
+        let cat = cat_map(n, a, b);
+        for _ in 0..self.cat_rounds {
+            total = total.iter().map(|&at| cat[at as usize]).collect();
+        }
+        let baker = baker_map(n, &partition)?;
+        for _ in 0..self.baker_rounds {
+            total = total.iter().map(|&at| baker[at as usize]).collect();
+        }
+
+        Some(total)
+    }
+
+    /// Logistic map keystreams for the forward and backward passes, and the chaining
+    /// value each starts from.
+    fn keystream(&self, len: usize) -> (Vec<u8>, Vec<u8>, u8) {
+        let mut rng = SplitMix(self.key ^ 0x6c6f_6769_7374_6963);
+        // Keep x0 off the map's fixed points and r deep in the chaotic regime.
+        let x0 = 0.1 + 0.8 * (rng.next() >> 11) as f64 / (1u64 << 53) as f64;
+        let r = 3.99 + 0.009 * (rng.next() >> 11) as f64 / (1u64 << 53) as f64;
+        let iv = rng.next() as u8;
+
+        let mut forward = logistic_keystream(x0, r, 2 * len);
+        let backward = forward.split_off(len);
+        (forward, backward, iv)
+    }
+}
+
+/// The generalized Arnold cat map on an n x n grid, `(x, y) -> (x + a y, b x + (ab + 1) y)`
+/// mod n, as a table from each row major index to its destination. Its determinant is 1,
+/// so it is a bijection for any n.
+pub fn cat_map(n: u32, a: u32, b: u32) -> Vec<u32> {
+    let n64 = n as u64;
+    let (a, b) = (a as u64 % n64, b as u64 % n64);
+    let mut table = Vec::with_capacity((n * n) as usize);
+    for y in 0..n64 {
+        for x in 0..n64 {
+            let nx = (x + a * y) % n64;
+            let ny = (b * x + (a * b + 1) % n64 * y) % n64;
+            table.push((ny * n64 + nx) as u32);
+        }
+    }
+    table
+}
+
+/**
+ * The discretized baker's map on an n x n grid. The grid is cut into vertical strips of
+ * the given widths, each of which must divide n, and every strip is stretched into
+ * horizontal bands. None if the widths don't partition n that way.
+ */
+pub fn baker_map(n: u32, widths: &[u32]) -> Option<Vec<u32>> {
+    if widths.iter().sum::<u32>() != n || widths.iter().any(|&w| w == 0 || !n.is_multiple_of(w)) {
+        return None;
+    }
+
+    let mut table = vec![0; (n * n) as usize];
+    let mut start = 0;
+    for &width in widths {
+        let q = n / width;
+        for x in start..start + width {
+            for y in 0..n {
+                let nx = q * (x - start) + y % q;
+                let ny = (y - y % q) / q + start;
+                table[(y * n + x) as usize] = ny * n + nx;
+            }
+        }
+        start += width;
+    }
+
+    Some(table)
+}
+
+/// Bytes from the logistic map `x -> r x (1 - x)`, after a warmup.
+pub fn logistic_keystream(x0: f64, r: f64, len: usize) -> Vec<u8> {
+    let mut x = x0;
+    for _ in 0..LOGISTIC_WARMUP {
+        x = r * x * (1. - x);
+    }
+
+    (0..len)
+        .map(|_| {
+            x = r * x * (1. - x);
+            // The low digits of the mantissa are the ones that look random.
+            ((x * 1e14).floor() as u64 % 256) as u8
+        })
+        .collect()
+}
+
+/// Keyed strip widths for `baker_map`, each a divisor of n.
+fn baker_partition(n: u32, rng: &mut SplitMix) -> Vec<u32> {
+    let divisors: Vec<u32> = (1..n).filter(|&d| n.is_multiple_of(d)).collect();
+
+    let mut widths = vec![];
+    let mut left = n;
+    while left > 0 {
+        let fits: Vec<u32> = divisors.iter().copied().filter(|&d| d <= left).collect();
+        let width = if fits.is_empty() {
+            left
+        } else {
+            fits[(rng.next() % fits.len() as u64) as usize]
+        };
+        widths.push(width);
+        left -= width;
+    }
+    widths
+}
+
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Difference;
+
+    fn gradient(n: u32) -> GrayImage {
+        GrayImage::from_fn(n, n, |x, y| image::Luma([(x * 3 + y * 5) as u8]))
+    }
+
+    fn is_bijection(table: &[u32]) -> bool {
+        let mut seen = vec![false; table.len()];
+        table
+            .iter()
+            .all(|&to| !std::mem::replace(&mut seen[to as usize], true))
+    }
+
+    #[test]
+    fn inverts_with_the_same_key_only() {
+        for n in [1, 37, 64] {
+            let original = gradient(n);
+            let mut image = original.clone();
+
+            assert!(Scrambler::new("key").apply(&mut image));
+            if n > 1 {
+                assert_ne!(image, original);
+            }
+            let mut wrong = image.clone();
+            assert!(Scrambler::new("kez").invert(&mut wrong));
+            if n > 1 {
+                assert_ne!(wrong, original);
+            }
+            assert!(Scrambler::new("key").invert(&mut image));
+            assert_eq!(image, original);
+        }
+
+        let plain = Scrambler::new("key")
+            .with_diffusion(false)
+            .with_cat_rounds(1);
+        let mut image = gradient(16);
+        plain.apply(&mut image);
+        plain.invert(&mut image);
+        assert_eq!(image, gradient(16));
+    }
+
+    #[test]
+    fn one_pixel_changes_every_pixel() {
+        let scrambler = Scrambler::new("key");
+        for (x, y) in [(0, 0), (32, 32), (63, 63)] {
+            let mut plain = gradient(64);
+            let mut tweaked = plain.clone();
+            let pixel = tweaked.get_pixel_mut(x, y);
+            pixel.0[0] = pixel.0[0].wrapping_add(1);
+
+            scrambler.apply(&mut plain);
+            scrambler.apply(&mut tweaked);
+            let difference = Difference::between(&plain, &tweaked).unwrap();
+            assert!(difference.npcr > 99., "{:?}", difference);
+            assert!(difference.uaci > 30., "{:?}", difference);
+        }
+    }
+
+    #[test]
+    fn leaves_other_shapes_untouched() {
+        let mut image = GrayImage::new(4, 8);
+        assert!(!Scrambler::new("key").apply(&mut image));
+        assert!(!Scrambler::new("key").invert(&mut image));
+        assert_eq!(image, GrayImage::new(4, 8));
+    }
+
+    #[test]
+    fn maps_are_permutations() {
+        assert!(is_bijection(&cat_map(37, 5, 11)));
+        assert!(is_bijection(&cat_map(64, 64, 3)));
+        assert!(is_bijection(&baker_map(12, &[6, 3, 2, 1]).unwrap()));
+        assert!(baker_map(12, &[5, 7]).is_none());
+        assert!(baker_map(12, &[6, 3]).is_none());
+
+        let keystream = logistic_keystream(0.3, 3.995, 4096);
+        assert_eq!(keystream, logistic_keystream(0.3, 3.995, 4096));
+        assert!(keystream.iter().filter(|&&k| k == keystream[0]).count() < 64);
+    }
+}
@@ -0,0 +1,242 @@
+use std::io::{self, Write};
+
+use super::Renderer;
+use crate::geometry::{Geometry, Point, PreGeometry};
+use image::codecs::png::PngEncoder;
+use image::ImageEncoder;
+use image::RgbImage;
+
+/// Where a spiral is hidden in a cover image. The decoder needs the same values back.
+#[derive(Clone, Copy, Debug)]
+pub struct StegoLayout {
+    pub dim: u32,
+    /// Bit-plane carrying the grid, 0 is the least significant.
+    pub plane: u8,
+    /// Seeds the keystream cells are whitened with, so the plane stays noise-like
+    /// rather than showing the mostly empty grid.
+    pub seed: u64,
+}
+
+impl StegoLayout {
+    pub fn new(dim: u32) -> StegoLayout {
+        StegoLayout {
+            dim,
+            plane: 0,
+            seed: 0,
+        }
+    }
+
+    pub fn with_plane(mut self, plane: u8) -> StegoLayout {
+        self.plane = plane.min(7);
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> StegoLayout {
+        self.seed = seed;
+        self
+    }
+}
+
+/**
+ * Hides a geometry in one bit-plane of a cover photo instead of drawing it on a black
+ * canvas. Each cell is one pixel of the top left dim x dim block, its bit written to
+ * all three channels so extraction can take a majority. Only survives lossless formats.
+ */
+pub struct Stego {
+    pub buf: RgbImage,
+    layout: StegoLayout,
+}
+
+impl Stego {
+    /// None if the cover is smaller than the grid.
+    pub fn with_cover(cover: RgbImage, layout: StegoLayout) -> Option<Stego> {
+        let (width, height) = cover.dimensions();
+        if width < layout.dim || height < layout.dim {
+            return None;
+        }
+
+        Some(Stego { buf: cover, layout })
+    }
+
+    pub fn layout(&self) -> &StegoLayout {
+        &self.layout
+    }
+
+    pub fn from_geometry(&mut self, geometry: &dyn Geometry<Point>) {
+        let dim = self.layout.dim;
+        let mut lit = vec![false; (dim * dim) as usize];
+        for point in geometry.get_points().iter() {
+            if point.x < dim && point.y < dim && point.z.unwrap_or(0) > 0 {
+                lit[(point.y * dim + point.x) as usize] = true;
+            }
+        }
+
+        let mask = 1u8 << self.layout.plane;
+        let mut keystream = Keystream::new(self.layout.seed);
+        for y in 0..dim {
+            for x in 0..dim {
+                let bit = lit[(y * dim + x) as usize] ^ keystream.next_bit();
+                let pixel = self.buf.get_pixel_mut(x, y);
+                for channel in pixel.0.iter_mut() {
+                    *channel = if bit {
+                        *channel | mask
+                    } else {
+                        *channel & !mask
+                    };
+                }
+            }
+        }
+    }
+
+    /// Read the grid back out of an image rendered by `Stego`, cells lit at 255.
+    pub fn extract(image: &RgbImage, layout: &StegoLayout) -> Option<PreGeometry> {
+        let dim = layout.dim;
+        let (width, height) = image.dimensions();
+        if width < dim || height < dim {
+            return None;
+        }
+
+        let mask = 1u8 << layout.plane.min(7);
+        let mut keystream = Keystream::new(layout.seed);
+        let mut points = Vec::with_capacity((dim * dim) as usize);
+        for y in 0..dim {
+            for x in 0..dim {
+                let votes = image
+                    .get_pixel(x, y)
+                    .0
+                    .iter()
+                    .filter(|&&channel| channel & mask != 0)
+                    .count();
+                let lit = (votes >= 2) ^ keystream.next_bit();
+                points.push(Point {
+                    x,
+                    y,
+                    z: Some(if lit { 255 } else { 0 }),
+                });
+            }
+        }
+
+        Some(((dim, dim), points))
+    }
+}
+
+impl Renderer<Point> for Stego {
+    fn render(&mut self, geometry: &dyn Geometry<Point>) {
+        self.from_geometry(geometry);
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let (width, height) = self.buf.dimensions();
+        PngEncoder::new(writer)
+            .write_image(self.buf.as_raw(), width, height, image::ColorType::Rgb8)
+            .map_err(io::Error::other)
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/png"
+    }
+}
+
+/// xorshift64*, one bit per cell. Not a cipher, it only has to look like noise.
+struct Keystream {
+    state: u64,
+    bits: u64,
+    left: u32,
+}
+
+impl Keystream {
+    fn new(seed: u64) -> Keystream {
+        Keystream {
+            // Zero is a fixed point of xorshift, keep away from it.
+            state: match seed ^ 0x9e37_79b9_7f4a_7c15 {
+                0 => 1,
+                state => state,
+            },
+            bits: 0,
+            left: 0,
+        }
+    }
+
+    fn next_bit(&mut self) -> bool {
+        if self.left == 0 {
+            self.state ^= self.state >> 12;
+            self.state ^= self.state << 25;
+            self.state ^= self.state >> 27;
+            self.bits = self.state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+            self.left = 64;
+        }
+
+        let bit = self.bits & 1 == 1;
+        self.bits >>= 1;
+        self.left -= 1;
+        bit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+    use crate::geometry::SpiralGeometry;
+
+    /// A cover with some texture to it, so every bit-plane holds a mix.
+    fn cover(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 3 + y) as u8, (x ^ y) as u8, (x * y / 7) as u8])
+        })
+    }
+
+    fn lit(pregeometry: &PreGeometry) -> Vec<bool> {
+        pregeometry.1.iter().map(|p| p.z.unwrap_or(0) > 0).collect()
+    }
+
+    #[test]
+    fn extracts_what_was_embedded() {
+        let mut geometry = SpiralGeometry::new(64);
+        geometry.translate(hex::encode(b"under cover"));
+        let mut drawn = vec![false; 64 * 64];
+        for point in geometry.get_points().iter() {
+            if point.x < 64 && point.y < 64 && point.z.unwrap_or(0) > 0 {
+                drawn[(point.y * 64 + point.x) as usize] = true;
+            }
+        }
+
+        for plane in [0, 3, 7] {
+            let layout = StegoLayout::new(64).with_plane(plane).with_seed(42);
+            let original = cover(80, 70);
+            let mut stego = Stego::with_cover(original.clone(), layout).unwrap();
+            stego.from_geometry(&geometry);
+
+            let extracted = Stego::extract(&stego.buf, &layout).unwrap();
+            assert_eq!(extracted.0, (64, 64));
+            assert_eq!(lit(&extracted), drawn, "plane {}", plane);
+
+            // Only the one plane of the grid's block changes.
+            let mask = !(1u8 << plane);
+            for (x, y, pixel) in stego.buf.enumerate_pixels() {
+                let before = original.get_pixel(x, y);
+                for (after, before) in pixel.0.iter().zip(before.0) {
+                    if x < 64 && y < 64 {
+                        assert_eq!(after & mask, before & mask);
+                    } else {
+                        assert_eq!(*after, before);
+                    }
+                }
+            }
+
+            // Another seed reads noise.
+            let wrong = Stego::extract(&stego.buf, &layout.with_seed(43)).unwrap();
+            assert_ne!(lit(&wrong), drawn);
+        }
+    }
+
+    #[test]
+    fn refuses_undersized_covers() {
+        let layout = StegoLayout::new(64);
+        assert!(Stego::with_cover(cover(63, 100), layout).is_none());
+        assert!(Stego::with_cover(cover(100, 63), layout).is_none());
+        assert!(Stego::with_cover(cover(64, 64), layout).is_some());
+        assert!(Stego::extract(&cover(64, 32), &layout).is_none());
+    }
+}
@@ -1,9 +1,15 @@
+use std::io::{self, Write};
+
+use super::Renderer;
 use crate::geometry::{Geometry, LossyPoint};
+use crate::metadata::EncodingParams;
+use draw::render::Renderer as _;
 use draw::*;
 
 pub struct Svg {
     pad: u32,
     canvas: Canvas,
+    metadata: Option<EncodingParams>,
 }
 
 impl Svg {
@@ -11,7 +17,17 @@ impl Svg {
         let imsize = pad * 2 + dim;
         let canvas = Canvas::new(imsize, imsize);
 
-        Svg { pad, canvas }
+        Svg {
+            pad,
+            canvas,
+            metadata: None,
+        }
+    }
+
+    /// Record how the image was made in a `<metadata>` element, see `EncodingParams`.
+    pub fn with_metadata(mut self, metadata: EncodingParams) -> Svg {
+        self.metadata = Some(metadata);
+        self
     }
 
     pub fn from_geometry(&mut self, geometry: &dyn Geometry<LossyPoint>) {
@@ -21,7 +37,7 @@ impl Svg {
             .get_points()
             .iter()
             .map(|point: &LossyPoint| {
-                let luminosity = point.z.unwrap_or_else(|| 0.) as u8;
+                let luminosity = point.z.unwrap_or(0.) as u8;
 
                 let x = self.pad as f32 + point.x;
                 let y = self.pad as f32 + point.y;
@@ -62,7 +78,65 @@ impl Svg {
     }
 
     pub fn export(&self, path: &str) {
-        render::save(&self.canvas, path, SvgRenderer::new())
+        <Svg as Renderer<LossyPoint>>::write_to_path(self, path)
             .expect("Failed to save your geometry to disk.")
     }
 }
+
+impl Renderer<LossyPoint> for Svg {
+    fn render(&mut self, geometry: &dyn Geometry<LossyPoint>) {
+        self.from_geometry(geometry);
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let svg = SvgRenderer::new().render(&self.canvas);
+        let Some(metadata) = &self.metadata else {
+            return writer.write_all(&svg);
+        };
+
+        // Metadata goes first inside the root element.
+        let root = svg.windows(4).position(|w| w == b"<svg");
+        let open_end = root.and_then(|root| {
+            svg[root..]
+                .iter()
+                .position(|&b| b == b'>')
+                .map(|end| root + end + 1)
+        });
+        match open_end {
+            Some(at) => {
+                writer.write_all(&svg[..at])?;
+                writer.write_all(metadata.to_svg().as_bytes())?;
+                writer.write_all(&svg[at..])
+            }
+            None => writer.write_all(&svg),
+        }
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/svg+xml"
+    }
+}
+
+impl Renderer<crate::geometry::Point> for Svg {
+    fn render(&mut self, geometry: &dyn Geometry<crate::geometry::Point>) {
+        Svg::background(&mut self.canvas);
+
+        for point in geometry.get_points().iter() {
+            let luminosity = point.z.unwrap_or(255).min(255) as u8;
+            let x = (self.pad + point.x) as f32;
+            let y = (self.pad + point.y) as f32;
+
+            self.canvas
+                .display_list
+                .add(Svg::point_at(x, y, luminosity));
+        }
+    }
+
+    fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        <Svg as Renderer<LossyPoint>>::write_to(self, writer)
+    }
+
+    fn mime_type(&self) -> &'static str {
+        "image/svg+xml"
+    }
+}
@@ -0,0 +1,314 @@
+use image::{GrayImage, Luma};
+
+/// Share pixels: ink blocks light when shares are stacked, clear lets it through.
+const INK: u8 = 0;
+const CLEAR: u8 = 255;
+
+/// OR shares need 2^(n-1) subpixels per cell, so keep n small enough to print.
+pub const MAX_OR_SHARES: u8 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VisualMode {
+    /// Shares are printed on transparencies and stacked. Every cell becomes a block of
+    /// subpixels and the stack only shows the image at reduced contrast.
+    Or,
+    /// Shares are combined digitally by xor, cell for cell and without loss.
+    Xor,
+}
+
+impl VisualMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "or" => Some(VisualMode::Or),
+            "xor" => Some(VisualMode::Xor),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Visual cryptography over binary images, such as a rendered `Bitmap`: splits an image
+ * into `shares` noise images which all have to be combined to reveal it. Any fewer
+ * shares have the same distribution whatever the image, so they say nothing about it.
+ *
+ * `Xor` shares are uniformly random bits. `Or` is the n out of n construction of Naor
+ * and Shamir: a cell expands to 2^(n-1) subpixels and every share inks exactly half of
+ * them, in a random order. Stacked, lit cells keep one clear subpixel while dark cells
+ * are fully inked.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct VisualCrypto {
+    mode: VisualMode,
+    shares: u8,
+}
+
+impl VisualCrypto {
+    /// None for fewer than two shares, or more than `MAX_OR_SHARES` in `Or` mode.
+    pub fn new(mode: VisualMode, shares: u8) -> Option<VisualCrypto> {
+        if shares < 2 || (mode == VisualMode::Or && shares > MAX_OR_SHARES) {
+            return None;
+        }
+
+        Some(VisualCrypto { mode, shares })
+    }
+
+    pub fn mode(&self) -> VisualMode {
+        self.mode
+    }
+
+    pub fn shares(&self) -> u8 {
+        self.shares
+    }
+
+    /// Width and height of the subpixel block every cell becomes in a share.
+    pub fn block(&self) -> (u32, u32) {
+        match self.mode {
+            VisualMode::Xor => (1, 1),
+            VisualMode::Or => {
+                let bits = self.shares as u32 - 1;
+                (1 << bits.div_ceil(2), 1 << (bits / 2))
+            }
+        }
+    }
+
+    /// Split a binary image, pixels above 127 lit, into share images.
+    pub fn split(&self, image: &GrayImage) -> Result<Vec<GrayImage>, getrandom::Error> {
+        match self.mode {
+            VisualMode::Xor => self.split_xor(image),
+            VisualMode::Or => self.split_or(image),
+        }
+    }
+
+    /// What stacking the shares shows: the darkest of each pixel for `Or`, their xor
+    /// for `Xor`. None if the shares don't match in number or size.
+    pub fn overlay(&self, shares: &[GrayImage]) -> Option<GrayImage> {
+        let (width, height) = self.check(shares)?;
+        Some(GrayImage::from_fn(width, height, |x, y| {
+            let pixels = shares.iter().map(|share| share.get_pixel(x, y).0[0]);
+            Luma([match self.mode {
+                VisualMode::Or => pixels.min().unwrap_or(INK),
+                VisualMode::Xor => lit_to_luma(pixels.fold(false, |acc, p| acc ^ (p > 127))),
+            }])
+        }))
+    }
+
+    /// Recover the original image at its own resolution, lit cells at 255. None if the
+    /// shares don't match in number or size.
+    pub fn reveal(&self, shares: &[GrayImage]) -> Option<GrayImage> {
+        let overlay = self.overlay(shares)?;
+        let (bw, bh) = self.block();
+        let (width, height) = (overlay.width() / bw, overlay.height() / bh);
+
+        Some(GrayImage::from_fn(width, height, |x, y| {
+            // Only a lit cell leaves a subpixel clear through every share.
+            let lit = (0..bw * bh)
+                .any(|s| overlay.get_pixel(x * bw + s % bw, y * bh + s / bw).0[0] > 127);
+            Luma([lit_to_luma(lit)])
+        }))
+    }
+
+    fn check(&self, shares: &[GrayImage]) -> Option<(u32, u32)> {
+        let (width, height) = shares.first()?.dimensions();
+        let (bw, bh) = self.block();
+        if shares.len() != self.shares as usize
+            || shares
+                .iter()
+                .any(|share| share.dimensions() != (width, height))
+            || width % bw != 0
+            || height % bh != 0
+        {
+            return None;
+        }
+
+        Some((width, height))
+    }
+
+    fn split_xor(&self, image: &GrayImage) -> Result<Vec<GrayImage>, getrandom::Error> {
+        let (width, height) = image.dimensions();
+        let mut random = Random::new();
+        let mut shares = vec![];
+        let mut parity: Vec<bool> = image.pixels().map(|p| p.0[0] > 127).collect();
+
+        // Every share but the last is pure noise, the last makes the xor come out right.
+        for _ in 1..self.shares {
+            let mut share = GrayImage::new(width, height);
+            for (pixel, parity) in share.pixels_mut().zip(parity.iter_mut()) {
+                let bit = random.bit()?;
+                *parity ^= bit;
+                pixel.0[0] = lit_to_luma(bit);
+            }
+            shares.push(share);
+        }
+
+        let last = GrayImage::from_fn(width, height, |x, y| {
+            Luma([lit_to_luma(parity[(y * width + x) as usize])])
+        });
+        shares.push(last);
+
+        Ok(shares)
+    }
+
+    fn split_or(&self, image: &GrayImage) -> Result<Vec<GrayImage>, getrandom::Error> {
+        let n = self.shares as u32;
+        let (bw, bh) = self.block();
+        let (width, height) = image.dimensions();
+
+        // Columns of the basis matrices as sets of shares inking that subpixel: the even
+        // subsets of the shares for lit cells, the odd ones for dark.
+        let (even, odd): (Vec<u32>, Vec<u32>) =
+            (0..1u32 << n).partition(|set| set.count_ones() % 2 == 0);
+
+        let mut random = Random::new();
+        let mut shares = vec![GrayImage::new(width * bw, height * bh); n as usize];
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let mut columns = if pixel.0[0] > 127 {
+                even.clone()
+            } else {
+                odd.clone()
+            };
+            random.shuffle(&mut columns)?;
+
+            for (s, set) in columns.into_iter().enumerate() {
+                let (sx, sy) = (x * bw + s as u32 % bw, y * bh + s as u32 / bw);
+                for (i, share) in shares.iter_mut().enumerate() {
+                    let inked = set & (1 << i) != 0;
+                    share.put_pixel(sx, sy, Luma([if inked { INK } else { CLEAR }]));
+                }
+            }
+        }
+
+        Ok(shares)
+    }
+}
+
+fn lit_to_luma(lit: bool) -> u8 {
+    if lit {
+        CLEAR
+    } else {
+        INK
+    }
+}
+
+/// Buffered bytes from the system random source. Shares are only as unreadable as these
+/// are unpredictable, so nothing seeded.
+struct Random {
+    buf: Vec<u8>,
+    at: usize,
+    bits: u8,
+    left: u8,
+}
+
+impl Random {
+    fn new() -> Random {
+        Random {
+            buf: vec![],
+            at: 0,
+            bits: 0,
+            left: 0,
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, getrandom::Error> {
+        if self.at == self.buf.len() {
+            self.buf = vec![0; 4096];
+            getrandom::getrandom(&mut self.buf)?;
+            self.at = 0;
+        }
+
+        self.at += 1;
+        Ok(self.buf[self.at - 1])
+    }
+
+    fn bit(&mut self) -> Result<bool, getrandom::Error> {
+        if self.left == 0 {
+            self.bits = self.byte()?;
+            self.left = 8;
+        }
+
+        let bit = self.bits & 1 == 1;
+        self.bits >>= 1;
+        self.left -= 1;
+        Ok(bit)
+    }
+
+    /// Uniform in 0..n for n up to 256, rejecting bytes that would bias it.
+    fn below(&mut self, n: usize) -> Result<usize, getrandom::Error> {
+        let limit = 256 - 256 % n;
+        loop {
+            let byte = self.byte()? as usize;
+            if byte < limit {
+                return Ok(byte % n);
+            }
+        }
+    }
+
+    /// Fisher-Yates, for up to 256 items.
+    fn shuffle<T>(&mut self, items: &mut [T]) -> Result<(), getrandom::Error> {
+        for i in (1..items.len()).rev() {
+            let j = self.below(i + 1)?;
+            items.swap(i, j);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(width: u32, height: u32) -> GrayImage {
+        GrayImage::from_fn(width, height, |x, y| {
+            Luma([lit_to_luma((x * 7 + y * 3) % 5 < 2)])
+        })
+    }
+
+    fn ink_ratio(share: &GrayImage) -> f64 {
+        let ink = share.pixels().filter(|p| p.0[0] == INK).count();
+        ink as f64 / share.pixels().len() as f64
+    }
+
+    #[test]
+    fn reveals_what_was_split() {
+        let secret = pattern(48, 40);
+        for mode in [VisualMode::Or, VisualMode::Xor] {
+            for n in 2..=4 {
+                let crypto = VisualCrypto::new(mode, n).unwrap();
+                let shares = crypto.split(&secret).unwrap();
+                assert_eq!(shares.len(), n as usize);
+                let (bw, bh) = crypto.block();
+                assert_eq!(shares[0].dimensions(), (48 * bw, 40 * bh));
+                assert_eq!(crypto.reveal(&shares).unwrap(), secret, "{:?} {}", mode, n);
+
+                // Short a share, or with one of another size, there's nothing to see.
+                assert!(crypto.reveal(&shares[1..]).is_none());
+                let mut odd = shares.clone();
+                odd[0] = GrayImage::new(8, 8);
+                assert!(crypto.reveal(&odd).is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn shares_are_half_ink_whatever_the_secret() {
+        let dark = GrayImage::new(64, 64);
+        let light = GrayImage::from_pixel(64, 64, Luma([CLEAR]));
+        for mode in [VisualMode::Or, VisualMode::Xor] {
+            let crypto = VisualCrypto::new(mode, 3).unwrap();
+            for secret in [&dark, &light, &pattern(64, 64)] {
+                for share in crypto.split(secret).unwrap() {
+                    let ratio = ink_ratio(&share);
+                    assert!((ratio - 0.5).abs() < 0.05, "{:?} {}", mode, ratio);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_unusable_share_counts() {
+        assert!(VisualCrypto::new(VisualMode::Xor, 1).is_none());
+        assert!(VisualCrypto::new(VisualMode::Or, MAX_OR_SHARES + 1).is_none());
+        assert!(VisualCrypto::new(VisualMode::Xor, MAX_OR_SHARES + 1).is_some());
+        assert_eq!(VisualMode::from_name("XOR"), Some(VisualMode::Xor));
+        assert_eq!(VisualMode::from_name("and"), None);
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt;
+
+use crate::geometry::SpiralGeometry;
+
+/// Leading bytes of every share, ahead of its set id and coordinates.
+const SHARE_MAGIC: &[u8; 4] = b"\0TSS";
+const SHARE_VERSION: u8 = 1;
+/// Magic, version, set id, threshold, count, index, share checksum.
+const HEADER_LEN: usize = 4 + 1 + 4 + 1 + 1 + 1 + 4;
+
+#[derive(Debug)]
+pub enum ShareError {
+    /// Thresholds run from 1 to the number of shares, which can't exceed 255.
+    Parameters {
+        threshold: u8,
+        count: u8,
+    },
+    /// The system random source failed.
+    Random(getrandom::Error),
+    /// Shares from more than one split were mixed together.
+    Inconsistent,
+    NotEnoughShares {
+        have: usize,
+        need: usize,
+    },
+    TooLarge(usize),
+}
+
+impl fmt::Display for ShareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShareError::Parameters { threshold, count } => write!(
+                f,
+                "can't require {} of {} shares, the threshold must be between 1 and the number of shares",
+                threshold, count
+            ),
+            ShareError::Random(e) => write!(f, "no randomness for the shares: {}", e),
+            ShareError::Inconsistent => write!(f, "shares are from different secrets"),
+            ShareError::NotEnoughShares { have, need } => {
+                write!(f, "{} of {} required shares present", have, need)
+            }
+            ShareError::TooLarge(n) => write!(f, "{} bytes is too large for one share", n),
+        }
+    }
+}
+
+impl std::error::Error for ShareError {}
+
+/**
+ * One share of a secret split with Shamir's scheme over GF(256). Every byte of the
+ * secret is the constant term of its own random polynomial of degree `threshold - 1`,
+ * and `data` holds those polynomials evaluated at `index`. Any `threshold` shares of
+ * the same set recover the secret, fewer are uniformly random and say nothing about it
+ * but its length.
+ */
+#[derive(Clone, Debug)]
+pub struct Share {
+    /// Random id shared by every share of one split, so sets can't be mixed up.
+    pub set: u32,
+    pub threshold: u8,
+    pub count: u8,
+    /// The x coordinate, numbered from 1.
+    pub index: u8,
+    pub data: Vec<u8>,
+}
+
+impl Share {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = SHARE_MAGIC.to_vec();
+        out.push(SHARE_VERSION);
+        out.extend(self.set.to_be_bytes());
+        out.extend([self.threshold, self.count, self.index]);
+        out.extend(crc32fast::hash(&self.data).to_be_bytes());
+        out.extend(&self.data);
+        out
+    }
+
+    /// Parse a decoded share. None if it isn't one, or its checksum doesn't match.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Share> {
+        let rest = bytes.strip_prefix(SHARE_MAGIC)?;
+        if rest.len() < HEADER_LEN - SHARE_MAGIC.len() || rest[0] != SHARE_VERSION {
+            return None;
+        }
+
+        let share = Share {
+            set: u32::from_be_bytes(rest[1..5].try_into().unwrap()),
+            threshold: rest[5],
+            count: rest[6],
+            index: rest[7],
+            data: rest[12..].to_vec(),
+        };
+        let checksum = u32::from_be_bytes(rest[8..12].try_into().unwrap());
+        if crc32fast::hash(&share.data) != checksum
+            || share.index == 0
+            || share.threshold == 0
+            || share.threshold > share.count
+        {
+            return None;
+        }
+
+        Some(share)
+    }
+}
+
+/// Secret bytes that fit in one share on a `dim` spiral.
+pub fn share_capacity(dim: u32) -> usize {
+    SpiralGeometry::capacity(dim).saturating_sub(HEADER_LEN)
+}
+
+/// Split a secret, typically the output of `Pipeline::encode`, into `count` shares of
+/// which any `threshold` recover it.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>, ShareError> {
+    if threshold == 0 || threshold > count {
+        return Err(ShareError::Parameters { threshold, count });
+    }
+
+    let mut set = [0u8; 4];
+    getrandom::getrandom(&mut set).map_err(ShareError::Random)?;
+    let set = u32::from_be_bytes(set);
+
+    // Coefficients for x^1 through x^(threshold - 1) of every byte's polynomial.
+    let degree = threshold as usize - 1;
+    let mut coefficients = vec![0u8; secret.len() * degree];
+    getrandom::getrandom(&mut coefficients).map_err(ShareError::Random)?;
+
+    Ok((1..=count)
+        .map(|x| {
+            let data = secret
+                .iter()
+                .enumerate()
+                .map(|(ix, &byte)| {
+                    let higher = &coefficients[ix * degree..(ix + 1) * degree];
+                    // Horner's rule from the highest coefficient down.
+                    higher
+                        .iter()
+                        .rev()
+                        .chain([&byte])
+                        .fold(0, |acc, &c| gf_mul(acc, x) ^ c)
+                })
+                .collect();
+
+            Share {
+                set,
+                threshold,
+                count,
+                index: x,
+                data,
+            }
+        })
+        .collect())
+}
+
+/// Recover the secret from shares of one split, in any order and with duplicates.
+pub fn combine(shares: &[Share]) -> Result<Vec<u8>, ShareError> {
+    let first = shares
+        .first()
+        .ok_or(ShareError::NotEnoughShares { have: 0, need: 1 })?;
+
+    let mut distinct: Vec<&Share> = vec![];
+    for share in shares {
+        if (share.set, share.threshold, share.count, share.data.len())
+            != (first.set, first.threshold, first.count, first.data.len())
+        {
+            return Err(ShareError::Inconsistent);
+        }
+        if !distinct.iter().any(|s| s.index == share.index) {
+            distinct.push(share);
+        }
+    }
+
+    let need = first.threshold as usize;
+    if distinct.len() < need {
+        return Err(ShareError::NotEnoughShares {
+            have: distinct.len(),
+            need,
+        });
+    }
+    let distinct = &distinct[..need];
+
+    // Lagrange basis polynomials evaluated at 0. Subtraction is xor in GF(256).
+    let basis: Vec<u8> = distinct
+        .iter()
+        .map(|share| {
+            distinct
+                .iter()
+                .filter(|other| other.index != share.index)
+                .fold(1, |acc, other| {
+                    gf_mul(acc, gf_mul(other.index, gf_inv(other.index ^ share.index)))
+                })
+        })
+        .collect();
+
+    Ok((0..first.data.len())
+        .map(|ix| {
+            distinct
+                .iter()
+                .zip(&basis)
+                .fold(0, |acc, (share, &l)| acc ^ gf_mul(share.data[ix], l))
+        })
+        .collect())
+}
+
+/// Multiplication in GF(2^8) modulo the AES polynomial x^8 + x^4 + x^3 + x + 1.
+fn gf_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        let carry = a & 0x80 != 0;
+        a <<= 1;
+        if carry {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    product
+}
+
+/// Multiplicative inverse, a^254 since the group has order 255. Zero maps to zero.
+fn gf_inv(a: u8) -> u8 {
+    let mut result = 1;
+    let mut base = a;
+    let mut exp = 254u8;
+    while exp != 0 {
+        if exp & 1 != 0 {
+            result = gf_mul(result, base);
+        }
+        base = gf_mul(base, base);
+        exp >>= 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn any_threshold_of_shares_recovers_the_secret() {
+        let secret = b"split three ways, any two will do";
+        let shares = split(secret, 2, 3).unwrap();
+        assert_eq!(shares.len(), 3);
+
+        for pair in [[0, 1], [1, 2], [2, 0]] {
+            let chosen: Vec<Share> = pair
+                .iter()
+                .map(|&ix| Share::from_bytes(&shares[ix].to_bytes()).unwrap())
+                .collect();
+            assert_eq!(combine(&chosen).unwrap(), secret);
+        }
+    }
+
+    #[test]
+    fn fewer_shares_or_mixed_sets_are_refused() {
+        let shares = split(b"three of five", 3, 5).unwrap();
+        let duplicated = [shares[0].clone(), shares[0].clone(), shares[1].clone()];
+        assert!(matches!(
+            combine(&duplicated),
+            Err(ShareError::NotEnoughShares { have: 2, need: 3 })
+        ));
+
+        let other = split(b"three of five", 3, 5).unwrap();
+        let mixed = [shares[0].clone(), shares[1].clone(), other[2].clone()];
+        assert!(matches!(combine(&mixed), Err(ShareError::Inconsistent)));
+
+        assert!(split(b"", 0, 2).is_err());
+        assert!(split(b"", 3, 2).is_err());
+    }
+
+    #[test]
+    fn corrupted_shares_do_not_parse() {
+        let mut bytes = split(b"checked", 1, 1).unwrap()[0].to_bytes();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        assert!(Share::from_bytes(&bytes).is_none());
+        assert!(Share::from_bytes(&bytes[..HEADER_LEN - 1]).is_none());
+    }
+
+    #[test]
+    fn field_inverses_multiply_to_one() {
+        for a in 1..=255u8 {
+            assert_eq!(gf_mul(a, gf_inv(a)), 1);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use std::fmt;
+
+use ed25519_dalek::{Signature, Signer};
+
+pub use ed25519_dalek::{SigningKey, VerifyingKey};
+
+/// Leading bytes of a signed payload, ahead of the key id and signature.
+const SIGNED_MAGIC: &[u8; 4] = b"\0TSG";
+const SIGNED_VERSION: u8 = 1;
+/// Magic, version, key id, signature.
+const HEADER_LEN: usize = 4 + 1 + 8 + 64;
+/// Domain separation, so a signature over a payload can't be passed off as one over
+/// anything else the key signs.
+const CONTEXT: &[u8] = b"tim signed payload v1\0";
+
+#[derive(Debug)]
+pub enum SigningError {
+    /// A key file or string isn't a hex encoded 32 byte key.
+    Key(String),
+    /// The system random source failed while generating a key.
+    Random(getrandom::Error),
+    /// The payload carries no signature.
+    Unsigned,
+    /// Signed by a different key, by its id.
+    WrongKey([u8; 8]),
+    /// The payload or its encoding parameters were changed after signing.
+    Invalid,
+}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigningError::Key(e) => write!(f, "bad key: {}", e),
+            SigningError::Random(e) => write!(f, "no randomness for a key: {}", e),
+            SigningError::Unsigned => write!(f, "payload is not signed"),
+            SigningError::WrongKey(id) => {
+                write!(f, "payload is signed by another key ({})", hex::encode(id))
+            }
+            SigningError::Invalid => write!(f, "signature does not match, the image was altered"),
+        }
+    }
+}
+
+impl std::error::Error for SigningError {}
+
+/// The encoding parameters a signature covers along with the payload. Decoders rebuild
+/// it from what they were asked to decode, so an image re-encoded differently fails.
+pub fn encoding_params(geometry: &str, dim: u32) -> String {
+    format!("{}/{}", geometry, dim)
+}
+
+pub fn generate_key() -> Result<SigningKey, SigningError> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(SigningError::Random)?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// Parse a secret key as written by `tim keygen`: the 32 byte seed in hex.
+pub fn signing_key_from_hex(key: &str) -> Result<SigningKey, SigningError> {
+    Ok(SigningKey::from_bytes(&key_bytes(key)?))
+}
+
+/// Parse a public key: 32 bytes in hex.
+pub fn verifying_key_from_hex(key: &str) -> Result<VerifyingKey, SigningError> {
+    VerifyingKey::from_bytes(&key_bytes(key)?).map_err(|e| SigningError::Key(e.to_string()))
+}
+
+/// First bytes of a public key, enough to tell which key signed a payload.
+pub fn key_id(key: &VerifyingKey) -> [u8; 8] {
+    key.as_bytes()[..8].try_into().unwrap()
+}
+
+fn key_bytes(key: &str) -> Result<[u8; 32], SigningError> {
+    let bytes = hex::decode(key.trim()).map_err(|e| SigningError::Key(e.to_string()))?;
+    bytes
+        .try_into()
+        .map_err(|bytes: Vec<u8>| SigningError::Key(format!("{} bytes, expected 32", bytes.len())))
+}
+
+/// Wrap a payload, typically the output of `Pipeline::encode`, with an Ed25519
+/// signature over it and `params`.
+pub fn sign(payload: &[u8], params: &str, key: &SigningKey) -> Vec<u8> {
+    let signature = key.sign(&message(payload, params));
+
+    let mut out = SIGNED_MAGIC.to_vec();
+    out.push(SIGNED_VERSION);
+    out.extend(key_id(&key.verifying_key()));
+    out.extend(signature.to_bytes());
+    out.extend(payload);
+    out
+}
+
+/// A decoded payload that carries a signature, not yet checked.
+#[derive(Clone, Debug)]
+pub struct Signed {
+    pub key_id: [u8; 8],
+    pub signature: Signature,
+    pub payload: Vec<u8>,
+}
+
+impl Signed {
+    /// None if the bytes aren't a signed payload.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Signed> {
+        let rest = bytes.strip_prefix(SIGNED_MAGIC)?;
+        if rest.len() < HEADER_LEN - SIGNED_MAGIC.len() || rest[0] != SIGNED_VERSION {
+            return None;
+        }
+
+        Some(Signed {
+            key_id: rest[1..9].try_into().unwrap(),
+            signature: Signature::from_bytes(rest[9..73].try_into().unwrap()),
+            payload: rest[73..].to_vec(),
+        })
+    }
+
+    pub fn verify(&self, params: &str, key: &VerifyingKey) -> Result<(), SigningError> {
+        if self.key_id != key_id(key) {
+            return Err(SigningError::WrongKey(self.key_id));
+        }
+
+        key.verify_strict(&message(&self.payload, params), &self.signature)
+            .map_err(|_| SigningError::Invalid)
+    }
+}
+
+/**
+ * Check a decoded payload against `key` and return what was signed. Fails for unsigned
+ * payloads too, a verifier that accepted those would be trivial to get around.
+ */
+pub fn verify(bytes: &[u8], params: &str, key: &VerifyingKey) -> Result<Vec<u8>, SigningError> {
+    let signed = Signed::from_bytes(bytes).ok_or(SigningError::Unsigned)?;
+    signed.verify(params, key)?;
+    Ok(signed.payload)
+}
+
+fn message(payload: &[u8], params: &str) -> Vec<u8> {
+    let mut message = CONTEXT.to_vec();
+    message.extend(params.as_bytes());
+    message.push(0);
+    message.extend(payload);
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_hex_keys() {
+        let key = generate_key().unwrap();
+        let secret = signing_key_from_hex(&hex::encode(key.to_bytes())).unwrap();
+        let public = verifying_key_from_hex(&hex::encode(key.verifying_key().as_bytes())).unwrap();
+        let params = encoding_params("spiral", 256);
+
+        let signed = sign(b"signed payload", &params, &secret);
+        assert_eq!(
+            verify(&signed, &params, &public).unwrap(),
+            b"signed payload"
+        );
+
+        assert!(signing_key_from_hex("abcd").is_err());
+        assert!(verifying_key_from_hex("not hex").is_err());
+    }
+
+    #[test]
+    fn rejects_tampering_other_keys_and_unsigned_payloads() {
+        let key = generate_key().unwrap();
+        let public = key.verifying_key();
+        let params = encoding_params("spiral", 256);
+        let signed = sign(b"signed payload", &params, &key);
+
+        let mut tampered = signed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(matches!(
+            verify(&tampered, &params, &public),
+            Err(SigningError::Invalid)
+        ));
+
+        let elsewhere = encoding_params("spiral", 512);
+        assert!(matches!(
+            verify(&signed, &elsewhere, &public),
+            Err(SigningError::Invalid)
+        ));
+
+        let other = generate_key().unwrap().verifying_key();
+        assert!(matches!(
+            verify(&signed, &params, &other),
+            Err(SigningError::WrongKey(id)) if id == key_id(&public)
+        ));
+
+        assert!(matches!(
+            verify(b"signed payload", &params, &public),
+            Err(SigningError::Unsigned)
+        ));
+        assert!(Signed::from_bytes(&signed[..HEADER_LEN - 1]).is_none());
+    }
+}
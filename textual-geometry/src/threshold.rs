@@ -0,0 +1,243 @@
+use crate::geometry::PreGeometry;
+
+/// Windows whose darkest and brightest cells differ by less than this are treated as
+/// flat, and fall back to the global level instead of splitting noise.
+const MIN_LOCAL_CONTRAST: u8 = 48;
+
+pub const DEFAULT_LOCAL_RADIUS: u32 = 8;
+
+/// How sampled cell luma is split into lit and dark. A cell is lit when its luma is
+/// strictly above the level chosen for it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Threshold {
+    /// Whatever suits the sampler: any non-zero luma for exact images, mid grey for
+    /// resampled ones and the contrast of the finder patterns for photos.
+    #[default]
+    Auto,
+    Fixed(u8),
+    /// One global level, chosen by Otsu's method over every sampled cell.
+    Otsu,
+    /// A level per cell, halfway between the darkest and brightest cell within `radius`
+    /// cells of it. Copes with uneven lighting that defeats any single level.
+    Local {
+        radius: u32,
+    },
+}
+
+impl Threshold {
+    /// Parse `auto`, `otsu`, `local`, `local:R` or a fixed level `0`..=`255`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.split_once(':') {
+            Some(("local", radius)) => Some(Threshold::Local {
+                radius: radius.parse().ok()?,
+            }),
+            Some(_) => None,
+            None => match name {
+                "auto" => Some(Threshold::Auto),
+                "otsu" => Some(Threshold::Otsu),
+                "local" => Some(Threshold::Local {
+                    radius: DEFAULT_LOCAL_RADIUS,
+                }),
+                level => level.parse().ok().map(Threshold::Fixed),
+            },
+        }
+    }
+
+    /// The level for every cell of `pregeometry`, in the same order as its points.
+    /// `auto` is the sampler's own level, used for `Auto`.
+    pub fn levels(&self, pregeometry: &PreGeometry, auto: u8) -> Vec<u8> {
+        let ((width, height), points) = pregeometry;
+        let luma: Vec<u8> = points.iter().map(|p| clamp_luma(p.z)).collect();
+
+        match *self {
+            Threshold::Auto => vec![auto; luma.len()],
+            Threshold::Fixed(level) => vec![level; luma.len()],
+            Threshold::Otsu => vec![otsu(luma.iter().copied()); luma.len()],
+            Threshold::Local { radius } => local(&luma, *width, *height, radius),
+        }
+    }
+}
+
+pub(crate) fn clamp_luma(z: Option<u32>) -> u8 {
+    z.unwrap_or(0).min(255) as u8
+}
+
+/// Otsu's threshold: the luma splitting the histogram into the two classes with the
+/// largest between-class variance.
+pub fn otsu(luma: impl Iterator<Item = u8>) -> u8 {
+    let mut histogram = [0u64; 256];
+    for value in luma {
+        histogram[value as usize] += 1;
+    }
+
+    let total: u64 = histogram.iter().sum();
+    let sum_all: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(value, count)| value as f64 * *count as f64)
+        .sum();
+
+    let mut best = (0u8, 0f64);
+    let mut weight_bg = 0u64;
+    let mut sum_bg = 0f64;
+    for (value, count) in histogram.iter().enumerate() {
+        weight_bg += count;
+        if weight_bg == 0 {
+            continue;
+        }
+        let weight_fg = total - weight_bg;
+        if weight_fg == 0 {
+            break;
+        }
+
+        sum_bg += value as f64 * *count as f64;
+        let mean_bg = sum_bg / weight_bg as f64;
+        let mean_fg = (sum_all - sum_bg) / weight_fg as f64;
+        let variance = weight_bg as f64 * weight_fg as f64 * (mean_bg - mean_fg).powi(2);
+        if variance > best.1 {
+            best = (value as u8, variance);
+        }
+    }
+
+    best.0
+}
+
+/// Bernsen style local thresholding over a `width` x `height` raster.
+fn local(luma: &[u8], width: u32, height: u32, radius: u32) -> Vec<u8> {
+    let (width, height) = (width as usize, height as usize);
+    if luma.len() != width * height {
+        return vec![otsu(luma.iter().copied()); luma.len()];
+    }
+
+    let global = otsu(luma.iter().copied());
+    let radius = radius as usize;
+
+    // Min and max along each row first, then down the columns of those.
+    let mut row_min = vec![0u8; luma.len()];
+    let mut row_max = vec![0u8; luma.len()];
+    for y in 0..height {
+        let row = &luma[y * width..(y + 1) * width];
+        for x in 0..width {
+            let window = &row[x.saturating_sub(radius)..(x + radius + 1).min(width)];
+            row_min[y * width + x] = *window.iter().min().unwrap_or(&0);
+            row_max[y * width + x] = *window.iter().max().unwrap_or(&0);
+        }
+    }
+
+    let mut levels = Vec::with_capacity(luma.len());
+    for y in 0..height {
+        for x in 0..width {
+            let rows = y.saturating_sub(radius)..(y + radius + 1).min(height);
+            let lo = rows
+                .clone()
+                .map(|r| row_min[r * width + x])
+                .min()
+                .unwrap_or(0);
+            let hi = rows.map(|r| row_max[r * width + x]).max().unwrap_or(0);
+
+            if hi - lo < MIN_LOCAL_CONTRAST {
+                levels.push(global);
+            } else {
+                levels.push(((lo as u16 + hi as u16) / 2) as u8);
+            }
+        }
+    }
+
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geometry::Point;
+
+    /// A `dim` x `dim` raster of cells, lit where `lit` says, at the luma `shade` gives.
+    fn raster(
+        dim: u32,
+        lit: impl Fn(u32, u32) -> bool,
+        shade: impl Fn(u32, u32, bool) -> u32,
+    ) -> PreGeometry {
+        let mut points = vec![];
+        for y in 0..dim {
+            for x in 0..dim {
+                let z = shade(x, y, lit(x, y));
+                points.push(Point { x, y, z: Some(z) });
+            }
+        }
+        ((dim, dim), points)
+    }
+
+    fn pattern(x: u32, y: u32) -> bool {
+        (x + 2 * y).is_multiple_of(3)
+    }
+
+    /// Cells the levels get wrong.
+    fn misread(pregeometry: &PreGeometry, levels: &[u8]) -> usize {
+        pregeometry
+            .1
+            .iter()
+            .zip(levels)
+            .filter(|(point, &level)| (clamp_luma(point.z) > level) != pattern(point.x, point.y))
+            .count()
+    }
+
+    #[test]
+    fn splits_a_bimodal_image_with_otsu() {
+        // Dark cells around 40 and lit ones around 200, a little noise on both.
+        let noisy = raster(32, pattern, |x, y, lit| {
+            let noise = (x * 7 + y * 13) % 21;
+            if lit {
+                190 + noise
+            } else {
+                30 + noise
+            }
+        });
+        let levels = Threshold::Otsu.levels(&noisy, 0);
+        assert!((50..190).contains(&levels[0]), "{}", levels[0]);
+        assert!(levels.iter().all(|&level| level == levels[0]));
+        assert_eq!(misread(&noisy, &levels), 0);
+
+        assert_eq!(otsu([10, 10, 10, 250, 250].into_iter()), 10);
+        assert_eq!(otsu([99; 16].into_iter()), 0);
+    }
+
+    #[test]
+    fn follows_an_illumination_gradient_locally() {
+        // Lit cells only 80 above dark ones, on lighting that rises 126 across the image,
+        // so dark cells on the right outshine lit ones on the left.
+        let lit_unevenly = raster(64, pattern, |x, _, lit| 2 * x + if lit { 80 } else { 0 });
+
+        let global = Threshold::Otsu.levels(&lit_unevenly, 0);
+        assert!(misread(&lit_unevenly, &global) > 0);
+
+        let local = Threshold::Local { radius: 4 }.levels(&lit_unevenly, 0);
+        assert_eq!(misread(&lit_unevenly, &local), 0);
+
+        // Flat windows fall back to the global level rather than splitting noise.
+        let flat = raster(16, |_, _| false, |x, y, _| 100 + (x + y) % 3);
+        let levels = Threshold::Local { radius: 2 }.levels(&flat, 0);
+        let global = otsu(flat.1.iter().map(|p| clamp_luma(p.z)));
+        assert!(levels.iter().all(|&level| level == global));
+    }
+
+    #[test]
+    fn parses_names() {
+        assert_eq!(Threshold::from_name("auto"), Some(Threshold::Auto));
+        assert_eq!(Threshold::from_name("otsu"), Some(Threshold::Otsu));
+        assert_eq!(
+            Threshold::from_name("local"),
+            Some(Threshold::Local {
+                radius: DEFAULT_LOCAL_RADIUS
+            })
+        );
+        assert_eq!(
+            Threshold::from_name("local:3"),
+            Some(Threshold::Local { radius: 3 })
+        );
+        assert_eq!(Threshold::from_name("0"), Some(Threshold::Fixed(0)));
+        assert_eq!(Threshold::from_name("255"), Some(Threshold::Fixed(255)));
+        for bad in ["256", "-1", "local:", "local:x", "otsu:2", "bernsen", ""] {
+            assert_eq!(Threshold::from_name(bad), None, "{:?}", bad);
+        }
+    }
+}
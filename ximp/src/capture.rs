@@ -0,0 +1,707 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use textual_geometry::decode::{decode_spiral, DecodeLimits};
+use textual_geometry::rendering::Layout;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::http_svc::{spiral_png, CAPACITY};
+
+const INDEX: &str = "index.json";
+/// Connections that have closed, one JSON object per line, appended as they close.
+const CLOSED: &str = "closed.jsonl";
+/// Bytes waiting for the writer at most. Encoding is much slower than proxying, past
+/// this reads go unrecorded rather than piling up in memory, and are noted as gaps.
+const MAX_QUEUED: usize = 16 * 1024 * 1024;
+
+/// Which way bytes went through the proxy.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Client to upstream.
+    Up,
+    Down,
+}
+
+impl Direction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::Up => "up",
+            Direction::Down => "down",
+        }
+    }
+}
+
+/// A capture's `index.json`, the connections still open. Closed ones move to `CLOSED`
+/// so the index stays as small as the number of connections at once.
+#[derive(Default, Deserialize, Serialize)]
+struct Index {
+    connections: Vec<Connection>,
+}
+
+/// Times are seconds since the Unix epoch.
+#[derive(Deserialize, Serialize)]
+struct Connection {
+    id: u64,
+    route: String,
+    client: String,
+    upstream: String,
+    opened: f64,
+    /// Null while open, or if ximp exited first.
+    closed: Option<f64>,
+    /// Bytes from the client.
+    sent: u64,
+    received: u64,
+    pages: Vec<Page>,
+    /// Stretches of either direction that aren't on any page.
+    #[serde(default)]
+    gaps: Vec<Gap>,
+}
+
+/// One spiral PNG, holding `len` bytes of a direction from `offset` on.
+#[derive(Deserialize, Serialize)]
+struct Page {
+    file: String,
+    direction: Direction,
+    offset: u64,
+    len: usize,
+    time: f64,
+}
+
+/// `len` bytes of a direction from `offset` on that were proxied but not recorded,
+/// because the writer fell behind or a page couldn't be written.
+#[derive(Deserialize, Serialize)]
+struct Gap {
+    direction: Direction,
+    offset: u64,
+    len: u64,
+}
+
+enum Event {
+    Open(Connection),
+    /// Bytes of a direction read at an offset into it.
+    Data(u64, Direction, u64, Vec<u8>),
+    /// When a connection closed and how many bytes were read each way, up then down,
+    /// including any that never reached the writer.
+    Closed(u64, f64, [u64; 2]),
+    Finish,
+}
+
+/**
+ * A record of proxied connections kept in a directory. Each direction of a connection
+ * is cut into pages of up to 2048 bytes, written as spiral PNGs once full and every
+ * `interval` otherwise. `index.json` lists the open connections and their pages, and
+ * `closed.jsonl` the ones that have come and gone. The encoding happens on a thread of
+ * its own, off the proxy's path.
+ */
+pub struct Capture {
+    events: Sender<Event>,
+    queued: Arc<AtomicUsize>,
+    next: AtomicU64,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Capture {
+    /// Start a capture in `dir`, which mustn't hold one already.
+    pub fn start(dir: &Path, interval: Duration) -> io::Result<Capture> {
+        std::fs::create_dir_all(dir)?;
+        if dir.join(INDEX).exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "already holds a capture",
+            ));
+        }
+
+        let (events, received) = mpsc::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let writer = Writer {
+            dir: dir.to_path_buf(),
+            open: HashMap::new(),
+            queued: queued.clone(),
+        };
+        writer.write_index();
+        let writer = std::thread::spawn(move || writer.run(received, interval));
+
+        Ok(Capture {
+            events,
+            queued,
+            next: AtomicU64::new(1),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Start recording a connection, until the returned recording is dropped.
+    pub fn open(&self, route: &str, client: &str, upstream: &str) -> Recording {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let events = self.events.clone();
+        let _ = events.send(Event::Open(Connection {
+            id,
+            route: route.to_string(),
+            client: client.to_string(),
+            upstream: upstream.to_string(),
+            opened: now(),
+            closed: None,
+            sent: 0,
+            received: 0,
+            pages: vec![],
+            gaps: vec![],
+        }));
+
+        Recording(Arc::new(RecordingInner {
+            id,
+            events,
+            queued: self.queued.clone(),
+            read: [AtomicU64::new(0), AtomicU64::new(0)],
+        }))
+    }
+
+    /// Write out what's left and the final index.
+    pub async fn finish(&self) {
+        let _ = self.events.send(Event::Finish);
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            let _ = tokio::task::spawn_blocking(move || writer.join()).await;
+        }
+    }
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |since| since.as_secs_f64())
+}
+
+/// One connection's share of a capture, cloned for each of its streams.
+#[derive(Clone)]
+pub struct Recording(Arc<RecordingInner>);
+
+struct RecordingInner {
+    id: u64,
+    events: Sender<Event>,
+    queued: Arc<AtomicUsize>,
+    /// Bytes read so far, up and down.
+    read: [AtomicU64; 2],
+}
+
+impl Recording {
+    /// Queue a read for the writer, or let it go as a gap if too much is queued already.
+    fn record(&self, direction: Direction, bytes: &[u8]) {
+        let inner = &self.0;
+        let read = &inner.read[direction as usize];
+        let offset = read.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+
+        let queued = inner.queued.fetch_add(bytes.len(), Ordering::Relaxed);
+        if queued + bytes.len() > MAX_QUEUED {
+            inner.queued.fetch_sub(bytes.len(), Ordering::Relaxed);
+            return;
+        }
+        let data = Event::Data(inner.id, direction, offset, bytes.to_vec());
+        if inner.events.send(data).is_err() {
+            inner.queued.fetch_sub(bytes.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for RecordingInner {
+    fn drop(&mut self) {
+        let read = self
+            .read
+            .each_ref()
+            .map(|read| read.load(Ordering::Relaxed));
+        let _ = self.events.send(Event::Closed(self.id, now(), read));
+    }
+}
+
+/// A stream whose reads are recorded as one direction of a connection, if it's captured.
+pub struct Recorded<S> {
+    inner: S,
+    recording: Option<(Recording, Direction)>,
+}
+
+impl<S> Recorded<S> {
+    pub fn new(inner: S, recording: Option<Recording>, direction: Direction) -> Recorded<S> {
+        Recorded {
+            inner,
+            recording: recording.map(|recording| (recording, direction)),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Recorded<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some((recording, direction))) = (&poll, &self.recording) {
+            let read = &buf.filled()[before..];
+            if !read.is_empty() {
+                recording.record(*direction, read);
+            }
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Recorded<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Where one direction of an open connection is up to.
+#[derive(Default)]
+struct Side {
+    /// Not yet on a page.
+    pending: Vec<u8>,
+    offset: u64,
+    pages: usize,
+}
+
+/// An open connection and its two directions.
+struct Open {
+    connection: Connection,
+    up: Side,
+    down: Side,
+}
+
+impl Open {
+    fn side(&mut self, direction: Direction) -> (&mut Connection, &mut Side) {
+        match direction {
+            Direction::Up => (&mut self.connection, &mut self.up),
+            Direction::Down => (&mut self.connection, &mut self.down),
+        }
+    }
+}
+
+struct Writer {
+    dir: PathBuf,
+    open: HashMap<u64, Open>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Writer {
+    fn run(mut self, events: mpsc::Receiver<Event>, interval: Duration) {
+        let mut flushed = Instant::now();
+        loop {
+            match events.recv_timeout(interval.saturating_sub(flushed.elapsed())) {
+                Ok(Event::Open(connection)) => {
+                    let open = Open {
+                        connection,
+                        up: Side::default(),
+                        down: Side::default(),
+                    };
+                    self.open.insert(open.connection.id, open);
+                }
+                Ok(Event::Data(id, direction, offset, bytes)) => {
+                    self.data(id, direction, offset, &bytes);
+                    self.queued.fetch_sub(bytes.len(), Ordering::Relaxed);
+                }
+                Ok(Event::Closed(id, at, read)) => {
+                    if let Some(open) = self.open.remove(&id) {
+                        self.close(open, at, read);
+                    }
+                }
+                Ok(Event::Finish) | Err(RecvTimeoutError::Disconnected) => {
+                    self.flush_all();
+                    self.write_index();
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+
+            if flushed.elapsed() >= interval {
+                self.flush_all();
+                self.write_index();
+                flushed = Instant::now();
+            }
+        }
+    }
+
+    fn data(&mut self, id: u64, direction: Direction, offset: u64, bytes: &[u8]) {
+        let Some(mut open) = self.open.remove(&id) else {
+            return;
+        };
+        let end = offset + bytes.len() as u64;
+        match direction {
+            Direction::Up => open.connection.sent = end,
+            Direction::Down => open.connection.received = end,
+        }
+
+        // Reads dropped before they got here leave a gap, pages never span one.
+        self.skip_to(&mut open, direction, offset);
+
+        // Full pages go out right away, the rest waits for more or the interval.
+        open.side(direction).1.pending.extend_from_slice(bytes);
+        loop {
+            let (_, side) = open.side(direction);
+            if side.pending.len() < CAPACITY {
+                break;
+            }
+            let page: Vec<u8> = side.pending.drain(..CAPACITY).collect();
+            self.page(&mut open, direction, &page);
+        }
+        self.open.insert(id, open);
+    }
+
+    /// Record everything of a direction short of `offset` not already on a page or
+    /// pending as a gap.
+    fn skip_to(&mut self, open: &mut Open, direction: Direction, offset: u64) {
+        let (_, side) = open.side(direction);
+        let expected = side.offset + side.pending.len() as u64;
+        if offset <= expected {
+            return;
+        }
+        if !side.pending.is_empty() {
+            let page = std::mem::take(&mut side.pending);
+            self.page(open, direction, &page);
+        }
+        Self::gap(open, direction, offset - expected);
+    }
+
+    /// Write out a closed connection's last pages, note anything read after the last
+    /// byte that reached the writer as a gap, and move it to the closed log.
+    fn close(&mut self, mut open: Open, at: f64, read: [u64; 2]) {
+        for (direction, read) in [(Direction::Up, read[0]), (Direction::Down, read[1])] {
+            self.skip_to(&mut open, direction, read);
+        }
+        self.flush(&mut open);
+
+        let connection = &mut open.connection;
+        connection.closed = Some(at);
+        (connection.sent, connection.received) = (read[0], read[1]);
+        let lost: u64 = connection.gaps.iter().map(|gap| gap.len).sum();
+        if lost > 0 {
+            eprintln!(
+                "[{}] Capture of {} lost {} bytes, see the gaps in {}",
+                connection.route, connection.id, lost, CLOSED
+            );
+        }
+
+        let path = self.dir.join(CLOSED);
+        let result = serde_json::to_vec(&connection)
+            .map_err(io::Error::other)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?
+                    .write_all(&line)
+            });
+        if let Err(e) = result {
+            eprintln!("Capture can't write {}: {}", path.display(), e);
+        }
+    }
+
+    fn flush(&mut self, open: &mut Open) {
+        for direction in [Direction::Up, Direction::Down] {
+            let (_, side) = open.side(direction);
+            if !side.pending.is_empty() {
+                let page = std::mem::take(&mut side.pending);
+                self.page(open, direction, &page);
+            }
+        }
+    }
+
+    fn flush_all(&mut self) {
+        let mut open = std::mem::take(&mut self.open);
+        for open in open.values_mut() {
+            self.flush(open);
+        }
+        self.open = open;
+    }
+
+    fn page(&self, open: &mut Open, direction: Direction, bytes: &[u8]) {
+        let (connection, side) = open.side(direction);
+        let file = format!(
+            "{:06}-{}-{:06}.png",
+            connection.id,
+            direction.name(),
+            side.pages
+        );
+
+        let result = spiral_png(bytes, vec![], Layout::new(256))
+            .and_then(|png| std::fs::write(self.dir.join(&file), png));
+        if let Err(e) = result {
+            eprintln!("Capture can't write {}: {}", file, e);
+            Self::gap(open, direction, bytes.len() as u64);
+            return;
+        }
+        connection.pages.push(Page {
+            file,
+            direction,
+            offset: side.offset,
+            len: bytes.len(),
+            time: now(),
+        });
+        side.offset += bytes.len() as u64;
+        side.pages += 1;
+    }
+
+    /// Skip `len` bytes of a direction that won't be on any page.
+    fn gap(open: &mut Open, direction: Direction, len: u64) {
+        let (connection, side) = open.side(direction);
+        match connection.gaps.last_mut() {
+            Some(gap) if gap.direction == direction && gap.offset + gap.len == side.offset => {
+                gap.len += len
+            }
+            _ => connection.gaps.push(Gap {
+                direction,
+                offset: side.offset,
+                len,
+            }),
+        }
+        side.offset += len;
+    }
+
+    /// Replaced whole, so readers never see half an index.
+    fn write_index(&self) {
+        #[derive(Serialize)]
+        struct OpenIndex<'a> {
+            connections: Vec<&'a Connection>,
+        }
+
+        let mut connections: Vec<&Connection> =
+            self.open.values().map(|open| &open.connection).collect();
+        connections.sort_by_key(|connection| connection.id);
+
+        let path = self.dir.join(INDEX);
+        let partial = self.dir.join(format!("{}.partial", INDEX));
+        let result = serde_json::to_vec_pretty(&OpenIndex { connections })
+            .map_err(io::Error::other)
+            .and_then(|json| std::fs::write(&partial, json))
+            .and_then(|_| std::fs::rename(&partial, &path));
+        if let Err(e) = result {
+            eprintln!("Capture can't write {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// Every connection of the capture in `dir`, closed or still open when it ended.
+fn connections(dir: &Path) -> Result<Vec<Connection>, Box<dyn Error>> {
+    let index_path = dir.join(INDEX);
+    let index =
+        std::fs::read(&index_path).map_err(|e| format!("{}: {}", index_path.display(), e))?;
+    let index: Index =
+        serde_json::from_slice(&index).map_err(|e| format!("{}: {}", index_path.display(), e))?;
+
+    let mut connections = vec![];
+    let closed_path = dir.join(CLOSED);
+    match std::fs::File::open(&closed_path) {
+        Ok(closed) => {
+            for line in io::BufReader::new(closed).lines() {
+                let line = line.map_err(|e| format!("{}: {}", closed_path.display(), e))?;
+                // The last line may have been cut short if ximp died writing it.
+                match serde_json::from_str(&line) {
+                    Ok(connection) => connections.push(connection),
+                    Err(e) => eprintln!("{}: skipping a line, {}", closed_path.display(), e),
+                }
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("{}: {}", closed_path.display(), e).into()),
+    }
+
+    // A capture still running may have logged a connection as closed before taking it
+    // out of the index, the closed entry is the complete one.
+    connections.extend(index.connections);
+    connections.sort_by_key(|connection| connection.id);
+    connections.dedup_by_key(|connection| connection.id);
+    Ok(connections)
+}
+
+/**
+ * Decode the capture in `dir` back to the streams it recorded, one file per connection
+ * and direction in `out`: `000001-up.bin` holds what the first client sent, and
+ * `000001-down.bin` what came back. Gaps in the capture are filled with zeros, so what
+ * follows stays at its offset, and reported.
+ */
+pub fn replay(dir: &Path, out: &Path) -> Result<(), Box<dyn Error>> {
+    let connections = connections(dir)?;
+    std::fs::create_dir_all(out)?;
+
+    for connection in &connections {
+        let mut lengths = vec![];
+        for (direction, recorded) in [
+            (Direction::Up, connection.sent),
+            (Direction::Down, connection.received),
+        ] {
+            let mut stream = vec![];
+            let mut gaps = connection
+                .gaps
+                .iter()
+                .filter(|gap| gap.direction == direction)
+                .peekable();
+            let mut fill = |stream: &mut Vec<u8>| {
+                while let Some(gap) = gaps.next_if(|gap| gap.offset == stream.len() as u64) {
+                    eprintln!(
+                        "Connection {} {} lost {} bytes at {}, filled with zeros",
+                        connection.id,
+                        direction.name(),
+                        gap.len,
+                        gap.offset
+                    );
+                    stream.resize(stream.len() + gap.len as usize, 0);
+                }
+            };
+            for page in connection
+                .pages
+                .iter()
+                .filter(|page| page.direction == direction)
+            {
+                fill(&mut stream);
+                if page.offset != stream.len() as u64 {
+                    return Err(
+                        format!("{} doesn't follow on from the page before", page.file).into(),
+                    );
+                }
+                let png = std::fs::read(dir.join(&page.file))
+                    .map_err(|e| format!("{}: {}", page.file, e))?;
+                let bytes = decode_spiral(&png, &DecodeLimits::default())
+                    .map_err(|e| format!("{}: {}", page.file, e))?;
+                if bytes.len() != page.len {
+                    return Err(format!(
+                        "{} holds {} bytes, not {}",
+                        page.file,
+                        bytes.len(),
+                        page.len
+                    )
+                    .into());
+                }
+                stream.extend(bytes);
+            }
+            fill(&mut stream);
+            if stream.len() as u64 != recorded {
+                eprintln!(
+                    "Connection {} {} is missing {} of {} bytes",
+                    connection.id,
+                    direction.name(),
+                    recorded.saturating_sub(stream.len() as u64),
+                    recorded
+                );
+            }
+
+            let file = format!("{:06}-{}.bin", connection.id, direction.name());
+            std::fs::write(out.join(file), &stream)?;
+            lengths.push(stream.len());
+        }
+        println!(
+            "{:06} {} to {} via {}: {} bytes sent, {} received",
+            connection.id,
+            connection.client,
+            connection.upstream,
+            connection.route,
+            lengths[0],
+            lengths[1]
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::testing::scratch;
+
+    #[tokio::test]
+    async fn replays_what_was_recorded() {
+        let dir = scratch("capture-replay");
+        let capture = Capture::start(&dir, Duration::from_secs(60)).unwrap();
+        let up: Vec<u8> = (0..5000u32).map(|i| (i * 31 % 251) as u8).collect();
+
+        let recording = capture.open("route", "client", "upstream");
+        for chunk in up.chunks(700) {
+            recording.record(Direction::Up, chunk);
+        }
+        recording.record(Direction::Down, b"reply");
+        drop(recording);
+        capture.finish().await;
+
+        assert!(Capture::start(&dir, Duration::from_secs(60)).is_err());
+        let index: Index =
+            serde_json::from_slice(&std::fs::read(dir.join(INDEX)).unwrap()).unwrap();
+        assert!(index.connections.is_empty());
+        let closed = std::fs::read_to_string(dir.join(CLOSED)).unwrap();
+        assert_eq!(closed.lines().count(), 1);
+
+        replay(&dir, &dir).unwrap();
+        assert_eq!(std::fs::read(dir.join("000001-up.bin")).unwrap(), up);
+        assert_eq!(
+            std::fs::read(dir.join("000001-down.bin")).unwrap(),
+            b"reply"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_offsets_across_dropped_reads() {
+        let dir = scratch("capture-gap");
+        let capture = Capture::start(&dir, Duration::from_secs(60)).unwrap();
+
+        let recording = capture.open("route", "client", "upstream");
+        recording.record(Direction::Up, b"before");
+        // As if the queue were full, the read is counted but never reaches the writer.
+        capture.queued.fetch_add(MAX_QUEUED, Ordering::Relaxed);
+        recording.record(Direction::Up, b"dropped");
+        capture.queued.fetch_sub(MAX_QUEUED, Ordering::Relaxed);
+        recording.record(Direction::Up, b"after");
+        recording.record(Direction::Down, b"reply");
+        // Dropped reads at the very end have no later read to show them up.
+        capture.queued.fetch_add(MAX_QUEUED, Ordering::Relaxed);
+        recording.record(Direction::Up, b"last");
+        recording.record(Direction::Down, b"lost");
+        capture.queued.fetch_sub(MAX_QUEUED, Ordering::Relaxed);
+        drop(recording);
+        capture.finish().await;
+
+        let connections = connections(&dir).unwrap();
+        let connection = &connections[0];
+        assert_eq!((connection.sent, connection.received), (22, 9));
+        let gaps: Vec<_> = connection
+            .gaps
+            .iter()
+            .map(|gap| (gap.direction, gap.offset, gap.len))
+            .collect();
+        assert_eq!(
+            gaps,
+            [
+                (Direction::Up, 6, 7),
+                (Direction::Up, 18, 4),
+                (Direction::Down, 5, 4)
+            ]
+        );
+
+        replay(&dir, &dir).unwrap();
+        let up = std::fs::read(dir.join("000001-up.bin")).unwrap();
+        assert_eq!(up, b"before\0\0\0\0\0\0\0after\0\0\0\0");
+        let down = std::fs::read(dir.join("000001-down.bin")).unwrap();
+        assert_eq!(down, b"reply\0\0\0\0");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,330 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::limits::Limits;
+use crate::pool::Balance;
+use crate::proxy_protocol::Version;
+
+/// What a route carries.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// Datagrams, relayed per client session.
+    Udp,
+}
+
+/// A listener and where the connections it accepts are sent.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Shown in the logs, defaults to the listen address.
+    pub name: Option<String>,
+    pub listen: String,
+    /// One address or a list of them to balance over.
+    #[serde(deserialize_with = "one_or_many")]
+    pub upstream: Vec<String>,
+    pub balance: Option<Balance>,
+    /// Seconds.
+    pub connect_timeout: Option<f64>,
+    /// Seconds between health checks of the upstreams.
+    pub health_interval: Option<f64>,
+    /// Clients start with a PROXY header, from a load balancer in front of ximp.
+    pub accept_proxy: Option<bool>,
+    /// Start upstream connections with a PROXY header of this version.
+    pub send_proxy: Option<Version>,
+    pub transport: Option<Transport>,
+    /// UDP routes write every datagram they relay here as a spiral PNG.
+    pub inspect: Option<PathBuf>,
+    /// TCP routes record their connections here, see `Capture`.
+    pub capture: Option<PathBuf>,
+    /// Seconds between writes of a capture's partly filled pages.
+    pub capture_interval: Option<f64>,
+    /// PEM certificate chain and key to terminate TLS with.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// Only accept clients with a certificate from one of these CAs.
+    pub tls_client_ca: Option<PathBuf>,
+    /// Connect to the upstreams over TLS, trusting these CAs.
+    pub upstream_ca: Option<PathBuf>,
+    /// The name upstream certificates are checked against, instead of their host.
+    pub upstream_name: Option<String>,
+    /// Seconds, overriding the limit for this route.
+    pub idle_timeout: Option<f64>,
+}
+
+impl Route {
+    /// A route to `upstream`, a comma separated list of addresses.
+    pub fn new(listen: &str, upstream: &str) -> Route {
+        Route {
+            name: None,
+            listen: listen.to_string(),
+            upstream: upstream
+                .split(',')
+                .filter(|addr| !addr.is_empty())
+                .map(String::from)
+                .collect(),
+            balance: None,
+            connect_timeout: None,
+            health_interval: None,
+            accept_proxy: None,
+            send_proxy: None,
+            transport: None,
+            inspect: None,
+            capture: None,
+            capture_interval: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            upstream_ca: None,
+            upstream_name: None,
+            idle_timeout: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.listen)
+    }
+}
+
+/// A transcribe server, encoding and decoding spirals over HTTP.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Transcribe {
+    /// Shown in the logs, defaults to the listen address.
+    pub name: Option<String>,
+    pub listen: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    /// Overrides for this server of the limits.
+    pub body_limit: Option<usize>,
+    /// Seconds.
+    pub idle_timeout: Option<f64>,
+    /// Encoding options for requests that don't set their own.
+    pub module: Option<u32>,
+    pub quiet: Option<u32>,
+    pub finders: Option<bool>,
+    pub compress: Option<String>,
+}
+
+impl Transcribe {
+    pub fn new(listen: &str) -> Transcribe {
+        Transcribe {
+            name: None,
+            listen: listen.to_string(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            body_limit: None,
+            idle_timeout: None,
+            module: None,
+            quiet: None,
+            finders: None,
+            compress: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.listen)
+    }
+}
+
+/**
+ * ximp's config file, TOML. Every `[[transcribe]]` and `[[route]]` table is a listener
+ * of its own, `[limits]` holds for all of them:
+ *
+ * ```toml
+ * [limits]
+ * max_connections = 4096
+ * idle_timeout = 60
+ *
+ * [[transcribe]]
+ * listen = "0.0.0.0:8443"
+ * tls_cert = "/etc/ximp/cert.pem"
+ * tls_key = "/etc/ximp/key.pem"
+ * body_limit = 65536
+ * module = 4
+ * quiet = 2
+ * compress = "deflate"
+ *
+ * [[route]]
+ * name = "transcribe"
+ * listen = "127.0.0.1:8081"
+ * upstream = ["127.0.0.1:8080", "127.0.0.1:8090"]
+ * balance = "least-connections"
+ * connect_timeout = 2.5
+ * health_interval = 10
+ * send_proxy = "v2"
+ * capture = "/var/tmp/capture"
+ * tls_cert = "/etc/ximp/cert.pem"
+ * tls_key = "/etc/ximp/key.pem"
+ * upstream_ca = "/etc/ximp/upstream-ca.pem"
+ *
+ * [[route]]
+ * name = "telemetry"
+ * listen = "0.0.0.0:9125"
+ * upstream = "10.0.0.7:9125"
+ * transport = "udp"
+ * inspect = "/var/tmp/telemetry"
+ * ```
+ */
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub transcribe: Vec<Transcribe>,
+    #[serde(default, rename = "route")]
+    pub routes: Vec<Route>,
+}
+
+impl Config {
+    pub fn from_path(path: &str) -> Result<Config, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(toml::from_str(&text).map_err(|e| format!("{}: {}", path, e))?)
+    }
+}
+
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => Ok(vec![addr]),
+        OneOrMany::Many(addrs) if !addrs.is_empty() => Ok(addrs),
+        OneOrMany::Many(_) => Err(serde::de::Error::custom("no upstream addresses")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn parse(text: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    #[test]
+    fn reads_a_full_config() {
+        let config = parse(
+            r#"
+            [limits]
+            max_connections = 4096
+            idle_timeout = 60
+            drain_timeout = 0.5
+
+            [[transcribe]]
+            listen = "0.0.0.0:8443"
+            body_limit = 65536
+            compress = "deflate"
+
+            [[route]]
+            name = "transcribe"
+            listen = "127.0.0.1:8081"
+            upstream = ["127.0.0.1:8080", "127.0.0.1:8090"]
+            balance = "least-connections"
+            connect_timeout = 2.5
+            send_proxy = "v2"
+
+            [[route]]
+            listen = "0.0.0.0:9125"
+            upstream = "10.0.0.7:9125"
+            transport = "udp"
+            inspect = "/var/tmp/telemetry"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.limits.max_connections, 4096);
+        assert_eq!(config.limits.idle_timeout, Duration::from_secs(60));
+        assert_eq!(config.limits.drain_timeout, Duration::from_millis(500));
+        assert_eq!(config.limits.body_limit, Limits::default().body_limit);
+
+        assert_eq!(config.transcribe.len(), 1);
+        let transcribe = &config.transcribe[0];
+        assert_eq!(transcribe.name(), "0.0.0.0:8443");
+        assert_eq!(transcribe.body_limit, Some(65536));
+        assert_eq!(transcribe.compress.as_deref(), Some("deflate"));
+
+        let [tcp, udp] = &config.routes[..] else {
+            panic!("{:?}", config.routes);
+        };
+        assert_eq!(tcp.name(), "transcribe");
+        assert_eq!(tcp.upstream, ["127.0.0.1:8080", "127.0.0.1:8090"]);
+        assert_eq!(tcp.balance, Some(Balance::LeastConnections));
+        assert_eq!(tcp.connect_timeout, Some(2.5));
+        assert_eq!(tcp.send_proxy, Some(Version::V2));
+        assert_eq!(tcp.transport, None);
+        assert_eq!(udp.name(), "0.0.0.0:9125");
+        assert_eq!(udp.upstream, ["10.0.0.7:9125"]);
+        assert_eq!(udp.transport, Some(Transport::Udp));
+        assert_eq!(udp.inspect, Some(PathBuf::from("/var/tmp/telemetry")));
+
+        let empty = parse("").unwrap();
+        assert!(empty.transcribe.is_empty() && empty.routes.is_empty());
+        assert_eq!(empty.limits.idle_timeout, Limits::default().idle_timeout);
+    }
+
+    #[test]
+    fn takes_one_upstream_or_many() {
+        let route = |upstream: &str| {
+            parse(&format!(
+                "[[route]]\nlisten = \"127.0.0.1:1\"\nupstream = {}",
+                upstream
+            ))
+            .map(|config| config.routes[0].upstream.clone())
+        };
+        assert_eq!(route("\"a:1\"").unwrap(), ["a:1"]);
+        assert_eq!(route("[\"a:1\", \"b:2\"]").unwrap(), ["a:1", "b:2"]);
+        assert!(route("[]").is_err());
+        assert!(route("7").is_err());
+
+        assert_eq!(
+            Route::new("127.0.0.1:1", "a:1,b:2,").upstream,
+            ["a:1", "b:2"]
+        );
+    }
+
+    #[test]
+    fn refuses_unknown_fields() {
+        for text in [
+            "listen = \"127.0.0.1:1\"",
+            "[limits]\nmax_conections = 10",
+            "[[transcribe]]\nlisten = \"127.0.0.1:1\"\ntls = true",
+            "[[route]]\nlisten = \"127.0.0.1:1\"\nupstream = \"a:1\"\nbalance = \"random\"",
+            "[[route]]\nlisten = \"127.0.0.1:1\"\nupstream = \"a:1\"\nupstreams = \"b:2\"",
+            "[[route]]\nlisten = \"127.0.0.1:1\"",
+        ] {
+            assert!(parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn refuses_seconds_that_arent_positive() {
+        for text in [
+            "[limits]\nidle_timeout = 0",
+            "[limits]\nidle_timeout = -5",
+            "[limits]\ndrain_timeout = 0.0",
+            "[limits]\ndrain_timeout = -0.5",
+        ] {
+            let error = parse(text).unwrap_err().to_string();
+            assert!(
+                error.contains("not a positive number of seconds"),
+                "{}",
+                error
+            );
+        }
+        assert!(parse("[limits]\nidle_timeout = 0.25").is_ok());
+    }
+}
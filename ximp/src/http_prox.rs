@@ -0,0 +1,414 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{ConnectInfo, Request, State};
+use axum::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use axum::http::{Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use futures::StreamExt;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use textual_geometry::pipeline::Pipeline;
+use textual_geometry::rendering::Layout;
+use tokio_rustls::TlsAcceptor;
+
+use crate::http_svc::{decode_png, spiral_png, CAPACITY};
+use crate::limits::{Connections, Limits};
+use crate::net::{Addr, Listener, Peer};
+
+/// Headers that describe one hop rather than the message, never forwarded.
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// What the HTTP proxy turns into images and back.
+#[derive(Clone, Debug)]
+pub struct Imaging {
+    /// Response media types, without parameters, replaced by their spiral encoding.
+    pub types: Vec<String>,
+    /// Pipeline stages applied before encoding, as for `/spiral?compress=`.
+    pub stages: Vec<String>,
+    /// Decode spiral PNG request bodies before forwarding them.
+    pub decode_uploads: bool,
+}
+
+impl Default for Imaging {
+    fn default() -> Imaging {
+        Imaging {
+            types: vec![String::from("text/plain"), String::from("application/json")],
+            stages: vec![],
+            decode_uploads: false,
+        }
+    }
+}
+
+struct Upstream {
+    client: Client<HttpConnector, Body>,
+    /// Host and port.
+    addr: String,
+    imaging: Imaging,
+    body_limit: usize,
+}
+
+/**
+ * A reverse proxy in front of a plain HTTP service on `upstream`. Responses of the types
+ * in `imaging` come back as spiral PNGs instead, as long as they fit one, and with
+ * `decode_uploads` spiral PNGs sent to it reach the upstream decoded.
+ */
+pub async fn http_prox(
+    listen: &str,
+    upstream: &str,
+    imaging: Imaging,
+    limits: Limits,
+    tls: Option<TlsAcceptor>,
+) -> Result<(), Box<dyn Error>> {
+    Pipeline::from_names(&imaging.stages)?;
+
+    let state = Arc::new(Upstream {
+        client: Client::builder(TokioExecutor::new()).build_http(),
+        addr: upstream.to_string(),
+        imaging,
+        body_limit: limits.body_limit,
+    });
+    let app = Router::new().fallback(forward).with_state(state);
+
+    let listener = Listener::bind(&Addr::parse(listen)).await?;
+    println!("ximp HTTP proxy listening on {}", listen);
+    println!("Imaging responses from: {}", upstream);
+
+    let connections = Connections::new(limits.max_connections);
+    crate::http_svc::serve(listener, app, limits, tls, connections.clone()).await;
+    connections.drain(limits.drain_timeout).await;
+    Ok(())
+}
+
+async fn forward(
+    State(upstream): State<Arc<Upstream>>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
+    req: Request,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let head = parts.method == Method::HEAD;
+
+    let path = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    parts.uri = match format!("http://{}{}", upstream.addr, path).parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+    strip_hop_by_hop(&mut parts.headers);
+    append_forwarded_for(&mut parts.headers, peer);
+
+    let body = if upstream.imaging.decode_uploads && media_type(&parts.headers) == "image/png" {
+        let png = match to_bytes(body, upstream.body_limit).await {
+            Ok(png) => png,
+            Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        };
+        let decoded = match tokio::task::spawn_blocking(move || decode_png(&png)).await {
+            Ok(Ok(decoded)) => decoded,
+            Ok(Err(e)) => return (StatusCode::UNPROCESSABLE_ENTITY, e).into_response(),
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        parts
+            .headers
+            .insert(header::CONTENT_LENGTH, decoded.len().into());
+        Body::from(decoded)
+    } else {
+        body
+    };
+
+    let res = match upstream
+        .client
+        .request(Request::from_parts(parts, body))
+        .await
+    {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("Upstream {} failed: {}", upstream.addr, e);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    let (mut parts, body) = res.into_parts();
+    strip_hop_by_hop(&mut parts.headers);
+    let body = Body::new(body);
+
+    let media = media_type(&parts.headers);
+    let encoded = parts
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|coding| coding != "identity");
+    let declared = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok());
+    // Bodiless responses describe a body that isn't there to be imaged.
+    let bodiless = head
+        || matches!(
+            parts.status,
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        );
+    if bodiless
+        || encoded
+        || declared.is_some_and(|len| len > upstream.body_limit)
+        || !upstream.imaging.types.contains(&media)
+    {
+        return Response::from_parts(parts, body);
+    }
+
+    // Without a length there's no telling whether the body fits before reading it.
+    let text = match read_up_to(body, upstream.body_limit).await {
+        Ok(Ok(text)) => text,
+        Ok(Err(body)) => return Response::from_parts(parts, body),
+        Err(e) => {
+            eprintln!("Upstream {} response unreadable: {}", upstream.addr, e);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+
+    let stages = upstream.imaging.stages.clone();
+    let imaged = tokio::task::spawn_blocking(move || {
+        let payload = Pipeline::from_names(&stages)
+            .and_then(|pipeline| pipeline.encode(&text))
+            .ok()?;
+        // Larger bodies would be cut short, better they pass as they are.
+        if payload.len() > CAPACITY {
+            return Some(Err(text));
+        }
+        spiral_png(&payload, stages, Layout::new(256)).ok().map(Ok)
+    })
+    .await;
+
+    match imaged {
+        Ok(Some(Ok(png))) => {
+            let original = parts.headers.remove(header::CONTENT_TYPE);
+            if let Some(original) = original {
+                parts.headers.insert("X-Original-Content-Type", original);
+            }
+            parts
+                .headers
+                .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, png.len().into());
+            Response::from_parts(parts, Body::from(png))
+        }
+        Ok(Some(Err(text))) => {
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, text.len().into());
+            Response::from_parts(parts, Body::from(text))
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// The whole of `body` if it's `limit` bytes at most. Otherwise a body that replays what
+/// was read of it, then carries on with the rest.
+async fn read_up_to(body: Body, limit: usize) -> Result<Result<Bytes, Body>, axum::Error> {
+    let mut stream = body.into_data_stream();
+    let mut chunks = vec![];
+    let mut len = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        len += chunk.len();
+        chunks.push(chunk);
+        if len > limit {
+            let read = futures::stream::iter(chunks.into_iter().map(Ok));
+            return Ok(Err(Body::from_stream(read.chain(stream))));
+        }
+    }
+    Ok(Ok(chunks.concat().into()))
+}
+
+/// The media type of a message, lower case and without parameters.
+fn media_type(headers: &HeaderMap) -> String {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase()
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    // Connection may name further headers that only concern this hop.
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+fn append_forwarded_for(headers: &mut HeaderMap, peer: Peer) {
+    // Unix socket clients have no address to add.
+    let Some(peer) = peer.socket_addr() else {
+        return;
+    };
+    let forwarded = match headers.get("X-Forwarded-For").and_then(|v| v.to_str().ok()) {
+        Some(earlier) => format!("{}, {}", earlier, peer.ip()),
+        None => peer.ip().to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded) {
+        headers.insert("X-Forwarded-For", value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::routing::{get, post};
+
+    use super::*;
+
+    const LIMIT: usize = 64 * 1024;
+
+    /// A plain HTTP service on loopback to proxy for.
+    async fn upstream() -> SocketAddr {
+        let text = (
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            "hello",
+        );
+        let html = ([(header::CONTENT_TYPE, "text/html")], "<p>hello</p>");
+        let empty = (
+            StatusCode::NO_CONTENT,
+            [(header::CONTENT_TYPE, "text/plain")],
+        );
+        // Streamed, so sent without a length.
+        let long = || async {
+            let chunks = (0..3).map(|i| Ok::<_, axum::Error>(vec![b'a' + i; LIMIT / 2]));
+            (
+                [(header::CONTENT_TYPE, "text/plain")],
+                Body::from_stream(futures::stream::iter(chunks)),
+            )
+        };
+        let echo = |headers: HeaderMap, body: Bytes| async move {
+            let kind = headers.get(header::CONTENT_TYPE).cloned();
+            ([(header::CONTENT_TYPE, kind.unwrap())], body)
+        };
+        let app = Router::new()
+            .route("/text", get(|| async move { text }))
+            .route("/html", get(|| async move { html }))
+            .route("/empty", get(|| async move { empty }))
+            .route("/long", get(long))
+            .route("/echo", post(echo));
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    async fn proxy(method: Method, path: &str, body: Request) -> (StatusCode, HeaderMap, Bytes) {
+        let addr = upstream().await;
+        let upstream = Arc::new(Upstream {
+            client: Client::builder(TokioExecutor::new()).build_http(),
+            addr: addr.to_string(),
+            imaging: Imaging {
+                decode_uploads: true,
+                ..Imaging::default()
+            },
+            body_limit: LIMIT,
+        });
+        let (mut parts, body) = body.into_parts();
+        parts.method = method;
+        parts.uri = path.parse().unwrap();
+        let peer = Peer::Tcp("192.0.2.7:51234".parse().unwrap());
+
+        let res = forward(
+            State(upstream),
+            ConnectInfo(peer),
+            Request::from_parts(parts, body),
+        );
+        let (parts, body) = res.await.into_parts();
+        let body = to_bytes(body, usize::MAX).await.unwrap();
+        (parts.status, parts.headers, body)
+    }
+
+    fn get_request() -> Request {
+        Request::new(Body::empty())
+    }
+
+    #[tokio::test]
+    async fn images_text_responses() {
+        let (status, headers, body) = proxy(Method::GET, "/text", get_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        assert_eq!(
+            headers["X-Original-Content-Type"],
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(headers[header::CONTENT_LENGTH], body.len().to_string());
+        assert_eq!(decode_png(&body).unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn passes_other_responses_through() {
+        let (_, headers, body) = proxy(Method::GET, "/html", get_request()).await;
+        assert_eq!(headers[header::CONTENT_TYPE], "text/html");
+        assert_eq!(body, "<p>hello</p>");
+
+        // Longer than the limit and no length up front, it goes through whole.
+        let (status, headers, body) = proxy(Method::GET, "/long", get_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(body.len(), 3 * LIMIT / 2);
+        assert!(body[..LIMIT / 2].iter().all(|&b| b == b'a'));
+        assert!(body[LIMIT..].iter().all(|&b| b == b'c'));
+    }
+
+    #[tokio::test]
+    async fn leaves_bodiless_responses_alone() {
+        let (status, headers, body) = proxy(Method::HEAD, "/text", get_request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert!(body.is_empty());
+
+        let (status, headers, body) = proxy(Method::GET, "/empty", get_request()).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn decodes_uploads() {
+        let png = spiral_png(b"uploaded", vec![], Layout::new(256)).unwrap();
+        let mut upload = Request::new(Body::from(png));
+        upload
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        let (status, headers, body) = proxy(Method::POST, "/echo", upload).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "application/octet-stream");
+        assert_eq!(body, "uploaded");
+
+        let mut garbage = Request::new(Body::from("not a png"));
+        garbage
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        let (status, _, _) = proxy(Method::POST, "/echo", garbage).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
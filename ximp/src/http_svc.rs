@@ -1,59 +1,302 @@
-use std::io::Cursor;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
 
 use axum::body::Bytes;
 use axum::debug_handler;
-use axum::extract::Query;
-use axum::http::{HeaderMap, StatusCode};
+use axum::extract::{ConnectInfo, DefaultBodyLimit, Query};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
 use axum::response::IntoResponse;
-use axum::{routing::get, Router};
-use hex;
+use axum::routing::post;
+use axum::{routing::get, Extension, Router};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
 use serde::Deserialize;
-use std::io::BufWriter;
-use textual_geometry::geometry::{Geometry, SpiralGeometry};
-use textual_geometry::rendering::Bitmap;
+use textual_geometry::decode::{decode_spiral_report, DecodeLimits, Sampling};
+use textual_geometry::encoder::Encoder;
+use textual_geometry::geometry::SpiralGeometry;
+use textual_geometry::metadata::{EncodingParams, MAX_MODULE, MAX_QUIET};
+use textual_geometry::pipeline::{stage_by_name, Pipeline};
+use textual_geometry::rendering::{Bitmap, Layout};
+use textual_geometry::signing::{self, Signed};
+use textual_geometry::threshold::Threshold;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 
-pub async fn http_svc() {
-    let app = Router::new().route("/spiral", get(echo_geometry).post(echo_geometry_lg));
+use crate::config::Transcribe;
+use crate::limits::{self, Activity, Connections, Limits, Tracked};
+use crate::net::{Addr, Listener, Peer};
+use crate::tls;
 
-    let addr = "0.0.0.0:8080";
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+/**
+ * Bind a transcribe server and serve it on a task of its own until shut down. Draining
+ * what's left open is up to the caller, who may be running other listeners alongside.
+ */
+pub async fn transcribe(
+    server: &Transcribe,
+    limits: Limits,
+    connections: Connections,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let name = server.name();
+    let mut limits = limits;
+    if let Some(bytes) = server.body_limit {
+        limits.body_limit = bytes;
+    }
+    if let Some(secs) = server.idle_timeout {
+        limits.idle_timeout = limits::seconds(secs).map_err(|e| format!("[{}] {}", name, e))?;
+    }
+    let defaults = EncodeOptions {
+        compress: server.compress.clone(),
+        module: server.module,
+        quiet: server.quiet,
+        finders: server.finders,
+    };
+    Pipeline::from_names(&defaults.stages()).map_err(|e| format!("[{}] {}", name, e))?;
+    defaults.layout().map_err(|e| format!("[{}] {}", name, e))?;
+    let tls = tls::acceptor(
+        server.tls_cert.as_deref(),
+        server.tls_key.as_deref(),
+        server.tls_client_ca.as_deref(),
+    )
+    .map_err(|e| format!("[{}] {}", name, e))?;
+
+    let app = Router::new()
+        .route("/spiral", get(echo_geometry).post(echo_geometry_lg))
+        .route("/spiral/decode", post(decode_geometry))
+        .layer(DefaultBodyLimit::max(limits.body_limit))
+        .layer(Extension(Arc::new(defaults)));
 
-    println!("ximp transcribe listening on {}", addr);
+    let listener = Listener::bind(&Addr::parse(&server.listen))
+        .await
+        .map_err(|e| format!("[{}] can't listen on {}: {}", name, server.listen, e))?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    println!("[{}] Transcribing on {}://{}", name, scheme, server.listen);
 
-    axum::serve(listener, app).await.unwrap();
+    Ok(tokio::spawn(serve(listener, app, limits, tls, connections)))
 }
 
-fn spiral_encode_str(s: String) -> (StatusCode, HeaderMap, Vec<u8>) {
-    let hex_encoded = hex::encode(s);
+/**
+ * Serve `app` on `listener` until shut down, over TLS given an acceptor. Handlers can
+ * extract the client's address as `ConnectInfo<Peer>`.
+ */
+pub(crate) async fn serve(
+    listener: Listener,
+    app: Router,
+    limits: Limits,
+    tls: Option<TlsAcceptor>,
+    connections: Connections,
+) {
+    // Served by hand rather than through `axum::serve`, which has no say over how many
+    // connections are open, how long they may idle or how they end.
+    while let Some((stream, peer, permit)) = connections.accept(&listener).await {
+        let app = app.clone().layer(Extension(ConnectInfo::<Peer>(peer)));
+        let service = TowerToHyperService::new(app);
+        let connections = connections.clone();
+        let tls = tls.clone();
 
-    let mut geometry = SpiralGeometry::new(256);
-    geometry.translate(hex_encoded);
-    let mut bitmap = Bitmap::new(256);
-    bitmap.from_geometry(&geometry);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let stream = match &tls {
+                Some(acceptor) => match tls::accept(acceptor, stream, limits.idle_timeout).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        if e.kind() != io::ErrorKind::UnexpectedEof {
+                            eprintln!("TLS with {} failed: {}", peer, e);
+                        }
+                        return;
+                    }
+                },
+                None => stream,
+            };
+            let activity = Activity::new();
+            let io = TokioIo::new(Tracked::new(stream, activity.clone()));
+            let builder = Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(io, service);
+            tokio::pin!(conn);
+
+            let mut draining = false;
+            loop {
+                tokio::select! {
+                    _ = conn.as_mut() => break,
+                    // Let a request in flight finish, then close instead of keeping alive.
+                    _ = connections.shutting_down(), if !draining => {
+                        conn.as_mut().graceful_shutdown();
+                        draining = true;
+                    }
+                    _ = activity.idle(limits.idle_timeout) => break,
+                }
+            }
+        });
+    }
+}
+
+fn spiral_encode_str(s: String, options: &EncodeOptions) -> (StatusCode, HeaderMap, Vec<u8>) {
+    let stages = options.stages();
+    let payload = match Pipeline::from_names(&stages).and_then(|p| p.encode(s.as_bytes())) {
+        Ok(payload) => payload,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                HeaderMap::new(),
+                e.to_string().into_bytes(),
+            )
+        }
+    };
+
+    let layout = match options.layout() {
+        Ok(layout) => layout,
+        Err(e) => return (StatusCode::BAD_REQUEST, HeaderMap::new(), e.into_bytes()),
+    };
+    let bytes = match spiral_png(&payload, stages, layout) {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                HeaderMap::new(),
+                Vec::new(),
+            )
+        }
+    };
 
     let mut headers = HeaderMap::new();
-    headers.insert("Content-Type", "image/png".parse().unwrap());
+    headers.insert("Content-Type", HeaderValue::from_static("image/png"));
     // headers.insert("Content-Disposition", "attachment; filename=\"transcribe.png\"".parse().unwrap());
 
-    let mut buf = BufWriter::new(Cursor::new(Vec::new()));
-    bitmap.write_png_to(&mut buf);
+    (StatusCode::OK, headers, bytes)
+}
 
-    let bytes: Vec<u8> = buf.into_inner().unwrap().into_inner();
+/// Bytes a 256x256 spiral holds.
+pub(crate) const CAPACITY: usize = SpiralGeometry::capacity(256);
 
-    (StatusCode::OK, headers, bytes)
+/// Render a payload, the output of a pipeline with `stages`, as a spiral PNG recording
+/// how it was made.
+pub(crate) fn spiral_png(
+    payload: &[u8],
+    stages: Vec<String>,
+    layout: Layout,
+) -> io::Result<Vec<u8>> {
+    let mut geometry = SpiralGeometry::new(256);
+    let encoder = Encoder::from_bytes(256, payload, &mut geometry);
+    let metadata = EncodingParams::new("spiral", 256)
+        .with_layout(&layout)
+        .with_stages(stages);
+    let mut bitmap = Bitmap::with_layout(layout).with_metadata(metadata);
+
+    encoder.to_bytes(&mut bitmap)
+}
+
+/// Decode a spiral PNG as `/spiral/decode` does without options: sampled the way its
+/// metadata says, or exactly, and any signature stripped unchecked.
+pub(crate) fn decode_png(png: &[u8]) -> Result<Vec<u8>, String> {
+    let sampling = EncodingParams::from_png(png)
+        .and_then(|params| params.sampling())
+        .unwrap_or(Sampling::Exact);
+    let report = decode_spiral_report(png, &DecodeLimits::default(), &sampling, &Threshold::Auto)
+        .map_err(|e| e.to_string())?;
+    let bytes = report
+        .payload
+        .ok_or_else(|| String::from("recovered sequence is not valid hex"))?;
+    let bytes = match Signed::from_bytes(&bytes) {
+        Some(signed) => signed.payload,
+        None => bytes,
+    };
+
+    Pipeline::from_manifest(&bytes, stage_by_name)
+        .and_then(|pipeline| pipeline.decode(&bytes))
+        .map_err(|e| e.to_string())
 }
 
 #[derive(Deserialize)]
 struct GeometryRequest {
     input: String,
+    compress: Option<String>,
+    module: Option<u32>,
+    quiet: Option<u32>,
+    finders: Option<bool>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+struct EncodeOptions {
+    compress: Option<String>,
+    module: Option<u32>,
+    quiet: Option<u32>,
+    finders: Option<bool>,
+}
+
+impl EncodeOptions {
+    /// These options, with any left unset taken from `defaults`.
+    fn or(&self, defaults: &EncodeOptions) -> EncodeOptions {
+        EncodeOptions {
+            compress: self.compress.clone().or(defaults.compress.clone()),
+            module: self.module.or(defaults.module),
+            quiet: self.quiet.or(defaults.quiet),
+            finders: self.finders.or(defaults.finders),
+        }
+    }
+
+    fn stages(&self) -> Vec<String> {
+        self.compress.iter().cloned().collect()
+    }
+
+    /// The layout asked for, refused if its module or quiet zone is past the limits.
+    fn layout(&self) -> Result<Layout, String> {
+        let module = self.module.unwrap_or(1);
+        if module > MAX_MODULE {
+            return Err(format!(
+                "module {} is over the limit of {}",
+                module, MAX_MODULE
+            ));
+        }
+        let quiet = quiet(self.quiet)?;
+
+        Ok(Layout::new(256)
+            .with_module(module)
+            .with_quiet(quiet)
+            .with_finders(self.finders.unwrap_or(false)))
+    }
+}
+
+fn quiet(quiet: Option<u32>) -> Result<u32, String> {
+    match quiet {
+        Some(quiet) if quiet > MAX_QUIET => Err(format!(
+            "quiet zone {} is over the limit of {}",
+            quiet, MAX_QUIET
+        )),
+        quiet => Ok(quiet.unwrap_or(0)),
+    }
+}
+
+#[derive(Deserialize)]
+struct DecodeOptions {
+    quiet: Option<u32>,
+    finders: Option<bool>,
+    threshold: Option<String>,
+    /// Hex encoded Ed25519 public key the payload has to be signed by.
+    verify: Option<String>,
 }
 
 #[debug_handler]
-async fn echo_geometry(query: Query<GeometryRequest>) -> impl IntoResponse {
-    spiral_encode_str(query.input.clone())
+async fn echo_geometry(
+    Extension(defaults): Extension<Arc<EncodeOptions>>,
+    query: Query<GeometryRequest>,
+) -> impl IntoResponse {
+    let query = query.0;
+    let options = EncodeOptions {
+        compress: query.compress,
+        module: query.module,
+        quiet: query.quiet,
+        finders: query.finders,
+    };
+
+    spiral_encode_str(query.input, &options.or(&defaults))
 }
 
-async fn echo_geometry_lg(body: Bytes) -> impl IntoResponse {
+async fn echo_geometry_lg(
+    Extension(defaults): Extension<Arc<EncodeOptions>>,
+    options: Query<EncodeOptions>,
+    body: Bytes,
+) -> impl IntoResponse {
     let utf8_request: String;
 
     if let Ok(decoded) = String::from_utf8(body.to_vec()) {
@@ -62,5 +305,108 @@ async fn echo_geometry_lg(body: Bytes) -> impl IntoResponse {
         return (StatusCode::BAD_REQUEST, HeaderMap::new(), Vec::<u8>::new());
     }
 
-    spiral_encode_str(utf8_request)
+    spiral_encode_str(utf8_request, &options.or(&defaults))
+}
+
+async fn decode_geometry(options: Query<DecodeOptions>, body: Bytes) -> impl IntoResponse {
+    let mut headers = HeaderMap::new();
+
+    // Images that record how they were made configure the decoder, unless told otherwise.
+    if let Err(e) = quiet(options.quiet) {
+        headers.insert("Content-Type", "text/plain".parse().unwrap());
+        return (StatusCode::BAD_REQUEST, headers, e.into_bytes());
+    }
+    let threshold = match options.threshold.as_deref().map(Threshold::from_name) {
+        None => Threshold::Auto,
+        Some(Some(threshold)) => threshold,
+        Some(None) => {
+            headers.insert("Content-Type", "text/plain".parse().unwrap());
+            let reason = b"unknown threshold".to_vec();
+            return (StatusCode::BAD_REQUEST, headers, reason);
+        }
+    };
+
+    let verify = match options
+        .verify
+        .as_deref()
+        .map(signing::verifying_key_from_hex)
+    {
+        None => None,
+        Some(Ok(key)) => Some(key),
+        Some(Err(e)) => {
+            headers.insert("Content-Type", "text/plain".parse().unwrap());
+            return (StatusCode::BAD_REQUEST, headers, e.to_string().into_bytes());
+        }
+    };
+
+    // Locating finders and thresholding a large image take a while, keep them off the
+    // runtime's workers.
+    let (finders, quiet) = (options.finders, options.quiet);
+    let report = tokio::task::spawn_blocking(move || {
+        let metadata = EncodingParams::from_png(&body).and_then(|params| params.sampling());
+        let sampling = match (finders, quiet, metadata) {
+            (Some(true), _, _) => Sampling::Finders(Layout::new(256)),
+            (_, Some(quiet), _) => Sampling::Layout(Layout::new(256).with_quiet(quiet)),
+            (_, _, Some(sampling)) => sampling,
+            _ => Sampling::Exact,
+        };
+        decode_spiral_report(&body, &DecodeLimits::default(), &sampling, &threshold)
+    })
+    .await;
+    let Ok(report) = report else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, headers, vec![]);
+    };
+    if let Ok(report) = &report {
+        // Let clients tell a clean decode from a damaged one without parsing the payload.
+        let reading = &report.reading;
+        headers.insert("X-Decode-Ambiguous", reading.ambiguous.len().into());
+        headers.insert("X-Decode-Empty", reading.empty.len().into());
+        let confidence = format!("{:.3}", reading.min_confidence());
+        headers.insert("X-Decode-Confidence", confidence.parse().unwrap());
+    }
+
+    let decoded = report.map_err(|e| e.to_string()).and_then(|report| {
+        report
+            .payload
+            .ok_or_else(|| String::from("recovered sequence is not valid hex"))
+    });
+    // When a key is given, anything not signed by it is refused rather than flagged.
+    let params = signing::encoding_params("spiral", 256);
+    let decoded = match (decoded, &verify) {
+        (Ok(bytes), Some(key)) => match signing::verify(&bytes, &params, key) {
+            Ok(payload) => {
+                headers.insert("X-Signature", "valid".parse().unwrap());
+                Ok(payload)
+            }
+            Err(e) => {
+                headers.insert("X-Signature", "invalid".parse().unwrap());
+                headers.insert("Content-Type", "text/plain".parse().unwrap());
+                return (StatusCode::FORBIDDEN, headers, e.to_string().into_bytes());
+            }
+        },
+        (Ok(bytes), None) => match Signed::from_bytes(&bytes) {
+            Some(signed) => {
+                headers.insert("X-Signature", "unverified".parse().unwrap());
+                Ok(signed.payload)
+            }
+            None => Ok(bytes),
+        },
+        (Err(e), _) => Err(e),
+    };
+    let decoded = decoded.and_then(|bytes| {
+        Pipeline::from_manifest(&bytes, stage_by_name)
+            .and_then(|pipeline| pipeline.decode(&bytes))
+            .map_err(|e| e.to_string())
+    });
+
+    match decoded {
+        Ok(bytes) => {
+            headers.insert("Content-Type", "application/octet-stream".parse().unwrap());
+            (StatusCode::OK, headers, bytes)
+        }
+        Err(e) => {
+            headers.insert("Content-Type", "text/plain".parse().unwrap());
+            (StatusCode::UNPROCESSABLE_ENTITY, headers, e.into_bytes())
+        }
+    }
 }
@@ -0,0 +1,318 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Deserializer};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+use crate::net::{Listener, Peer, Stream};
+
+/// What a long running ximp holds its connections to. The `[limits]` table of a config
+/// file, with times in seconds.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_connections: usize,
+    /// Connections that carry nothing either way for this long are closed.
+    #[serde(deserialize_with = "in_seconds")]
+    pub idle_timeout: Duration,
+    /// Largest request body the transcribe server reads.
+    pub body_limit: usize,
+    /// How long open connections get to finish after a shutdown signal.
+    #[serde(deserialize_with = "in_seconds")]
+    pub drain_timeout: Duration,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_connections: 1024,
+            idle_timeout: Duration::from_secs(300),
+            body_limit: 1 << 20,
+            drain_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A positive number of seconds as a duration.
+pub fn seconds(secs: f64) -> Result<Duration, String> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Ok(duration),
+        _ => Err(format!("{} is not a positive number of seconds", secs)),
+    }
+}
+
+fn in_seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    seconds(f64::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+/**
+ * The connections of one ximp process, across all its listeners. Accepting waits for a
+ * free slot once `max` are open, so excess clients queue in the listen backlog instead of
+ * being dropped. On SIGINT or SIGTERM every listener stops accepting and `drain` waits
+ * for the open connections to finish.
+ */
+#[derive(Clone)]
+pub struct Connections {
+    permits: Arc<Semaphore>,
+    max: u32,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl Connections {
+    pub fn new(max: usize) -> Connections {
+        let max = max.clamp(1, Semaphore::MAX_PERMITS.min(u32::MAX as usize)) as u32;
+        let (tx, shutdown) = watch::channel(false);
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            println!("Shutting down, no longer accepting connections");
+            let _ = tx.send(true);
+        });
+
+        Connections {
+            permits: Arc::new(Semaphore::new(max as usize)),
+            max,
+            shutdown,
+        }
+    }
+
+    /// The next connection along with its slot, which it holds until dropped. None once
+    /// shutting down.
+    pub async fn accept(
+        &self,
+        listener: &Listener,
+    ) -> Option<(Stream, Peer, OwnedSemaphorePermit)> {
+        let permit = tokio::select! {
+            permit = self.permits.clone().acquire_owned() => permit.ok()?,
+            _ = self.shutting_down() => return None,
+        };
+
+        loop {
+            tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, peer)) => return Some((stream, peer, permit)),
+                    Err(e) => {
+                        // Out of file descriptors, most likely. Give connections a moment
+                        // to close rather than spinning on the error.
+                        eprintln!("Failed to accept: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                },
+                _ = self.shutting_down() => return None,
+            }
+        }
+    }
+
+    /// Resolves once a shutdown signal has arrived.
+    pub async fn shutting_down(&self) {
+        let mut shutdown = self.shutdown.clone();
+        let _ = shutdown.wait_for(|&down| down).await;
+    }
+
+    /// Wait for every open connection to close, for at most `timeout`.
+    pub async fn drain(&self, timeout: Duration) {
+        let open = self.max as usize - self.permits.available_permits();
+        if open == 0 {
+            return;
+        }
+
+        println!("Waiting for {} open connections", open);
+        let all = self.permits.acquire_many(self.max);
+        if tokio::time::timeout(timeout, all).await.is_err() {
+            let open = self.max as usize - self.permits.available_permits();
+            eprintln!(
+                "{} connections still open after {:.1}s, closing them",
+                open,
+                timeout.as_secs_f64()
+            );
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+            return;
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// When a connection last carried any data, shared between its stream and a watchdog.
+#[derive(Clone)]
+pub struct Activity {
+    since: Instant,
+    /// Milliseconds after `since`.
+    last: Arc<AtomicU64>,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            since: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn touch(&self) {
+        let now = self.since.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    /// Resolves once nothing has been read or written for `timeout`.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let quiet = self.since.elapsed().saturating_sub(last);
+            if quiet >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - quiet).await;
+        }
+    }
+}
+
+/// A stream that records its reads and writes in an `Activity`.
+pub struct Tracked<S> {
+    inner: S,
+    activity: Activity,
+}
+
+impl<S> Tracked<S> {
+    pub fn new(inner: S, activity: Activity) -> Tracked<S> {
+        Tracked { inner, activity }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() > before {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    async fn listener() -> (Listener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (Listener::Tcp(listener), addr)
+    }
+
+    #[tokio::test]
+    async fn holds_connections_at_max() {
+        let (listener, addr) = listener().await;
+        let connections = Connections::new(1);
+        let _first = TcpStream::connect(addr).await.unwrap();
+        let _second = TcpStream::connect(addr).await.unwrap();
+
+        let (_, _, permit) = connections.accept(&listener).await.unwrap();
+        let waiting =
+            tokio::time::timeout(Duration::from_millis(200), connections.accept(&listener));
+        assert!(waiting.await.is_err());
+
+        // The second is still in the backlog, and gets its turn once the first closes.
+        drop(permit);
+        let next = tokio::time::timeout(Duration::from_secs(5), connections.accept(&listener));
+        assert!(next.await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn drains_open_connections_for_at_most_the_timeout() {
+        let connections = Connections::new(4);
+        let started = Instant::now();
+        connections.drain(Duration::from_secs(10)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let stuck = connections.permits.clone().acquire_owned().await.unwrap();
+        let started = Instant::now();
+        connections.drain(Duration::from_millis(200)).await;
+        let waited = started.elapsed();
+        assert!(waited >= Duration::from_millis(200));
+        assert!(waited < Duration::from_secs(2));
+
+        // A connection closing ends the wait early.
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(stuck);
+        });
+        let started = Instant::now();
+        connections.drain(Duration::from_secs(10)).await;
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn tracks_activity_until_idle() {
+        let activity = Activity::new();
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut tracked = Tracked::new(server, activity.clone());
+
+        let idle = Duration::from_millis(300);
+        let started = Instant::now();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        tracked.read_exact(&mut buf).await.unwrap();
+
+        // The read pushed the deadline back.
+        activity.idle(idle).await;
+        assert!(started.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn takes_only_positive_seconds() {
+        assert_eq!(seconds(1.5), Ok(Duration::from_millis(1500)));
+        for bad in [0., -1., f64::NAN, f64::INFINITY] {
+            assert!(seconds(bad).is_err(), "{}", bad);
+        }
+    }
+}
@@ -1,17 +1,38 @@
+mod capture;
+mod config;
+mod http_prox;
 mod http_svc;
+mod limits;
+mod net;
+mod pool;
+mod proxy_protocol;
 mod tcp_prox;
+mod tls;
+mod tunnel;
+mod udp_prox;
 
 extern crate getopts;
 use getopts::Options;
 use std::env::args;
 use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use http_svc::http_svc as transcribe;
-use tcp_prox::prox as proxy;
+use chacha20poly1305::XChaCha20Poly1305;
+use tokio_rustls::TlsAcceptor;
+
+use config::{Config, Route, Transcribe, Transport};
+use http_prox::{http_prox, Imaging};
+use http_svc::transcribe;
+use limits::{Connections, Limits};
+use pool::Balance;
+use proxy_protocol::Version;
+use tcp_prox::{prox as proxy, Proxies};
+use tunnel::{tunnel_client, tunnel_server};
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} FILE [options]", program);
-    opts.usage(&brief);
+    print!("{}", opts.usage(&brief));
 }
 
 #[tokio::main]
@@ -25,7 +46,154 @@ async fn main() -> Result<(), Box<dyn Error>> {
         "m",
         "mode",
         "Mode in which to run ximp",
-        "[proxy, transcribe]",
+        "[transcribe, proxy, all, http-proxy, tunnel-client, tunnel-server, replay]",
+    );
+    opts.optmulti(
+        "l",
+        "listen",
+        "Address to listen on, repeated for several proxy routes. unix:PATH for a Unix \
+         socket",
+        "ADDR",
+    );
+    opts.optmulti(
+        "u",
+        "upstream",
+        "Where to forward to, paired with the --listen in the same position. Proxy \
+         routes take a comma separated pool",
+        "ADDR[,ADDR...]",
+    );
+    opts.optopt(
+        "",
+        "balance",
+        "How proxy routes pick from their pool",
+        "[round-robin, least-connections]",
+    );
+    opts.optflag(
+        "",
+        "accept-proxy",
+        "Proxy clients start with a PROXY protocol header",
+    );
+    opts.optopt(
+        "",
+        "send-proxy",
+        "Start proxy upstream connections with a PROXY protocol header",
+        "[v1, v2]",
+    );
+    opts.optopt(
+        "",
+        "connect-timeout",
+        "Proxy upstream connect timeout",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "health-interval",
+        "Time between upstream health checks",
+        "SECS",
+    );
+    opts.optflag("", "udp", "Proxy routes relay UDP datagrams instead of TCP");
+    opts.optopt(
+        "",
+        "inspect",
+        "Write every datagram UDP routes relay to DIR as a spiral PNG",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "capture",
+        "Record proxied TCP connections to DIR as spiral PNGs, or the capture to replay",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "capture-interval",
+        "Time between writes of partly filled capture pages (5)",
+        "SECS",
+    );
+    opts.optopt(
+        "o",
+        "out",
+        "Where replay writes the recorded streams, the capture by default",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "tls-cert",
+        "Serve TLS with this PEM certificate chain",
+        "FILE",
+    );
+    opts.optopt("", "tls-key", "Private key for --tls-cert, PEM", "FILE");
+    opts.optopt(
+        "",
+        "tls-client-ca",
+        "Only accept TLS clients with a certificate from these CAs",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "upstream-ca",
+        "Proxy to upstreams over TLS, trusting these CAs",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "upstream-name",
+        "Name upstream certificates must have, their host by default",
+        "NAME",
+    );
+    opts.optopt(
+        "c",
+        "config",
+        "Transcribe servers, proxy routes and limits from a TOML file. Without a mode, \
+         all of them are run",
+        "FILE",
+    );
+    opts.optopt(
+        "k",
+        "key",
+        "Pre-shared tunnel key, 32 bytes in hex",
+        "KEYFILE",
+    );
+    opts.optopt(
+        "",
+        "image-types",
+        "Response types the HTTP proxy turns into images (text/plain,application/json)",
+        "TYPE[,TYPE...]",
+    );
+    opts.optopt(
+        "",
+        "compress",
+        "Compress HTTP proxy responses before imaging them",
+        "ALGORITHM",
+    );
+    opts.optflag(
+        "",
+        "decode-uploads",
+        "Decode spiral PNG request bodies before the HTTP proxy forwards them",
+    );
+    opts.optopt(
+        "",
+        "max-connections",
+        "Connections open at once, more wait to be accepted (1024)",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "idle-timeout",
+        "Close connections idle for this long (300)",
+        "SECS",
+    );
+    opts.optopt(
+        "",
+        "body-limit",
+        "Largest request body transcribe reads (1048576)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "drain-timeout",
+        "How long open connections get to finish on SIGINT or SIGTERM (30)",
+        "SECS",
     );
     opts.optflag("h", "help", "Show options");
 
@@ -39,16 +207,73 @@ async fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    match m
-        .opt_str("m")
-        .unwrap_or(String::from("transcribe"))
-        .as_str()
-    {
+    let config = match m.opt_str("c") {
+        Some(path) => Config::from_path(&path)?,
+        None => Config::default(),
+    };
+    let limits = connection_limits(&m, config.limits)?;
+    let mode = match m.opt_str("m") {
+        Some(mode) => mode,
+        None if m.opt_present("c") => String::from("all"),
+        None => String::from("transcribe"),
+    };
+
+    match mode.as_str() {
         "transcribe" => {
-            transcribe().await;
+            let listen = m.opt_str("l").unwrap_or(String::from("0.0.0.0:8080"));
+            let mut server = Transcribe::new(&listen);
+            server.tls_cert = m.opt_str("tls-cert").map(PathBuf::from);
+            server.tls_key = m.opt_str("tls-key").map(PathBuf::from);
+            server.tls_client_ca = m.opt_str("tls-client-ca").map(PathBuf::from);
+            serve_all(vec![server], vec![], limits).await?;
         }
         "proxy" => {
-            proxy().await?;
+            let mut routes = proxy_routes(&m, config.routes)?;
+            if routes.is_empty() {
+                let fallback = Route::new("127.0.0.1:8081", "127.0.0.1:8080");
+                routes = proxy_routes(&m, vec![fallback])?;
+            }
+            proxy(routes, limits).await?;
+        }
+        "all" => {
+            let routes = proxy_routes(&m, config.routes)?;
+            if config.transcribe.is_empty() && routes.is_empty() {
+                return Err("nothing to run, no [[transcribe]] or [[route]] in the config".into());
+            }
+            serve_all(config.transcribe, routes, limits).await?;
+        }
+        "http-proxy" => {
+            let listen = m.opt_str("l").unwrap_or(String::from("127.0.0.1:8081"));
+            let upstream = m.opt_str("u").unwrap_or(String::from("127.0.0.1:8080"));
+            let mut imaging = Imaging::default();
+            if let Some(types) = m.opt_str("image-types") {
+                imaging.types = types
+                    .split(',')
+                    .map(|media| media.trim().to_ascii_lowercase())
+                    .filter(|media| !media.is_empty())
+                    .collect();
+            }
+            imaging.stages = m.opt_str("compress").into_iter().collect();
+            imaging.decode_uploads = m.opt_present("decode-uploads");
+            let tls = server_tls(&m)?;
+            http_prox(&listen, &upstream, imaging, limits, tls).await?;
+        }
+        "tunnel-client" => {
+            let listen = m.opt_str("l").unwrap_or(String::from("127.0.0.1:8081"));
+            let upstream = m.opt_str("u").unwrap_or(String::from("127.0.0.1:8082"));
+            tunnel_client(&listen, &upstream, tunnel_key(&m)?, limits).await?;
+        }
+        "tunnel-server" => {
+            let listen = m.opt_str("l").unwrap_or(String::from("127.0.0.1:8082"));
+            let upstream = m.opt_str("u").unwrap_or(String::from("127.0.0.1:8080"));
+            tunnel_server(&listen, &upstream, tunnel_key(&m)?, limits).await?;
+        }
+        "replay" => {
+            let capture = m
+                .opt_str("capture")
+                .ok_or("replay needs the --capture to decode")?;
+            let out = m.opt_str("o").unwrap_or(capture.clone());
+            capture::replay(Path::new(&capture), Path::new(&out))?;
         }
         m => {
             panic!("Unknown ximp mode {}", m);
@@ -57,3 +282,113 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+fn tunnel_key(m: &getopts::Matches) -> Result<Arc<XChaCha20Poly1305>, Box<dyn Error>> {
+    let path = m
+        .opt_str("k")
+        .ok_or("tunnel modes need a pre-shared --key")?;
+    tunnel::read_key(&path)
+}
+
+/// Transcribe servers and proxy routes together, sharing the connection limit and
+/// shutdown. Everything binds before anything is served.
+async fn serve_all(
+    servers: Vec<Transcribe>,
+    routes: Vec<Route>,
+    limits: Limits,
+) -> Result<(), Box<dyn Error>> {
+    let connections = Connections::new(limits.max_connections);
+    let mut running = vec![];
+    for server in &servers {
+        running.push(transcribe(server, limits, connections.clone()).await?);
+    }
+    let proxies = Proxies::bind(routes, limits, connections.clone()).await?;
+
+    // A server that failed shouldn't cost the proxies their drain or captures their
+    // last pages, so everything is closed before any failure is reported.
+    let stopped = futures::future::join_all(running).await;
+    proxies.close(&connections, limits.drain_timeout).await?;
+    for server in stopped {
+        server?;
+    }
+    Ok(())
+}
+
+fn server_tls(m: &getopts::Matches) -> Result<Option<TlsAcceptor>, Box<dyn Error>> {
+    let path = |name| m.opt_str(name).map(PathBuf::from);
+    tls::acceptor(
+        path("tls-cert").as_deref(),
+        path("tls-key").as_deref(),
+        path("tls-client-ca").as_deref(),
+    )
+}
+
+/// `routes` from the config file, then one for every `--listen` and `--upstream` pair.
+fn proxy_routes(
+    m: &getopts::Matches,
+    mut routes: Vec<Route>,
+) -> Result<Vec<Route>, Box<dyn Error>> {
+    let listen = m.opt_strs("l");
+    let upstream = m.opt_strs("u");
+    if listen.len() != upstream.len() {
+        return Err(format!(
+            "--listen and --upstream go in pairs, got {} and {}",
+            listen.len(),
+            upstream.len()
+        )
+        .into());
+    }
+    routes.extend(listen.iter().zip(&upstream).map(|(l, u)| Route::new(l, u)));
+
+    // Options apply to every route that doesn't set its own in the config file.
+    let balance = match m.opt_str("balance") {
+        Some(name) => Some(Balance::from_name(&name).ok_or("unknown balancing method")?),
+        None => None,
+    };
+    let connect_timeout = m.opt_get::<f64>("connect-timeout")?;
+    let health_interval = m.opt_get::<f64>("health-interval")?;
+    let accept_proxy = m.opt_present("accept-proxy").then_some(true);
+    let send_proxy = match m.opt_str("send-proxy") {
+        Some(name) => Some(Version::from_name(&name).ok_or("unknown PROXY protocol version")?),
+        None => None,
+    };
+    let transport = m.opt_present("udp").then_some(Transport::Udp);
+    let inspect = m.opt_str("inspect").map(PathBuf::from);
+    let capture = m.opt_str("capture").map(PathBuf::from);
+    let capture_interval = m.opt_get::<f64>("capture-interval")?;
+    let path = |name| m.opt_str(name).map(PathBuf::from);
+    for route in &mut routes {
+        route.transport = route.transport.or(transport);
+        route.inspect = route.inspect.take().or(inspect.clone());
+        route.capture = route.capture.take().or(capture.clone());
+        route.capture_interval = route.capture_interval.or(capture_interval);
+        route.tls_cert = route.tls_cert.take().or(path("tls-cert"));
+        route.tls_key = route.tls_key.take().or(path("tls-key"));
+        route.tls_client_ca = route.tls_client_ca.take().or(path("tls-client-ca"));
+        route.upstream_ca = route.upstream_ca.take().or(path("upstream-ca"));
+        route.upstream_name = route.upstream_name.take().or(m.opt_str("upstream-name"));
+        route.accept_proxy = route.accept_proxy.or(accept_proxy);
+        route.send_proxy = route.send_proxy.or(send_proxy);
+        route.balance = route.balance.or(balance);
+        route.connect_timeout = route.connect_timeout.or(connect_timeout);
+        route.health_interval = route.health_interval.or(health_interval);
+    }
+    Ok(routes)
+}
+
+/// The config file's limits, with any given as options instead.
+fn connection_limits(m: &getopts::Matches, mut limits: Limits) -> Result<Limits, Box<dyn Error>> {
+    if let Some(max) = m.opt_get::<usize>("max-connections")? {
+        limits.max_connections = max;
+    }
+    if let Some(secs) = m.opt_get::<f64>("idle-timeout")? {
+        limits.idle_timeout = limits::seconds(secs)?;
+    }
+    if let Some(bytes) = m.opt_get::<usize>("body-limit")? {
+        limits.body_limit = bytes;
+    }
+    if let Some(secs) = m.opt_get::<f64>("drain-timeout")? {
+        limits.drain_timeout = limits::seconds(secs)?;
+    }
+    Ok(limits)
+}
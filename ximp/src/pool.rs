@@ -0,0 +1,268 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::time::timeout;
+
+use crate::net::{Addr, Stream};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Balance {
+    /// Backends take turns.
+    #[default]
+    RoundRobin,
+    /// The backend with the fewest open connections, taking turns among equals.
+    LeastConnections,
+}
+
+impl Balance {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "round-robin" => Some(Balance::RoundRobin),
+            "least-connections" => Some(Balance::LeastConnections),
+            _ => None,
+        }
+    }
+}
+
+struct Backend {
+    addr: Addr,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+}
+
+/// Holds a backend's connection count up while a proxied connection is open.
+pub struct Lease {
+    pool: Arc<Pool>,
+    index: usize,
+}
+
+impl Lease {
+    pub fn addr(&self) -> &Addr {
+        &self.pool.backends[self.index].addr
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.pool.backends[self.index]
+            .active
+            .fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/**
+ * The backends one route balances over. Backends start out healthy, go down when a
+ * connection or health check fails, and come back once a health check gets through.
+ * Connections are only tried against healthy backends, falling back to the next one
+ * on failure; when all of them are down, every backend is tried anyway rather than
+ * refusing outright on possibly stale checks.
+ */
+pub struct Pool {
+    /// Route name, for the logs.
+    name: String,
+    backends: Vec<Backend>,
+    balance: Balance,
+    connect_timeout: Duration,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    pub fn new(name: &str, addrs: &[String], balance: Balance, connect_timeout: Duration) -> Pool {
+        Pool {
+            name: name.to_string(),
+            backends: addrs
+                .iter()
+                .map(|addr| Backend {
+                    addr: Addr::parse(addr),
+                    healthy: AtomicBool::new(true),
+                    active: AtomicUsize::new(0),
+                })
+                .collect(),
+            balance,
+            connect_timeout,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// How long a connection attempt gets, handshakes on top of it get as long.
+    pub fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    /// Connect to the first backend that answers, in balancing order.
+    pub async fn connect(self: &Arc<Self>) -> io::Result<(Stream, Lease)> {
+        let mut last = None;
+        for index in self.candidates() {
+            let backend = &self.backends[index];
+            match self.try_connect(&backend.addr).await {
+                Ok(stream) => {
+                    self.mark(index, true);
+                    backend.active.fetch_add(1, Ordering::Relaxed);
+                    let lease = Lease {
+                        pool: self.clone(),
+                        index,
+                    };
+                    return Ok((stream, lease));
+                }
+                Err(e) => {
+                    eprintln!("[{}] can't reach {}: {}", self.name, backend.addr, e);
+                    self.mark(index, false);
+                    last = Some(e);
+                }
+            }
+        }
+
+        Err(last.unwrap_or_else(|| io::Error::other("no upstreams")))
+    }
+
+    /// Probe every backend with a TCP connect, forever, `interval` apart.
+    pub async fn check(self: Arc<Self>, interval: Duration) {
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            for (index, backend) in self.backends.iter().enumerate() {
+                let up = self.try_connect(&backend.addr).await.is_ok();
+                self.mark(index, up);
+            }
+        }
+    }
+
+    /// Indices of the backends to try, best first.
+    fn candidates(&self) -> Vec<usize> {
+        let mut healthy: Vec<usize> = (0..self.backends.len())
+            .filter(|&i| self.backends[i].healthy.load(Ordering::Relaxed))
+            .collect();
+        if healthy.is_empty() {
+            healthy = (0..self.backends.len()).collect();
+        }
+
+        // Turns go round the healthy backends only, so the one after a dead backend
+        // doesn't get its share as well.
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+        healthy.rotate_left(start);
+
+        if self.balance == Balance::LeastConnections {
+            // Stable, so equally loaded backends still take turns.
+            healthy.sort_by_key(|&i| self.backends[i].active.load(Ordering::Relaxed));
+        }
+        healthy
+    }
+
+    async fn try_connect(&self, addr: &Addr) -> io::Result<Stream> {
+        timeout(self.connect_timeout, Stream::connect(addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?
+    }
+
+    fn mark(&self, index: usize, healthy: bool) {
+        let backend = &self.backends[index];
+        if backend.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+            let state = if healthy { "up" } else { "down" };
+            println!("[{}] upstream {} is {}", self.name, backend.addr, state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// A listener that accepts and holds on to connections, and its address.
+    async fn backend() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let mut open = vec![];
+            while let Ok((stream, _)) = listener.accept().await {
+                open.push(stream);
+            }
+        });
+        addr
+    }
+
+    /// An address nothing listens on.
+    async fn dead() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    fn balanced(addrs: &[String], balance: Balance) -> Arc<Pool> {
+        Arc::new(Pool::new("test", addrs, balance, Duration::from_secs(2)))
+    }
+
+    async fn connected(pool: &Arc<Pool>) -> Lease {
+        pool.connect().await.unwrap().1
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_healthy_backends() {
+        let (down, up) = (dead().await, backend().await);
+        let pool = balanced(&[down, up.clone()], Balance::RoundRobin);
+
+        // Whichever comes first in turn, the connection ends up at the live one.
+        for _ in 0..4 {
+            assert_eq!(connected(&pool).await.addr().to_string(), up);
+        }
+        assert!(!pool.backends[0].healthy.load(Ordering::Relaxed));
+        assert!(pool.backends[1].healthy.load(Ordering::Relaxed));
+        assert_eq!(pool.candidates(), vec![1]);
+
+        // With everything down, each is tried anyway.
+        let stranded = balanced(&[dead().await, dead().await], Balance::RoundRobin);
+        assert!(stranded.connect().await.is_err());
+        assert_eq!(stranded.candidates().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn picks_the_least_connected_backend() {
+        let addrs = [backend().await, backend().await, backend().await];
+        let pool = balanced(&addrs, Balance::LeastConnections);
+
+        let first = connected(&pool).await;
+        let second = connected(&pool).await;
+        let third = connected(&pool).await;
+        let mut used: Vec<String> = [&first, &second, &third]
+            .iter()
+            .map(|lease| lease.addr().to_string())
+            .collect();
+        used.sort();
+        let mut all = addrs.to_vec();
+        all.sort();
+        assert_eq!(used, all);
+
+        // Only the backend whose connection closed has a free slot.
+        let freed = second.addr().to_string();
+        drop(second);
+        let next = connected(&pool).await;
+        assert_eq!(next.addr().to_string(), freed);
+        assert_eq!(
+            pool.backends
+                .iter()
+                .map(|backend| backend.active.load(Ordering::Relaxed))
+                .collect::<Vec<_>>(),
+            vec![1, 1, 1]
+        );
+
+        drop((first, third, next));
+        assert!(pool
+            .backends
+            .iter()
+            .all(|backend| backend.active.load(Ordering::Relaxed) == 0));
+    }
+
+    #[test]
+    fn parses_balance_names() {
+        assert_eq!(Balance::from_name("Round-Robin"), Some(Balance::RoundRobin));
+        assert_eq!(
+            Balance::from_name("least-connections"),
+            Some(Balance::LeastConnections)
+        );
+        assert_eq!(Balance::from_name("random"), None);
+    }
+}
@@ -0,0 +1,316 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Leading bytes of a version 2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest version 1 header, including its CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// HAProxy PROXY protocol versions, 1 is text and 2 binary.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "v1" | "1" => Some(Version::V1),
+            "v2" | "2" => Some(Version::V2),
+            _ => None,
+        }
+    }
+}
+
+/// The connection a PROXY header describes, as the sender saw it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Addresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("PROXY header: {}", reason),
+    )
+}
+
+/**
+ * Read the PROXY header, either version, off the start of a connection and nothing
+ * past it. None for headers without addresses: `UNKNOWN`, `LOCAL`, or ones for
+ * families other than TCP and UDP over IP. A connection without a header is an error,
+ * listeners that expect one should never pass on what a client claims.
+ */
+pub async fn read_header<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Addresses>> {
+    let mut start = [0u8; 12];
+    reader.read_exact(&mut start).await?;
+
+    if start == *V2_SIGNATURE {
+        let mut fixed = [0u8; 4];
+        reader.read_exact(&mut fixed).await?;
+        let len = u16::from_be_bytes([fixed[2], fixed[3]]) as usize;
+        let mut rest = vec![0u8; len];
+        reader.read_exact(&mut rest).await?;
+        return parse_v2(fixed[0], fixed[1], &rest);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("missing"));
+    }
+    // Byte at a time, so nothing after the CRLF is consumed.
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("line too long"));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<Addresses>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, sport, dport] => {
+            let ip = |text: &str| text.parse::<IpAddr>().map_err(|_| invalid("bad address"));
+            let port = |text: &str| text.parse::<u16>().map_err(|_| invalid("bad port"));
+            let (source, destination) = (ip(source)?, ip(destination)?);
+            if source.is_ipv4() != (*family == "TCP4") || destination.is_ipv4() != source.is_ipv4()
+            {
+                return Err(invalid("address family mismatch"));
+            }
+
+            Ok(Some(Addresses {
+                source: SocketAddr::new(source, port(sport)?),
+                destination: SocketAddr::new(destination, port(dport)?),
+            }))
+        }
+        _ => Err(invalid("malformed")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, rest: &[u8]) -> io::Result<Option<Addresses>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, the sender's own connection, such as a health check.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+
+    // The high nibble is the address family, the low one TCP or UDP.
+    let (source, destination, ports) = match family >> 4 {
+        1 if rest.len() >= 12 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 4]>::try_from(&rest[at..at + 4]).unwrap());
+            (ip(0), ip(4), &rest[8..12])
+        }
+        2 if rest.len() >= 36 => {
+            let ip = |at: usize| IpAddr::from(<[u8; 16]>::try_from(&rest[at..at + 16]).unwrap());
+            (ip(0), ip(16), &rest[32..36])
+        }
+        1 | 2 => return Err(invalid("truncated addresses")),
+        // Unspecified or Unix addresses, nothing to report.
+        _ => return Ok(None),
+    };
+
+    Ok(Some(Addresses {
+        source: SocketAddr::new(source, u16::from_be_bytes([ports[0], ports[1]])),
+        destination: SocketAddr::new(destination, u16::from_be_bytes([ports[2], ports[3]])),
+    }))
+}
+
+/// A header announcing `addresses`, or one telling the receiver to use the connection's
+/// own when there are none to pass on.
+pub fn header(version: Version, addresses: Option<Addresses>) -> Vec<u8> {
+    // Both ends have to be the same family, IPv4 goes over as mapped IPv6 if need be.
+    let addresses = addresses.map(|addresses| {
+        let (source, destination) = (addresses.source, addresses.destination);
+        if source.is_ipv4() == destination.is_ipv4() {
+            (source, destination)
+        } else {
+            (mapped(source), mapped(destination))
+        }
+    });
+
+    match version {
+        Version::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut out = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY.
+            out.push(0x21);
+            let mut body = vec![];
+            let family = match addresses {
+                Some((source, destination)) => {
+                    for addr in [source, destination] {
+                        match addr.ip() {
+                            IpAddr::V4(ip) => body.extend(ip.octets()),
+                            IpAddr::V6(ip) => body.extend(ip.octets()),
+                        }
+                    }
+                    body.extend(source.port().to_be_bytes());
+                    body.extend(destination.port().to_be_bytes());
+                    // TCP over IPv4 or IPv6.
+                    if source.is_ipv4() {
+                        0x11
+                    } else {
+                        0x21
+                    }
+                }
+                None => 0x00,
+            };
+            out.push(family);
+            out.extend((body.len() as u16).to_be_bytes());
+            out.extend(body);
+            out
+        }
+    }
+}
+
+fn mapped(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut bytes: &[u8]) -> (io::Result<Option<Addresses>>, Vec<u8>) {
+        let parsed = read_header(&mut bytes).await;
+        (parsed, bytes.to_vec())
+    }
+
+    fn addresses(source: &str, destination: &str) -> Addresses {
+        Addresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips_both_versions() {
+        let v4 = addresses("192.0.2.7:51234", "198.51.100.1:443");
+        let v6 = addresses("[2001:db8::7]:51234", "[2001:db8::1]:443");
+        let mixed = addresses("192.0.2.7:51234", "[2001:db8::1]:443");
+
+        for version in [Version::V1, Version::V2] {
+            for sent in [v4, v6] {
+                let mut stream = header(version, Some(sent));
+                stream.extend(b"GET / HTTP/1.1\r\n");
+                let (parsed, rest) = read(&stream).await;
+                assert_eq!(parsed.unwrap(), Some(sent));
+                assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+            }
+
+            let (parsed, _) = read(&header(version, Some(mixed))).await;
+            let parsed = parsed.unwrap().unwrap();
+            assert_eq!(parsed.source, mapped(mixed.source));
+            assert_eq!(parsed.destination, mixed.destination);
+
+            let (parsed, rest) = read(&header(version, None)).await;
+            assert_eq!(parsed.unwrap(), None);
+            assert!(rest.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_truncated_headers() {
+        let sent = addresses("[2001:db8::7]:51234", "[2001:db8::1]:443");
+        for version in [Version::V1, Version::V2] {
+            let whole = header(version, Some(sent));
+            for len in 0..whole.len() {
+                assert!(
+                    read(&whole[..len]).await.0.is_err(),
+                    "{:?} {}",
+                    version,
+                    len
+                );
+            }
+        }
+
+        // A version 2 header whose length doesn't cover its addresses.
+        let mut short = header(Version::V2, Some(sent));
+        short[14..16].copy_from_slice(&12u16.to_be_bytes());
+        assert!(read(&short).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_overlong_and_malformed_headers() {
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.extend([b'1'; 200]);
+        long.extend(b"\r\n");
+        assert!(read(&long).await.0.is_err());
+
+        for line in [
+            &b"GET / HTTP/1.1\r\n\r\n"[..],
+            b"PROXY TCP4 192.0.2.7 198.51.100.1 51234\r\n",
+            b"PROXY TCP4 192.0.2.7 198.51.100.1 51234 99999\r\n",
+            b"PROXY TCP4 2001:db8::7 2001:db8::1 51234 443\r\n",
+            b"PROXY TCP6 192.0.2.7 2001:db8::1 51234 443\r\n",
+            b"PROXY UDP4 192.0.2.7 198.51.100.1 51234 443\r\n",
+            b"PROXY TCP4 192.0.2.\xff 198.51.100.1 51234 443\r\n",
+        ] {
+            assert!(
+                read(line).await.0.is_err(),
+                "{:?}",
+                String::from_utf8_lossy(line)
+            );
+        }
+
+        let v2 = header(
+            Version::V2,
+            Some(addresses("192.0.2.7:1", "198.51.100.1:2")),
+        );
+        for (at, value) in [(12, 0x11), (12, 0x2f)] {
+            let mut bad = v2.clone();
+            bad[at] = value;
+            assert!(read(&bad).await.0.is_err());
+        }
+        // Unix addresses carry nothing to report, and are skipped over whole.
+        let mut unix = v2.clone();
+        unix[13] = 0x31;
+        assert_eq!(read(&unix).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn survives_garbage() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        };
+
+        for prefix in [&b"PROXY "[..], &V2_SIGNATURE[..]] {
+            for len in 0..200 {
+                let mut bytes = prefix.to_vec();
+                bytes.extend((0..len).map(|_| next()));
+                let _ = read(&bytes).await;
+            }
+        }
+    }
+}
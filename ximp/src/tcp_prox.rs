@@ -1,36 +1,375 @@
-use std::env;
+use std::collections::HashMap;
 use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use futures::FutureExt;
-use tokio::io::copy_bidirectional;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{copy_bidirectional, AsyncWriteExt};
+use tokio::task::{JoinError, JoinHandle};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
-pub async fn prox() -> Result<(), Box<dyn Error>> {
-    let listen_addr = env::args()
-        .nth(1)
-        .unwrap_or_else(|| "127.0.0.1:8081".to_string());
-    let server_addr = env::args()
-        .nth(2)
-        .unwrap_or_else(|| "127.0.0.1:8080".to_string());
+use crate::capture::{Capture, Direction, Recorded};
+use crate::config::{Route, Transport};
+use crate::limits::{self, Activity, Connections, Limits, Tracked};
+use crate::net::{Addr, Listener, Peer, Stream};
+use crate::pool::Pool;
+use crate::proxy_protocol::{self, Addresses, Version};
+use crate::tls;
+use crate::udp_prox::Relay;
 
-    println!("Listening on: {}", listen_addr);
-    println!("Proxying to: {}", server_addr);
+const CONNECT_TIMEOUT: f64 = 3.0;
+const HEALTH_INTERVAL: f64 = 5.0;
+const CAPTURE_INTERVAL: f64 = 5.0;
 
-    let listener = TcpListener::bind(&server_addr).await?;
+/// How one route treats PROXY protocol headers.
+#[derive(Clone, Copy)]
+struct ProxyProtocol {
+    accept: bool,
+    send: Option<Version>,
+}
 
-    while let Ok((mut inbound, _)) = listener.accept().await {
-        let mut outbound = TcpStream::connect(server_addr.clone()).await?;
+/// TLS on either side of one route.
+struct Tls {
+    accept: Option<TlsAcceptor>,
+    connect: Option<TlsConnector>,
+    upstream_name: Option<String>,
+}
 
-        tokio::spawn(async move {
-            copy_bidirectional(&mut inbound, &mut outbound)
-                .map(|r| {
-                    if let Err(e) = r {
-                        eprintln!("Failed to proxy with error {}", e);
-                    }
-                })
+/// What the connections of one route share.
+struct Proxy {
+    name: String,
+    pool: Arc<Pool>,
+    protocol: ProxyProtocol,
+    tls: Tls,
+    capture: Option<Arc<Capture>>,
+}
+
+/// Proxy every route from its own listener until shut down, then drain.
+pub async fn prox(routes: Vec<Route>, limits: Limits) -> Result<(), Box<dyn Error>> {
+    let connections = Connections::new(limits.max_connections);
+    let proxies = Proxies::bind(routes, limits, connections.clone()).await?;
+    proxies.close(&connections, limits.drain_timeout).await?;
+    Ok(())
+}
+
+/// Routes being proxied, each from its own listener on a task of its own.
+pub struct Proxies {
+    listeners: Vec<JoinHandle<()>>,
+    /// Routes capturing to the same directory share one capture.
+    captures: HashMap<PathBuf, Arc<Capture>>,
+}
+
+impl Proxies {
+    /// Bind every route and start proxying. UDP routes are relayed instead.
+    pub async fn bind(
+        routes: Vec<Route>,
+        limits: Limits,
+        connections: Connections,
+    ) -> Result<Proxies, Box<dyn Error>> {
+        let mut listeners = vec![];
+        let mut captures: HashMap<PathBuf, Arc<Capture>> = HashMap::new();
+        for route in routes {
+            let name = route.name().to_string();
+            if route.upstream.is_empty() {
+                return Err(format!("[{}] has no upstream", name).into());
+            }
+            let mut limits = limits;
+            if let Some(secs) = route.idle_timeout {
+                limits.idle_timeout = seconds(&name, secs)?;
+            }
+            if route.transport == Some(Transport::Udp) {
+                let relay = Relay::bind(&route, connections.clone(), limits).await?;
+                println!(
+                    "[{}] Relaying UDP {} to {}",
+                    name,
+                    route.listen,
+                    route.upstream.join(", ")
+                );
+                listeners.push(tokio::spawn(Arc::new(relay).serve()));
+                continue;
+            }
+            let connect_timeout = seconds(&name, route.connect_timeout.unwrap_or(CONNECT_TIMEOUT))?;
+            let health_interval = seconds(&name, route.health_interval.unwrap_or(HEALTH_INTERVAL))?;
+
+            // Bind them all up front so a bad address fails startup, not a route later on.
+            let listener = Listener::bind(&Addr::parse(&route.listen))
                 .await
+                .map_err(|e| format!("[{}] can't listen on {}: {}", name, route.listen, e))?;
+            println!(
+                "[{}] Proxying {} to {}",
+                name,
+                route.listen,
+                route.upstream.join(", ")
+            );
+
+            let pool = Arc::new(Pool::new(
+                &name,
+                &route.upstream,
+                route.balance.unwrap_or_default(),
+                connect_timeout,
+            ));
+            let protocol = ProxyProtocol {
+                accept: route.accept_proxy.unwrap_or(false),
+                send: route.send_proxy,
+            };
+            let capture = match &route.capture {
+                Some(dir) => match captures.get(dir) {
+                    Some(capture) => Some(capture.clone()),
+                    None => {
+                        let interval =
+                            seconds(&name, route.capture_interval.unwrap_or(CAPTURE_INTERVAL))?;
+                        let capture = Capture::start(dir, interval).map_err(|e| {
+                            format!("[{}] can't capture to {}: {}", name, dir.display(), e)
+                        })?;
+                        println!("[{}] Capturing to {}", name, dir.display());
+                        let capture = Arc::new(capture);
+                        captures.insert(dir.clone(), capture.clone());
+                        Some(capture)
+                    }
+                },
+                None => None,
+            };
+            let tls = Tls {
+                accept: tls::acceptor(
+                    route.tls_cert.as_deref(),
+                    route.tls_key.as_deref(),
+                    route.tls_client_ca.as_deref(),
+                )
+                .map_err(|e| format!("[{}] {}", name, e))?,
+                connect: match &route.upstream_ca {
+                    Some(ca) => Some(tls::connector(ca).map_err(|e| format!("[{}] {}", name, e))?),
+                    None => None,
+                },
+                upstream_name: route.upstream_name.clone(),
+            };
+            tokio::spawn(pool.clone().check(health_interval));
+            let proxy = Proxy {
+                name,
+                pool,
+                protocol,
+                tls,
+                capture,
+            };
+            listeners.push(tokio::spawn(serve(
+                listener,
+                Arc::new(proxy),
+                connections.clone(),
+                limits,
+            )));
+        }
+
+        Ok(Proxies {
+            listeners,
+            captures,
+        })
+    }
+
+    /// Wait for the listeners to stop on shutdown, then for every connection in
+    /// `connections` to finish, and write out what the captures hold.
+    pub async fn close(
+        self,
+        connections: &Connections,
+        timeout: Duration,
+    ) -> Result<(), JoinError> {
+        // Drain and finish the captures even if a listener failed, then report it.
+        let stopped = futures::future::join_all(self.listeners).await;
+        connections.drain(timeout).await;
+        for capture in self.captures.values() {
+            capture.finish().await;
+        }
+        stopped.into_iter().collect()
+    }
+}
+
+fn seconds(name: &str, secs: f64) -> Result<Duration, String> {
+    limits::seconds(secs).map_err(|e| format!("[{}] {}", name, e))
+}
+
+async fn serve(listener: Listener, proxy: Arc<Proxy>, connections: Connections, limits: Limits) {
+    while let Some((inbound, peer, permit)) = connections.accept(&listener).await {
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            let _permit = permit;
+            proxy.relay(inbound, peer, limits).await;
         });
     }
+}
 
-    Ok(())
+impl Proxy {
+    async fn relay(&self, mut inbound: Stream, peer: Peer, limits: Limits) {
+        let name = &self.name;
+        let started = Instant::now();
+
+        // The connection as the client made it, unless a load balancer says otherwise.
+        let mut client = match (peer.socket_addr(), inbound.local_addr()) {
+            (Some(source), Some(destination)) => Some(Addresses {
+                source,
+                destination,
+            }),
+            _ => None,
+        };
+        if self.protocol.accept {
+            let header = proxy_protocol::read_header(&mut inbound);
+            match tokio::time::timeout(limits.idle_timeout, header).await {
+                Ok(Ok(addresses)) => client = addresses.or(client),
+                // Closed without a word, a health check most likely.
+                Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+                Ok(Err(e)) => {
+                    eprintln!("[{}] {} dropped: {}", name, peer, e);
+                    return;
+                }
+                Err(_) => {
+                    eprintln!("[{}] {} dropped, no PROXY header", name, peer);
+                    return;
+                }
+            }
+        }
+        let from = match client {
+            Some(client) if self.protocol.accept => client.source.to_string(),
+            _ => peer.to_string(),
+        };
+        // Behind a PROXY header, which load balancers send in the clear.
+        if let Some(acceptor) = &self.tls.accept {
+            inbound = match tls::accept(acceptor, inbound, limits.idle_timeout).await {
+                Ok(inbound) => inbound,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return,
+                Err(e) => {
+                    eprintln!("[{}] {} dropped, TLS failed: {}", name, from, e);
+                    return;
+                }
+            };
+        }
+
+        let (mut outbound, lease) = match self.pool.connect().await {
+            Ok(connected) => connected,
+            Err(e) => {
+                eprintln!("[{}] {} dropped, no upstream reachable: {}", name, from, e);
+                return;
+            }
+        };
+        if let Some(version) = self.protocol.send {
+            let header = proxy_protocol::header(version, client);
+            if let Err(e) = outbound.write_all(&header).await {
+                eprintln!("[{}] {} failed: {}", name, from, e);
+                return;
+            }
+        }
+        if let Some(connector) = &self.tls.connect {
+            let upstream_name = self.tls.upstream_name.as_deref();
+            let handshake = tls::connect(
+                connector,
+                outbound,
+                lease.addr(),
+                upstream_name,
+                self.pool.connect_timeout(),
+            );
+            outbound = match handshake.await {
+                Ok(outbound) => outbound,
+                Err(e) => {
+                    eprintln!(
+                        "[{}] {} dropped, TLS to {} failed: {}",
+                        name,
+                        from,
+                        lease.addr(),
+                        e
+                    );
+                    return;
+                }
+            };
+        }
+        println!("[{}] {} connected to {}", name, from, lease.addr());
+
+        let recording = self
+            .capture
+            .as_ref()
+            .map(|capture| capture.open(name, &from, &lease.addr().to_string()));
+        let activity = Activity::new();
+        let inbound = Tracked::new(inbound, activity.clone());
+        let mut inbound = Recorded::new(inbound, recording.clone(), Direction::Up);
+        let mut outbound = Recorded::new(outbound, recording, Direction::Down);
+        let relay = tokio::select! {
+            relay = copy_bidirectional(&mut inbound, &mut outbound) => relay,
+            _ = activity.idle(limits.idle_timeout) => {
+                println!("[{}] {} idle for {:.1}s, closing", name, from, limits.idle_timeout.as_secs_f64());
+                return;
+            }
+        };
+
+        match relay {
+            Ok((sent, received)) => println!(
+                "[{}] {} closed after {:.1}s, {} bytes sent, {} received",
+                name,
+                from,
+                started.elapsed().as_secs_f64(),
+                sent,
+                received
+            ),
+            Err(e) => eprintln!("[{}] {} failed: {}", name, from, e),
+        }
+    }
+}
+
+// The route under test listens on a Unix socket.
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::tls::testing::{scratch, Ca};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn terminates_and_re_encrypts_tls() {
+        let dir = scratch("proxy-tls");
+        let ca = Ca::new(&dir, "ca");
+        let (proxy_cert, proxy_key) = ca.issue("proxy", "proxy.test");
+        let (upstream_cert, upstream_key) = ca.issue("upstream", "upstream.test");
+        let timeout = Duration::from_secs(5);
+
+        // An upstream that only speaks TLS, as upstream.test, and echoes.
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        let acceptor = tls::acceptor(Some(&upstream_cert), Some(&upstream_key), None)
+            .unwrap()
+            .unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = upstream.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    // Health checks hang up before a handshake.
+                    if let Ok(stream) = tls::accept(&acceptor, Stream::Tcp(stream), timeout).await {
+                        let (mut read, mut write) = tokio::io::split(stream);
+                        let _ = tokio::io::copy(&mut read, &mut write).await;
+                        let _ = write.shutdown().await;
+                    }
+                });
+            }
+        });
+
+        let listen = format!("unix:{}", dir.join("proxy.sock").display());
+        let mut route = Route::new(&listen, &upstream_addr);
+        route.tls_cert = Some(proxy_cert);
+        route.tls_key = Some(proxy_key);
+        route.upstream_ca = Some(ca.path());
+        route.upstream_name = Some(String::from("upstream.test"));
+        let limits = Limits::default();
+        let connections = Connections::new(limits.max_connections);
+        let _proxies = Proxies::bind(vec![route], limits, connections)
+            .await
+            .unwrap();
+
+        let addr = Addr::parse(&listen);
+        let stream = Stream::connect(&addr).await.unwrap();
+        let connector = tls::connector(&ca.path()).unwrap();
+        let mut stream = tls::connect(&connector, stream, &addr, Some("proxy.test"), timeout)
+            .await
+            .unwrap();
+        stream.write_all(b"through both").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut echoed = vec![];
+        stream.read_to_end(&mut echoed).await.unwrap();
+
+        assert_eq!(echoed, b"through both");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,296 @@
+use std::error::Error;
+use std::io::{self, BufReader};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::net::{Addr, Stream};
+
+/**
+ * TLS for a listener, from PEM files: `cert` holds the certificate chain, leaf first,
+ * and `key` its private key. With `client_ca`, clients have to present a certificate
+ * issued by one of the CAs in that file. None without a certificate, for plaintext.
+ */
+pub fn acceptor(
+    cert: Option<&Path>,
+    key: Option<&Path>,
+    client_ca: Option<&Path>,
+) -> Result<Option<TlsAcceptor>, Box<dyn Error>> {
+    let (cert, key) = match (cert, key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) if client_ca.is_none() => return Ok(None),
+        (None, None) => return Err("client certificates need a TLS certificate and key".into()),
+        _ => return Err("TLS needs both a certificate and a key".into()),
+    };
+
+    let builder = ServerConfig::builder();
+    let builder = match client_ca {
+        Some(ca) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots(ca)?)).build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    let config = builder
+        .with_single_cert(certs(cert)?, private_key(key)?)
+        .map_err(|e| format!("{}: {}", key.display(), e))?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
+
+/// TLS to upstreams whose certificates were issued by one of the CAs in `ca`.
+pub fn connector(ca: &Path) -> Result<TlsConnector, Box<dyn Error>> {
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots(ca)?)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates", path.display()));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .ok_or_else(|| format!("{}: no private key", path.display()))
+}
+
+fn roots(path: &Path) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for cert in certs(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+/// The server end of a handshake, given up after `timeout`.
+pub async fn accept(
+    acceptor: &TlsAcceptor,
+    stream: Stream,
+    timeout: Duration,
+) -> io::Result<Stream> {
+    match tokio::time::timeout(timeout, acceptor.accept(stream)).await {
+        Ok(tls) => Ok(Stream::Tls(Box::new(tls?.into()))),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "TLS handshake timed out",
+        )),
+    }
+}
+
+/// The client end of a handshake with `addr`, which has to prove it is `name`, or else
+/// the host it was reached by.
+pub async fn connect(
+    connector: &TlsConnector,
+    stream: Stream,
+    addr: &Addr,
+    name: Option<&str>,
+    timeout: Duration,
+) -> io::Result<Stream> {
+    let name = match (name, addr) {
+        (Some(name), _) => name.to_string(),
+        (None, Addr::Tcp(addr)) => host(addr).to_string(),
+        #[cfg(unix)]
+        (None, Addr::Unix(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS to a Unix socket needs a server name",
+            ))
+        }
+    };
+    let name =
+        ServerName::try_from(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    match tokio::time::timeout(timeout, connector.connect(name, stream)).await {
+        Ok(tls) => Ok(Stream::Tls(Box::new(tls?.into()))),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "TLS handshake timed out",
+        )),
+    }
+}
+
+/// `host` of `host:port`, without the brackets around an IPv6 address.
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// Throwaway CAs and certificates for tests, written out as PEM files.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::path::{Path, PathBuf};
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+
+    pub struct Ca {
+        cert: Certificate,
+        key: KeyPair,
+        dir: PathBuf,
+        name: String,
+    }
+
+    impl Ca {
+        /// A CA written to `dir/name.pem`.
+        pub fn new(dir: &Path, name: &str) -> Ca {
+            std::fs::create_dir_all(dir).unwrap();
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params.distinguished_name.push(DnType::CommonName, name);
+            let cert = params.self_signed(&key).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+
+            Ca {
+                cert,
+                key,
+                dir: dir.to_path_buf(),
+                name: name.to_string(),
+            }
+        }
+
+        pub fn path(&self) -> PathBuf {
+            self.dir.join(format!("{}.pem", self.name))
+        }
+
+        /// A certificate for `dns_name` and its key, as `dir/file.pem` and `dir/file.key`.
+        pub fn issue(&self, file: &str, dns_name: &str) -> (PathBuf, PathBuf) {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![dns_name.to_string()]).unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+
+            let cert_path = self.dir.join(format!("{}.pem", file));
+            let key_path = self.dir.join(format!("{}.key", file));
+            std::fs::write(&cert_path, cert.pem()).unwrap();
+            std::fs::write(&key_path, key.serialize_pem()).unwrap();
+            (cert_path, key_path)
+        }
+    }
+
+    pub fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ximp-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::testing::{scratch, Ca};
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Whether a handshake and a byte each way get through, as seen from both ends.
+    async fn exchange(acceptor: TlsAcceptor, connector: TlsConnector, name: Option<&str>) -> bool {
+        let timeout = Duration::from_secs(5);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = Addr::Tcp(listener.local_addr().unwrap().to_string());
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await?;
+            let mut stream = accept(&acceptor, Stream::Tcp(stream), timeout).await?;
+            let byte = stream.read_u8().await?;
+            stream.write_u8(byte + 1).await?;
+            stream.shutdown().await
+        });
+        let client = async {
+            let stream = Stream::connect(&addr).await?;
+            let mut stream = connect(&connector, stream, &addr, name, timeout).await?;
+            stream.write_u8(1).await?;
+            stream.read_u8().await
+        };
+
+        let client = client.await;
+        let server = server.await.unwrap();
+        server.is_ok() && matches!(client, Ok(2))
+    }
+
+    /// A connector trusting `ca`, presenting `cert` and `key` when given.
+    fn client(ca: &Path, identity: Option<(PathBuf, PathBuf)>) -> TlsConnector {
+        let builder = ClientConfig::builder().with_root_certificates(roots(ca).unwrap());
+        let config = match identity {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(certs(&cert).unwrap(), private_key(&key).unwrap())
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        TlsConnector::from(Arc::new(config))
+    }
+
+    #[tokio::test]
+    async fn terminates_tls() {
+        let dir = scratch("tls-terminate");
+        let ca = Ca::new(&dir, "ca");
+        let (cert, key) = ca.issue("server", "server.test");
+
+        let server = acceptor(Some(&cert), Some(&key), None).unwrap().unwrap();
+        let client = connector(&ca.path()).unwrap();
+        assert!(exchange(server, client, Some("server.test")).await);
+
+        assert!(acceptor(None, None, None).unwrap().is_none());
+        assert!(acceptor(Some(&cert), None, None).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn requires_client_certificates_from_the_client_ca() {
+        let dir = scratch("tls-client-auth");
+        let ca = Ca::new(&dir, "ca");
+        let clients = Ca::new(&dir, "clients");
+        let other = Ca::new(&dir, "other");
+        let (cert, key) = ca.issue("server", "server.test");
+        let acceptor = || {
+            acceptor(Some(&cert), Some(&key), Some(&clients.path()))
+                .unwrap()
+                .unwrap()
+        };
+
+        let trusted = clients.issue("trusted", "client.test");
+        let untrusted = other.issue("untrusted", "client.test");
+        let name = Some("server.test");
+        assert!(exchange(acceptor(), client(&ca.path(), Some(trusted)), name).await);
+        assert!(!exchange(acceptor(), client(&ca.path(), None), name).await);
+        assert!(!exchange(acceptor(), client(&ca.path(), Some(untrusted)), name).await);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn checks_upstream_names() {
+        let dir = scratch("tls-names");
+        let ca = Ca::new(&dir, "ca");
+        let (cert, key) = ca.issue("upstream", "upstream.test");
+        let acceptor = || acceptor(Some(&cert), Some(&key), None).unwrap().unwrap();
+        let connector = || connector(&ca.path()).unwrap();
+
+        assert!(exchange(acceptor(), connector(), Some("upstream.test")).await);
+        // By default the name is the host, 127.0.0.1 here, which the certificate isn't for.
+        assert!(!exchange(acceptor(), connector(), None).await);
+        assert!(!exchange(acceptor(), connector(), Some("other.test")).await);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn takes_the_host_of_an_address() {
+        assert_eq!(host("example.com:443"), "example.com");
+        assert_eq!(host("[::1]:8443"), "::1");
+    }
+}
@@ -0,0 +1,427 @@
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use textual_geometry::decode::{decode_spiral, DecodeLimits};
+use textual_geometry::encoder::Encoder;
+use textual_geometry::geometry::SpiralGeometry;
+use textual_geometry::rendering::Bitmap;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::limits::{Activity, Connections, Limits, Tracked};
+use crate::net::{Addr, Listener, Stream};
+
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+/// Bytes one 256x256 spiral frame holds.
+const FRAME_CAPACITY: usize = SpiralGeometry::capacity(256);
+/// Stream bytes carried per frame, after the nonce and tag.
+const CHUNK_LEN: usize = FRAME_CAPACITY - NONCE_LEN - TAG_LEN;
+/// Largest PNG accepted off the link, well above what any frame encodes to.
+const MAX_FRAME_LEN: u32 = 1 << 20;
+/// Each end's half of a session id.
+const NONCE_HALF: usize = 16;
+const SESSION_LEN: usize = 2 * NONCE_HALF;
+
+/// Which way a frame travels, bound into its authentication so frames can't be
+/// reflected back at their sender.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Direction {
+    /// From the tunnel client towards the upstream service.
+    Upstream,
+    Downstream,
+}
+
+impl Direction {
+    fn reverse(self) -> Direction {
+        match self {
+            Direction::Upstream => Direction::Downstream,
+            Direction::Downstream => Direction::Upstream,
+        }
+    }
+}
+
+/// Everything both ends of one tunnelled connection agree on.
+#[derive(Clone)]
+struct Session {
+    cipher: Arc<XChaCha20Poly1305>,
+    /// Random per connection, the server's half followed by the client's. With both
+    /// ends contributing, frames recorded from one connection can't be replayed into
+    /// another, by either side.
+    id: [u8; SESSION_LEN],
+}
+
+/// Read a pre-shared tunnel key: 32 bytes in hex, e.g. from `openssl rand -hex 32`.
+pub fn read_key(path: &str) -> Result<Arc<XChaCha20Poly1305>, Box<dyn Error>> {
+    let key = hex::decode(std::fs::read_to_string(path)?.trim())?;
+    let cipher = XChaCha20Poly1305::new_from_slice(&key)
+        .map_err(|_| format!("tunnel key in {} must be 32 bytes", path))?;
+    Ok(Arc::new(cipher))
+}
+
+/**
+ * The client end of a tunnel. Every connection accepted on `listen` gets its own link
+ * to the tunnel server at `tunnel`; the byte stream is cut into chunks, sealed with the
+ * pre-shared key and sent as spiral encoded PNG frames.
+ */
+pub async fn tunnel_client(
+    listen: &str,
+    tunnel: &str,
+    cipher: Arc<XChaCha20Poly1305>,
+    limits: Limits,
+) -> Result<(), Box<dyn Error>> {
+    let listener = Listener::bind(&Addr::parse(listen)).await?;
+    println!("Tunnel client listening on: {}", listen);
+    println!("Tunnelling to: {}", tunnel);
+
+    let connections = Connections::new(limits.max_connections);
+    serve_client(
+        listener,
+        Addr::parse(tunnel),
+        cipher,
+        connections.clone(),
+        limits,
+    )
+    .await;
+    connections.drain(limits.drain_timeout).await;
+    Ok(())
+}
+
+async fn serve_client(
+    listener: Listener,
+    tunnel: Addr,
+    cipher: Arc<XChaCha20Poly1305>,
+    connections: Connections,
+    limits: Limits,
+) {
+    while let Some((inbound, peer, permit)) = connections.accept(&listener).await {
+        let tunnel = tunnel.clone();
+        let cipher = cipher.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let result = async {
+                let mut link = Stream::connect(&tunnel).await?;
+                let id = handshake(&mut link, Direction::Upstream, limits.idle_timeout).await?;
+                let session = Session { cipher, id };
+                splice(inbound, link, session, Direction::Upstream, limits).await
+            };
+            if let Err(e) = result.await {
+                eprintln!("Tunnel for {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// The server end of a tunnel: opens frames arriving on `listen` and forwards the
+/// stream to `upstream`, sealing whatever comes back.
+pub async fn tunnel_server(
+    listen: &str,
+    upstream: &str,
+    cipher: Arc<XChaCha20Poly1305>,
+    limits: Limits,
+) -> Result<(), Box<dyn Error>> {
+    let listener = Listener::bind(&Addr::parse(listen)).await?;
+    println!("Tunnel server listening on: {}", listen);
+    println!("Forwarding to: {}", upstream);
+
+    let connections = Connections::new(limits.max_connections);
+    serve_server(
+        listener,
+        Addr::parse(upstream),
+        cipher,
+        connections.clone(),
+        limits,
+    )
+    .await;
+    connections.drain(limits.drain_timeout).await;
+    Ok(())
+}
+
+async fn serve_server(
+    listener: Listener,
+    upstream: Addr,
+    cipher: Arc<XChaCha20Poly1305>,
+    connections: Connections,
+    limits: Limits,
+) {
+    while let Some((mut link, peer, permit)) = connections.accept(&listener).await {
+        let upstream = upstream.clone();
+        let cipher = cipher.clone();
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            let result = async {
+                let id = handshake(&mut link, Direction::Downstream, limits.idle_timeout).await?;
+                let outbound = Stream::connect(&upstream).await?;
+                let session = Session { cipher, id };
+                splice(outbound, link, session, Direction::Downstream, limits).await
+            };
+            if let Err(e) = result.await {
+                eprintln!("Tunnel from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Swap random halves of the session id over a new link, each end sending its own
+/// before reading the other's. `sending` says which end this is.
+async fn handshake(
+    link: &mut Stream,
+    sending: Direction,
+    timeout: Duration,
+) -> io::Result<[u8; SESSION_LEN]> {
+    let mut ours = [0u8; NONCE_HALF];
+    getrandom::getrandom(&mut ours).map_err(|e| io::Error::other(e.to_string()))?;
+    let mut theirs = [0u8; NONCE_HALF];
+
+    let exchange = async {
+        link.write_all(&ours).await?;
+        link.read_exact(&mut theirs).await
+    };
+    match tokio::time::timeout(timeout, exchange).await {
+        Ok(exchanged) => exchanged?,
+        Err(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "tunnel handshake timed out",
+            ))
+        }
+    };
+
+    let (server, client) = match sending {
+        Direction::Upstream => (theirs, ours),
+        Direction::Downstream => (ours, theirs),
+    };
+    let mut id = [0u8; SESSION_LEN];
+    id[..NONCE_HALF].copy_from_slice(&server);
+    id[NONCE_HALF..].copy_from_slice(&client);
+    Ok(id)
+}
+
+/// Carry `plain` over `link` until both directions have ended, or nothing has passed
+/// either way for the idle timeout. What is read from `plain` travels as `sending`,
+/// frames off the link the other way.
+async fn splice(
+    plain: Stream,
+    link: Stream,
+    session: Session,
+    sending: Direction,
+    limits: Limits,
+) -> io::Result<()> {
+    let activity = Activity::new();
+    let (plain_read, plain_write) = tokio::io::split(Tracked::new(plain, activity.clone()));
+    let (link_read, link_write) = tokio::io::split(Tracked::new(link, activity.clone()));
+
+    tokio::select! {
+        spliced = async {
+            tokio::try_join!(
+                seal_frames(plain_read, link_write, session.clone(), sending),
+                open_frames(link_read, plain_write, session, sending.reverse()),
+            )
+        } => spliced.map(|_| ()),
+        _ = activity.idle(limits.idle_timeout) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("idle for {:.1}s", limits.idle_timeout.as_secs_f64()),
+        )),
+    }
+}
+
+/// Frames are a big endian length and a PNG. An empty chunk marks the end of the
+/// stream, so a link cut short is an error rather than a clean close.
+async fn seal_frames(
+    mut reader: impl AsyncRead + Unpin,
+    mut link: impl AsyncWrite + Unpin,
+    session: Session,
+    direction: Direction,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; CHUNK_LEN];
+    for counter in 0u64.. {
+        let n = reader.read(&mut buf).await?;
+        let chunk = buf[..n].to_vec();
+        let session = session.clone();
+        // Rendering and PNG encoding are too slow for the executor's threads.
+        let frame = tokio::task::spawn_blocking(move || seal(&session, direction, counter, &chunk))
+            .await??;
+
+        link.write_u32(frame.len() as u32).await?;
+        link.write_all(&frame).await?;
+        if n == 0 {
+            return link.shutdown().await;
+        }
+    }
+
+    Ok(())
+}
+
+async fn open_frames(
+    mut link: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    session: Session,
+    direction: Direction,
+) -> io::Result<()> {
+    for counter in 0u64.. {
+        let len = link.read_u32().await?;
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} byte frame is too large", len),
+            ));
+        }
+        let mut frame = vec![0u8; len as usize];
+        link.read_exact(&mut frame).await?;
+
+        let session = session.clone();
+        let chunk = tokio::task::spawn_blocking(move || open(&session, direction, counter, &frame))
+            .await??;
+        if chunk.is_empty() {
+            return writer.shutdown().await;
+        }
+        writer.write_all(&chunk).await?;
+    }
+
+    Ok(())
+}
+
+/// Seal a chunk and render it as a PNG frame.
+fn seal(
+    session: &Session,
+    direction: Direction,
+    counter: u64,
+    chunk: &[u8],
+) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce).map_err(|e| io::Error::other(e.to_string()))?;
+    let sealed = session
+        .cipher
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            Payload {
+                msg: chunk,
+                aad: &associated_data(session, direction, counter),
+            },
+        )
+        .map_err(|_| io::Error::other("failed to seal frame"))?;
+
+    let mut payload = nonce.to_vec();
+    payload.extend(sealed);
+
+    let mut geometry = SpiralGeometry::new(256);
+    let encoder = Encoder::from_bytes(256, &payload, &mut geometry);
+    encoder.to_bytes(&mut Bitmap::new(256))
+}
+
+/// Decode a PNG frame and open the chunk it carries. Frames out of order, from another
+/// session or direction, or tampered with all fail authentication.
+fn open(
+    session: &Session,
+    direction: Direction,
+    counter: u64,
+    frame: &[u8],
+) -> io::Result<Vec<u8>> {
+    let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+    let payload = decode_spiral(frame, &DecodeLimits::default())
+        .map_err(|e| invalid(format!("undecodable frame: {}", e)))?;
+    if payload.len() < NONCE_LEN + TAG_LEN {
+        return Err(invalid(String::from("truncated frame")));
+    }
+
+    let (nonce, sealed) = payload.split_at(NONCE_LEN);
+    session
+        .cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: sealed,
+                aad: &associated_data(session, direction, counter),
+            },
+        )
+        .map_err(|_| invalid(format!("frame {} failed authentication", counter)))
+}
+
+fn associated_data(session: &Session, direction: Direction, counter: u64) -> Vec<u8> {
+    let mut aad = session.id.to_vec();
+    aad.push(direction as u8);
+    aad.extend(counter.to_be_bytes());
+    aad
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn listener() -> (Listener, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (Listener::Tcp(listener), addr)
+    }
+
+    #[tokio::test]
+    async fn round_trips_over_loopback() {
+        let cipher = Arc::new(XChaCha20Poly1305::new(&[7u8; 32].into()));
+        let limits = Limits::default();
+        let connections = Connections::new(limits.max_connections);
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = echo.accept().await.unwrap();
+            let (mut read, mut write) = stream.split();
+            tokio::io::copy(&mut read, &mut write).await.unwrap();
+            write.shutdown().await.unwrap();
+        });
+
+        let (server, server_addr) = listener().await;
+        tokio::spawn(serve_server(
+            server,
+            Addr::Tcp(echo_addr),
+            cipher.clone(),
+            connections.clone(),
+            limits,
+        ));
+        let (client, client_addr) = listener().await;
+        tokio::spawn(serve_client(
+            client,
+            Addr::Tcp(server_addr),
+            cipher,
+            connections,
+            limits,
+        ));
+
+        // Several frames each way, the last one partly filled.
+        let sent: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let mut stream = TcpStream::connect(&client_addr).await.unwrap();
+        stream.write_all(&sent).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, sent);
+    }
+
+    #[test]
+    fn frames_only_open_in_their_own_session() {
+        let cipher = Arc::new(XChaCha20Poly1305::new(&[7u8; 32].into()));
+        let session = Session {
+            cipher,
+            id: [1; SESSION_LEN],
+        };
+        let frame = seal(&session, Direction::Downstream, 0, b"hello").unwrap();
+        assert_eq!(
+            open(&session, Direction::Downstream, 0, &frame).unwrap(),
+            b"hello"
+        );
+
+        // A recorded server half replayed to a client that picked a new half of its own.
+        let mut replayed = session.clone();
+        replayed.id[NONCE_HALF..].copy_from_slice(&[2; NONCE_HALF]);
+        assert!(open(&replayed, Direction::Downstream, 0, &frame).is_err());
+        assert!(open(&session, Direction::Upstream, 0, &frame).is_err());
+        assert!(open(&session, Direction::Downstream, 1, &frame).is_err());
+    }
+}
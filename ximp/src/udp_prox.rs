@@ -0,0 +1,405 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use textual_geometry::rendering::Layout;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::Notify;
+
+use crate::capture::Direction;
+use crate::config::Route;
+use crate::http_svc::{spiral_png, CAPACITY};
+use crate::limits::{Activity, Connections, Limits};
+#[cfg(unix)]
+use crate::net::Addr;
+
+/// Largest datagram UDP carries.
+const MAX_DATAGRAM: usize = 65535;
+/// Datagrams waiting to be imaged at most. Encoding is much slower than relaying, past
+/// this datagrams pass uninspected rather than piling up in memory.
+const MAX_QUEUED: usize = 1024;
+
+/**
+ * Writes relayed datagrams out as spiral PNGs, numbered in the order they passed. The
+ * encoding happens on a thread of its own, datagrams arriving while it is `MAX_QUEUED`
+ * behind are counted and skipped.
+ */
+struct Inspector {
+    dir: PathBuf,
+    prefix: String,
+    seq: AtomicU64,
+    images: SyncSender<(PathBuf, Vec<u8>)>,
+    skipped: Arc<AtomicU64>,
+}
+
+impl Inspector {
+    fn new(dir: PathBuf, name: &str) -> io::Result<Inspector> {
+        std::fs::create_dir_all(&dir)?;
+        let (images, received) = mpsc::sync_channel::<(PathBuf, Vec<u8>)>(MAX_QUEUED);
+        let skipped = Arc::new(AtomicU64::new(0));
+        let prefix = file_safe(name);
+
+        let counted = skipped.clone();
+        let label = name.to_string();
+        // Ends once the relay, and with it the sender, is gone.
+        std::thread::spawn(move || {
+            for (path, payload) in received {
+                let written = spiral_png(&payload, vec![], Layout::new(256))
+                    .and_then(|png| std::fs::write(&path, png));
+                if let Err(e) = written {
+                    eprintln!("Can't write {}: {}", path.display(), e);
+                }
+                let skipped = counted.swap(0, Ordering::Relaxed);
+                if skipped > 0 {
+                    eprintln!(
+                        "[{}] {} datagrams not inspected, too many queued",
+                        label, skipped
+                    );
+                }
+            }
+        });
+
+        Ok(Inspector {
+            dir,
+            prefix,
+            seq: AtomicU64::new(0),
+            images,
+            skipped,
+        })
+    }
+
+    /// Encoded off the relay path, a failure only costs the image.
+    fn record(&self, client: SocketAddr, direction: Direction, datagram: &[u8]) {
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!(
+            "{}-{:08}-{}-{}.png",
+            self.prefix,
+            seq,
+            file_safe(&client.to_string()),
+            direction.name()
+        ));
+        if datagram.len() > CAPACITY {
+            eprintln!(
+                "{} holds the first {} of {} bytes",
+                path.display(),
+                CAPACITY,
+                datagram.len()
+            );
+        }
+        let payload = datagram[..datagram.len().min(CAPACITY)].to_vec();
+
+        if let Err(TrySendError::Full(_)) = self.images.try_send((path, payload)) {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn file_safe(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// One client's traffic, over a socket of its own connected to the upstream picked for it.
+struct Session {
+    upstream: UdpSocket,
+    addr: String,
+    activity: Activity,
+    started: Instant,
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// Sending failed, the client's next datagram opens a new session.
+    failed: Notify,
+}
+
+/**
+ * A UDP route. Each client address gets a session with its own upstream socket, so
+ * replies find their way back to whoever sent the request, and sessions end once
+ * nothing has passed either way for the idle timeout. New sessions take the upstreams
+ * in turn.
+ */
+pub struct Relay {
+    name: String,
+    socket: UdpSocket,
+    upstreams: Vec<String>,
+    next: AtomicUsize,
+    sessions: Mutex<HashMap<SocketAddr, Arc<Session>>>,
+    inspector: Option<Inspector>,
+    connections: Connections,
+    limits: Limits,
+}
+
+impl Relay {
+    pub async fn bind(
+        route: &Route,
+        connections: Connections,
+        limits: Limits,
+    ) -> Result<Relay, Box<dyn Error>> {
+        let name = route.name().to_string();
+        #[cfg(unix)]
+        if let Some(unix) = std::iter::once(&route.listen)
+            .chain(&route.upstream)
+            .map(|addr| Addr::parse(addr))
+            .find(|addr| matches!(addr, Addr::Unix(_)))
+        {
+            return Err(format!("[{}] UDP routes can't use {}", name, unix).into());
+        }
+
+        let socket = UdpSocket::bind(&route.listen)
+            .await
+            .map_err(|e| format!("[{}] can't listen on {}: {}", name, route.listen, e))?;
+        let inspector = match &route.inspect {
+            Some(dir) => {
+                let inspector = Inspector::new(dir.clone(), &name);
+                Some(inspector.map_err(|e| format!("[{}] {}: {}", name, dir.display(), e))?)
+            }
+            None => None,
+        };
+
+        Ok(Relay {
+            name,
+            socket,
+            upstreams: route.upstream.clone(),
+            next: AtomicUsize::new(0),
+            sessions: Mutex::new(HashMap::new()),
+            inspector,
+            connections,
+            limits,
+        })
+    }
+
+    /// Relay client datagrams until shut down.
+    pub async fn serve(self: Arc<Self>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, client) = tokio::select! {
+                received = self.socket.recv_from(&mut buf) => match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("[{}] failed to receive: {}", self.name, e);
+                        continue;
+                    }
+                },
+                _ = self.connections.shutting_down() => return,
+            };
+            let datagram = &buf[..len];
+
+            let Some(session) = self.session(client).await else {
+                continue;
+            };
+            session.activity.touch();
+            match session.upstream.send(datagram).await {
+                Ok(_) => {
+                    session.sent.fetch_add(len as u64, Ordering::Relaxed);
+                    if let Some(inspector) = &self.inspector {
+                        inspector.record(client, Direction::Up, datagram);
+                    }
+                }
+                Err(e) => {
+                    eprintln!(
+                        "[{}] {} to {} failed: {}",
+                        self.name, client, session.addr, e
+                    );
+                    self.end(client, &session);
+                    session.failed.notify_one();
+                }
+            }
+        }
+    }
+
+    /// The client's session, opened on its first datagram. None when it can't be.
+    async fn session(self: &Arc<Self>, client: SocketAddr) -> Option<Arc<Session>> {
+        let open = {
+            let sessions = self.sessions.lock().unwrap();
+            if let Some(session) = sessions.get(&client) {
+                return Some(session.clone());
+            }
+            sessions.len()
+        };
+        if open >= self.limits.max_connections {
+            eprintln!("[{}] {} dropped, {} sessions open", self.name, client, open);
+            return None;
+        }
+
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let addr = &self.upstreams[next % self.upstreams.len()];
+        let upstream = match connect(addr).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                eprintln!(
+                    "[{}] {} dropped, can't reach {}: {}",
+                    self.name, client, addr, e
+                );
+                return None;
+            }
+        };
+        println!("[{}] {} relayed to {}", self.name, client, addr);
+
+        let session = Arc::new(Session {
+            upstream,
+            addr: addr.clone(),
+            activity: Activity::new(),
+            started: Instant::now(),
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            failed: Notify::new(),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(client, session.clone());
+        tokio::spawn(self.clone().answer(client, session.clone()));
+
+        Some(session)
+    }
+
+    /// Send the upstream's replies back to the client until the session ends.
+    async fn answer(self: Arc<Self>, client: SocketAddr, session: Arc<Session>) {
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let ended = loop {
+            tokio::select! {
+                received = session.upstream.recv(&mut buf) => match received {
+                    Ok(len) => {
+                        session.activity.touch();
+                        let datagram = &buf[..len];
+                        if let Err(e) = self.socket.send_to(datagram, client).await {
+                            eprintln!("[{}] {} from {} failed: {}", self.name, client, session.addr, e);
+                            continue;
+                        }
+                        session.received.fetch_add(len as u64, Ordering::Relaxed);
+                        if let Some(inspector) = &self.inspector {
+                            inspector.record(client, Direction::Down, datagram);
+                        }
+                    }
+                    Err(e) => break format!("{} failed: {}", session.addr, e),
+                },
+                // An ICMP error reported by a send, most likely the upstream port is closed.
+                _ = session.failed.notified() => break format!("{} unreachable", session.addr),
+                _ = session.activity.idle(self.limits.idle_timeout) => {
+                    break format!("idle for {:.1}s", self.limits.idle_timeout.as_secs_f64());
+                }
+                _ = self.connections.shutting_down() => break String::from("shutting down"),
+            }
+        };
+
+        self.end(client, &session);
+        println!(
+            "[{}] {} closed after {:.1}s, {}, {} bytes sent, {} received",
+            self.name,
+            client,
+            session.started.elapsed().as_secs_f64(),
+            ended,
+            session.sent.load(Ordering::Relaxed),
+            session.received.load(Ordering::Relaxed)
+        );
+    }
+
+    /// Forget `session`, unless the client has moved on to another.
+    fn end(&self, client: SocketAddr, session: &Arc<Session>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(&client)
+            .is_some_and(|open| Arc::ptr_eq(open, session))
+        {
+            sessions.remove(&client);
+        }
+    }
+}
+
+/// A socket that only talks to `addr`, bound for its address family.
+async fn connect(addr: &str) -> io::Result<UdpSocket> {
+    let addr = lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// An upstream that answers every datagram with its own address and the datagram.
+    async fn echo() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let reply = format!("{} {}", from, String::from_utf8_lossy(&buf[..len]));
+                let _ = socket.send_to(reply.as_bytes(), from).await;
+            }
+        });
+        addr
+    }
+
+    async fn ask(client: &UdpSocket, relay: SocketAddr, text: &str) -> String {
+        client.send_to(text.as_bytes(), relay).await.unwrap();
+        let mut buf = vec![0u8; MAX_DATAGRAM];
+        let receive = client.recv_from(&mut buf);
+        let (len, from) = tokio::time::timeout(Duration::from_secs(5), receive)
+            .await
+            .expect("no reply")
+            .unwrap();
+        assert_eq!(from, relay);
+        String::from_utf8_lossy(&buf[..len]).into_owned()
+    }
+
+    #[tokio::test]
+    async fn maps_clients_to_sessions_until_idle() {
+        let upstream = echo().await;
+        let route = Route::new("127.0.0.1:0", &upstream.to_string());
+        let limits = Limits {
+            idle_timeout: Duration::from_millis(300),
+            ..Limits::default()
+        };
+        let connections = Connections::new(limits.max_connections);
+        let relay = Arc::new(Relay::bind(&route, connections, limits).await.unwrap());
+        let addr = relay.socket.local_addr().unwrap();
+        tokio::spawn(relay.clone().serve());
+
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let first = ask(&a, addr, "one").await;
+        let second = ask(&b, addr, "two").await;
+        let again = ask(&a, addr, "three").await;
+
+        // Each client gets its own upstream socket, and keeps it while it's busy.
+        let (seen, text) = first.split_once(' ').unwrap();
+        assert_eq!(text, "one");
+        assert_eq!(again, format!("{} three", seen));
+        let (other, text) = second.split_once(' ').unwrap();
+        assert_eq!(text, "two");
+        assert_ne!(other, seen);
+        {
+            let sessions = relay.sessions.lock().unwrap();
+            assert_eq!(sessions.len(), 2);
+            assert!(sessions.contains_key(&a.local_addr().unwrap()));
+            assert!(sessions.contains_key(&b.local_addr().unwrap()));
+        }
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert!(relay.sessions.lock().unwrap().is_empty());
+
+        // A quiet client comes back to a new session.
+        assert!(ask(&a, addr, "four").await.ends_with(" four"));
+        assert_eq!(relay.sessions.lock().unwrap().len(), 1);
+    }
+}